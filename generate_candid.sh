@@ -0,0 +1,9 @@
+#!/bin/bash
+set -e
+
+# Regenerates the Candid interface from the Rust code and refreshes the declarations.
+# Requires: cargo install candid-extractor
+cargo build --target wasm32-unknown-unknown --release -p lain_art_backend
+candid-extractor target/wasm32-unknown-unknown/release/lain_art_backend.wasm > src/lain_art_backend/lain_art_backend.did
+dfx generate lain_art_backend
+cp src/declarations/lain_art_backend/lain_art_backend.did* src/lain_art_frontend/src/declarations/lain_art_backend/
@@ -2,21 +2,24 @@ import type { Principal } from '@dfinity/principal';
 import type { ActorMethod } from '@dfinity/agent';
 import type { IDL } from '@dfinity/candid';
 
+export type Addition = { 'Ok' : Embedding } |
+  { 'Err' : string };
 export interface ApprovedArtwork {
   'id' : bigint,
-  'approved_at' : bigint,
+  'creator' : Principal,
   'mime_type' : string,
   'image_data' : Uint8Array | number[],
-  'submission_id' : bigint,
-  'confidence_score' : number,
-  'recognized_as' : string,
+  'recognition_score' : number,
+  'timestamp' : bigint,
 }
-export interface ArtMeta {
-  'sha256' : Uint8Array | number[],
-  'mime' : string,
-  'size' : bigint,
-  'chunks' : bigint,
+export interface BoundingBox {
+  'top' : number,
+  'left' : number,
+  'bottom' : number,
+  'right' : number,
 }
+export type Detection = { 'Ok' : BoundingBox } |
+  { 'Err' : string };
 export interface Embedding { 'v0' : Array<number> }
 export interface FeeInvoice {
   'memo' : [] | [bigint],
@@ -24,64 +27,44 @@ export interface FeeInvoice {
   'amount' : bigint,
 }
 export interface Person { 'label' : string, 'score' : number }
-export interface RewardResult { 'token_id' : bigint, 'reward_txid' : string }
-export type Status = { 'Rewarded' : null } |
-  { 'Rejected' : { 'reason' : string } } |
-  { 'PendingUpload' : null } |
-  { 'AwaitingFee' : null } |
-  { 'Verified' : { 'originality' : number, 'visibility' : number } } |
-  { 'Verifying' : null };
-export interface Submission {
-  'id' : bigint,
-  'art' : [] | [ArtMeta],
-  'status' : Status,
-  'updated_at' : bigint,
-  'creator' : Principal,
-  'nft_token_id' : [] | [bigint],
-  'created_at' : bigint,
-  'fee_paid' : boolean,
-}
+export type Recognition = { 'Ok' : Person } |
+  { 'Err' : string };
+export type Result = { 'Ok' : BoundingBox } |
+  { 'Err' : string };
+export type Result_1 = { 'Ok' : null } |
+  { 'Err' : string };
+export type Result_2 = { 'Ok' : bigint } |
+  { 'Err' : string };
 export interface _SERVICE {
-  'add' : ActorMethod<
-    [string, Uint8Array | number[]],
-    { 'Ok' : Embedding } |
-      { 'Err' : string }
+  'add' : ActorMethod<[string, Uint8Array | number[]], Addition>,
+  'append_face_detection_model_bytes' : ActorMethod<
+    [Uint8Array | number[]],
+    undefined
   >,
-  'confirm_fee' : ActorMethod<[bigint], undefined>,
-  'detect' : ActorMethod<
+  'append_face_recognition_model_bytes' : ActorMethod<
     [Uint8Array | number[]],
-    { 'Ok' : Array<[number, number, number, number]> } |
-      { 'Err' : string }
+    undefined
   >,
+  'clear_face_detection_model_bytes' : ActorMethod<[], undefined>,
+  'clear_face_recognition_model_bytes' : ActorMethod<[], undefined>,
+  'detect' : ActorMethod<[Uint8Array | number[]], Detection>,
   'finalize_asset' : ActorMethod<
     [bigint, string, bigint, Uint8Array | number[]],
     undefined
   >,
-  'finalize_reward' : ActorMethod<[bigint], RewardResult>,
   'get_approved_artwork' : ActorMethod<[], Array<ApprovedArtwork>>,
   'get_artwork_by_id' : ActorMethod<[bigint], [] | [ApprovedArtwork]>,
   'get_artwork_count' : ActorMethod<[], bigint>,
   'get_face_count' : ActorMethod<[], bigint>,
   'get_fee_invoice' : ActorMethod<[bigint], FeeInvoice>,
-  'get_submission' : ActorMethod<[bigint], [] | [Submission]>,
   'list_stored_faces' : ActorMethod<[], Array<string>>,
-  'override_verdict' : ActorMethod<[bigint, Status, string], undefined>,
   'put_chunk' : ActorMethod<[bigint, bigint, Uint8Array | number[]], undefined>,
-  'recognize' : ActorMethod<
-    [Uint8Array | number[]],
-    { 'Ok' : Person } |
-      { 'Err' : string }
-  >,
+  'recognize' : ActorMethod<[Uint8Array | number[]], Recognition>,
   'remove_face' : ActorMethod<[string], undefined>,
-  'set_verdict' : ActorMethod<[bigint, number, number, string], undefined>,
-  'setup_models' : ActorMethod<[], { 'Ok' : null } | { 'Err' : string }>,
+  'run_face_detection' : ActorMethod<[bigint], Result>,
+  'setup_models' : ActorMethod<[], Result_1>,
   'start_submission' : ActorMethod<[], bigint>,
-  'trigger_verification' : ActorMethod<[bigint], undefined>,
-  'verify_and_store_artwork' : ActorMethod<
-    [bigint],
-    { 'Ok' : ApprovedArtwork } |
-      { 'Err' : string }
-  >,
+  'verify_and_store_artwork' : ActorMethod<[bigint], Result_2>,
 }
 export declare const idlFactory: IDL.InterfaceFactory;
 export declare const init: (args: { IDL: typeof IDL }) => IDL.Type[];
@@ -1,3 +1,6 @@
 fn main() {
-    println!("cargo:rustc-link-arg=--export=__getrandom_custom");
-}
\ No newline at end of file
+    // The custom getrandom hook only exists in the canister (wasm32) build.
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("wasm32") {
+        println!("cargo:rustc-link-arg=--export=__getrandom_custom");
+    }
+}
@@ -0,0 +1,109 @@
+//! Appeals against rejections.
+//!
+//! The creator of a rejected submission can appeal once, with a written
+//! justification, within `APPEAL_WINDOW` of the rejection. The submission goes
+//! back to the review queue, and the reviewer's decision and note are recorded
+//! on the appeal. Fee refunds are held until the rejection is final, so that
+//! an artwork approved on appeal has not been refunded.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use num_traits::ToPrimitive;
+
+use crate::backup::not_restoring;
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::review;
+use crate::submissions::{self, Status, Submission};
+
+/// Maximum length of a justification, in characters.
+const MAX_JUSTIFICATION_LENGTH: usize = 2_000;
+
+/// How long a rejection can be appealed, in nanoseconds.
+pub const APPEAL_WINDOW: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AppealOutcome {
+    /// Whether the artwork was approved on appeal.
+    pub approved: bool,
+    pub reviewer: Principal,
+    pub note: String,
+    pub decided_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Appeal {
+    pub justification: String,
+    /// The rejection reason that was appealed.
+    pub rejected_with: String,
+    pub opened_at: u64,
+    pub outcome: Option<AppealOutcome>,
+}
+
+/// Nanoseconds until the rejection of a submission can no longer be appealed;
+/// 0 once it is final, i.e. the window has passed or the appeal was decided.
+pub fn window_remaining(sub: &Submission) -> u64 {
+    if !matches!(sub.status, Status::Rejected { .. }) || sub.appeal.is_some() {
+        return 0;
+    }
+    // Submissions rejected by older versions have no rejection time.
+    let rejected_at = sub.rejected_at.unwrap_or(sub.updated_at);
+    rejected_at.saturating_add(APPEAL_WINDOW).saturating_sub(ic_cdk::api::time())
+}
+
+/// Records the outcome of an open appeal, if the submission has one.
+pub fn resolve(submission_id: u64, approved: bool, note: String) {
+    submissions::update(submission_id, |sub| {
+        if let Some(appeal) = sub.appeal.as_mut().filter(|appeal| appeal.outcome.is_none()) {
+            appeal.outcome = Some(AppealOutcome {
+                approved,
+                reviewer: ic_cdk::caller(),
+                note,
+                decided_at: ic_cdk::api::time(),
+            });
+        }
+    });
+}
+
+/// Appeals the rejection of a submission and sends it back to the review queue.
+/// Only the creator can appeal, once per submission and within `APPEAL_WINDOW`,
+/// and not while verification is paused.
+#[ic_cdk::update(guard = "not_restoring")]
+fn appeal_rejection(submission_id: Nat, justification: String) -> Result<(), ServiceError> {
+    pause::check(PauseSwitch::Verification)?;
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if sub.creator != ic_cdk::caller() {
+        return Err("Only the creator can appeal".into());
+    }
+    let Status::Rejected { reason } = sub.status else {
+        return Err("Only rejected submissions can be appealed".into());
+    };
+    if sub.appeal.is_some() {
+        return Err("This submission was already appealed".into());
+    }
+    if window_remaining(&sub) == 0 {
+        return Err("The appeal window of this submission has closed".into());
+    }
+    let justification = justification.trim().to_string();
+    if justification.is_empty() {
+        return Err("The justification must not be empty".into());
+    }
+    if justification.chars().count() > MAX_JUSTIFICATION_LENGTH {
+        return Err(format!("The justification must not exceed {} characters", MAX_JUSTIFICATION_LENGTH).into());
+    }
+    let image_data = review::image(submission_id)?;
+
+    submissions::update(submission_id, |sub| {
+        sub.appeal = Some(Appeal {
+            justification: justification.clone(),
+            rejected_with: reason,
+            opened_at: ic_cdk::api::time(),
+            outcome: None,
+        });
+    });
+    let (recognition_score, ranking) = match sub.review {
+        Some(review) => (review.recognition_score, review.ranking),
+        None => (None, None),
+    };
+    review::enqueue(submission_id, image_data, format!("Appeal: {}", justification), recognition_score, ranking);
+    Ok(())
+}
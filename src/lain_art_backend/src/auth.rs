@@ -0,0 +1,67 @@
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+use crate::backup;
+use crate::storage::{self, Mem, REVIEWERS_MEMORY_ID};
+
+thread_local! {
+    // Stable reviewer role: principal -> time the role was granted
+    static REVIEWERS: RefCell<StableBTreeMap<Principal, u64, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(REVIEWERS_MEMORY_ID))
+    );
+}
+
+/// Guard for administrative endpoints: only controllers of the canister may
+/// call them, and not while a backup is being restored.
+pub fn is_admin() -> Result<(), String> {
+    backup::not_restoring()?;
+    is_controller()
+}
+
+/// Whether the caller controls the canister.
+pub fn is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Only canister controllers can call this method".to_string())
+    }
+}
+
+/// Guard for review endpoints: reviewers and controllers may call them.
+pub fn is_reviewer() -> Result<(), String> {
+    backup::not_restoring()?;
+    let caller = ic_cdk::caller();
+    if REVIEWERS.with(|reviewers| reviewers.borrow().contains_key(&caller)) || is_admin().is_ok() {
+        Ok(())
+    } else {
+        Err("Only reviewers can call this method".to_string())
+    }
+}
+
+/// Returns the number of reviewers.
+pub fn reviewer_count() -> u64 {
+    REVIEWERS.with(|reviewers| reviewers.borrow().len())
+}
+
+/// Grants the reviewer role.
+#[ic_cdk::update(guard = "is_admin")]
+fn add_reviewer(reviewer: Principal) {
+    REVIEWERS.with(|reviewers| {
+        reviewers.borrow_mut().insert(reviewer, ic_cdk::api::time());
+    });
+}
+
+/// Revokes the reviewer role.
+#[ic_cdk::update(guard = "is_admin")]
+fn remove_reviewer(reviewer: Principal) {
+    REVIEWERS.with(|reviewers| {
+        reviewers.borrow_mut().remove(&reviewer);
+    });
+}
+
+/// Returns all principals with the reviewer role.
+#[ic_cdk::query(guard = "is_admin")]
+fn list_reviewers() -> Vec<Principal> {
+    REVIEWERS.with(|reviewers| reviewers.borrow().iter().map(|(reviewer, _)| reviewer).collect())
+}
@@ -0,0 +1,268 @@
+//! Backup and restore of the whole canister state.
+//!
+//! All persistent state (models, face database, submissions, gallery, NFT
+//! ledger, reviewers, configuration) lives in stable memory behind the memory
+//! manager, so a backup is a copy of the stable memory. `start_backup` freezes
+//! a snapshot: it records the size of the stable memory and the SHA-256 of
+//! every chunk in a manifest with a new backup ID, and pauses every switch of
+//! `pause` until `finish_backup`, so that submissions, verification, rewards
+//! and enrollment cannot write in the meantime. `get_backup_chunk` streams the
+//! chunks of that backup and refuses chunks that no longer match their frozen
+//! checksum, e.g. after a configuration change; the backup then has to be
+//! started again.
+//!
+//! Restoring writes the chunks into the stable memory of a freshly installed
+//! canister that holds no state at all, checking every chunk against the
+//! manifest, and the canister then has to be upgraded so that all structures
+//! are loaded from the restored memory. From `start_restore` until that
+//! upgrade, every other update and timer is refused, since the structures on
+//! the heap no longer match the stable memory. Heap state, such as
+//! verification jobs in progress, is not part of a backup.
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::stable;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::auth::{self, is_admin, is_controller};
+use crate::{enrollment, gallery, nft, phash, pipeline, storage, submissions, upgrade};
+
+/// Version of the backup format.
+pub const BACKUP_FORMAT_VERSION: u32 = 2;
+
+/// Bytes per chunk. Restoring sends the chunks as ingress messages, which are
+/// limited to 2MB.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+const FINISHED: &str = "The restore is finished; upgrade the canister to load the restored state";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BackupManifest {
+    pub version: u32,
+    /// Identifies the backup; every chunk carries it.
+    pub backup_id: u64,
+    pub created_at: u64,
+    /// Size of the stable memory in bytes.
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunks: u64,
+    /// SHA-256 of every chunk, taken when the backup started.
+    pub checksums: Vec<Vec<u8>>,
+}
+
+impl BackupManifest {
+    /// Offset and length of a chunk.
+    fn range(&self, index: u64) -> (u64, u64) {
+        let offset = index * self.chunk_size;
+        (offset, self.chunk_size.min(self.size - offset))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BackupChunk {
+    pub backup_id: u64,
+    pub index: u64,
+    pub data: Vec<u8>,
+    pub sha256: Vec<u8>,
+}
+
+/// A restore in progress. It lives on the heap, so only the upgrade that loads
+/// the restored state ends it.
+struct Restore {
+    manifest: BackupManifest,
+    received: BTreeSet<u64>,
+    /// Set by `finish_restore`; nothing can be written any more.
+    finished: bool,
+}
+
+thread_local! {
+    // The backup being taken; it lives on the heap, so an upgrade ends it.
+    static BACKUP: RefCell<Option<BackupManifest>> = const { RefCell::new(None) };
+    static RESTORE: RefCell<Option<Restore>> = const { RefCell::new(None) };
+}
+
+/// Whether the stable memory holds no state yet: no models, faces,
+/// submissions, artwork, tokens, reference frames or reviewers.
+fn is_empty() -> bool {
+    storage::models_empty()
+        && storage::get_face_database_size() == 0
+        && submissions::next_id() == 1
+        && gallery::count() == 0
+        && nft::total_supply() == 0
+        && phash::frame_count() == 0
+        && auth::reviewer_count() == 0
+}
+
+/// Whether a backup is being taken. Every pause switch is on until it finishes.
+pub fn is_running() -> bool {
+    BACKUP.with_borrow(Option::is_some)
+}
+
+/// Whether a backup is being restored and the canister not yet upgraded.
+pub fn is_restoring() -> bool {
+    RESTORE.with_borrow(Option::is_some)
+}
+
+/// Guard for every update except the restore itself: fails from
+/// `start_restore` until the upgrade that loads the restored state.
+pub fn not_restoring() -> Result<(), String> {
+    if is_restoring() {
+        return Err("A backup is being restored; upgrade the canister to load it".to_string());
+    }
+    Ok(())
+}
+
+fn read_chunk(manifest: &BackupManifest, index: u64) -> Vec<u8> {
+    let (offset, length) = manifest.range(index);
+    let mut data = vec![0; length as usize];
+    stable::stable_read(offset, &mut data);
+    data
+}
+
+/// Freezes a snapshot of the current state and pauses all writes until
+/// `finish_backup`. Fails while background jobs are still running.
+#[ic_cdk::update(guard = "is_admin")]
+fn start_backup() -> Result<BackupManifest, String> {
+    if is_running() {
+        return Err("A backup is already running".to_string());
+    }
+    let jobs = upgrade::background_jobs();
+    if !jobs.is_empty() {
+        return Err(jobs.join(", "));
+    }
+    let size = stable::stable_size() * WASM_PAGE_SIZE;
+    let now = ic_cdk::api::time();
+    let mut manifest = BackupManifest {
+        version: BACKUP_FORMAT_VERSION,
+        backup_id: now,
+        created_at: now,
+        size,
+        chunk_size: CHUNK_SIZE,
+        chunks: size.div_ceil(CHUNK_SIZE),
+        checksums: Vec::new(),
+    };
+    manifest.checksums = (0..manifest.chunks)
+        .map(|index| Sha256::digest(read_chunk(&manifest, index)).to_vec())
+        .collect();
+    BACKUP.set(Some(manifest.clone()));
+    ic_cdk::println!("[Backup] Backup {} started with {} chunks", manifest.backup_id, manifest.chunks);
+    Ok(manifest)
+}
+
+/// Returns the manifest of the running backup.
+#[ic_cdk::query(guard = "is_admin")]
+fn get_backup_manifest() -> Option<BackupManifest> {
+    BACKUP.with_borrow(Clone::clone)
+}
+
+/// Returns a chunk of the running backup. Fails if the chunk changed since
+/// the backup started.
+#[ic_cdk::query(guard = "is_admin")]
+fn get_backup_chunk(backup_id: u64, index: u64) -> Result<BackupChunk, String> {
+    let manifest = get_backup_manifest().filter(|manifest| manifest.backup_id == backup_id);
+    let manifest = manifest.ok_or(format!("Backup {} is not running", backup_id))?;
+    if index >= manifest.chunks {
+        return Err(format!("The backup has {} chunks", manifest.chunks));
+    }
+    let data = read_chunk(&manifest, index);
+    let sha256 = Sha256::digest(&data).to_vec();
+    if sha256 != manifest.checksums[index as usize] {
+        return Err(format!("Chunk {} changed since the backup started", index));
+    }
+    Ok(BackupChunk { backup_id, index, data, sha256 })
+}
+
+/// Ends the running backup and lifts its pause.
+#[ic_cdk::update(guard = "is_admin")]
+fn finish_backup(backup_id: u64) -> Result<(), String> {
+    if get_backup_manifest().is_none_or(|manifest| manifest.backup_id != backup_id) {
+        return Err(format!("Backup {} is not running", backup_id));
+    }
+    BACKUP.set(None);
+    pipeline::resume();
+    enrollment::resume();
+    ic_cdk::println!("[Backup] Backup {} finished", backup_id);
+    Ok(())
+}
+
+/// Prepares a freshly installed canister to restore a backup.
+#[ic_cdk::update(guard = "is_controller")]
+fn start_restore(manifest: BackupManifest) -> Result<(), String> {
+    if is_restoring() {
+        return Err("A restore is already in progress".to_string());
+    }
+    if manifest.version != BACKUP_FORMAT_VERSION {
+        return Err(format!("Unsupported backup version {}", manifest.version));
+    }
+    if manifest.chunk_size == 0 || manifest.chunk_size > 2 * CHUNK_SIZE {
+        return Err("Invalid chunk size".to_string());
+    }
+    if manifest.chunks != manifest.size.div_ceil(manifest.chunk_size) {
+        return Err("The number of chunks does not match the size".to_string());
+    }
+    if manifest.checksums.len() as u64 != manifest.chunks {
+        return Err("The number of checksums does not match the chunks".to_string());
+    }
+    if !is_empty() {
+        return Err("Backups can only be restored into an empty canister".to_string());
+    }
+    let pages = manifest.size.div_ceil(WASM_PAGE_SIZE);
+    let current = stable::stable_size();
+    if pages > current {
+        stable::stable_grow(pages - current).map_err(|err| format!("Failed to grow stable memory: {:?}", err))?;
+    }
+    RESTORE.set(Some(Restore { manifest, received: BTreeSet::new(), finished: false }));
+    Ok(())
+}
+
+/// Writes a chunk of the backup after checking it against the manifest.
+/// Chunks may arrive in any order.
+#[ic_cdk::update(guard = "is_controller")]
+fn restore_chunk(chunk: BackupChunk) -> Result<(), String> {
+    RESTORE.with_borrow_mut(|restore| {
+        let restore = restore.as_mut().ok_or("No restore in progress")?;
+        if restore.finished {
+            return Err(FINISHED.to_string());
+        }
+        if chunk.backup_id != restore.manifest.backup_id {
+            return Err(format!("Chunk {} belongs to another backup", chunk.index));
+        }
+        if chunk.index >= restore.manifest.chunks {
+            return Err(format!("The backup has {} chunks", restore.manifest.chunks));
+        }
+        let (offset, length) = restore.manifest.range(chunk.index);
+        if chunk.data.len() as u64 != length {
+            return Err(format!("Chunk {} should have {} bytes", chunk.index, length));
+        }
+        let sha256 = Sha256::digest(&chunk.data);
+        if sha256.as_slice() != restore.manifest.checksums[chunk.index as usize].as_slice() {
+            return Err(format!("Checksum mismatch in chunk {}", chunk.index));
+        }
+        stable::stable_write(offset, &chunk.data);
+        restore.received.insert(chunk.index);
+        Ok(())
+    })
+}
+
+/// Checks that every chunk was restored and closes the restore. Upgrade the
+/// canister right after, so that it loads the restored state; until then,
+/// every update keeps failing, and so does calling this again.
+#[ic_cdk::update(guard = "is_controller")]
+fn finish_restore() -> Result<u64, String> {
+    RESTORE.with_borrow_mut(|restore| {
+        let restore = restore.as_mut().ok_or("No restore in progress")?;
+        if restore.finished {
+            return Err(FINISHED.to_string());
+        }
+        let missing = restore.manifest.chunks - restore.received.len() as u64;
+        if missing > 0 {
+            return Err(format!("{} chunks are missing", missing));
+        }
+        restore.finished = true;
+        ic_cdk::println!("[Backup] Backup {} restored; waiting for the upgrade", restore.manifest.backup_id);
+        Ok(restore.manifest.chunks)
+    })
+}
@@ -0,0 +1,392 @@
+//! Face detection models and modes.
+//!
+//! A face detection model comes with a `DetectorFormat` that decodes its
+//! outputs into boxes and provides its default preprocessing. The format is read
+//! from the `lain_art.*` entries of the ONNX metadata, so that an anime face
+//! detector with a YOLO-style output can replace or complement UltraFace.
+//!
+//! UltraFace sees the whole image at 320x240, so a small face in a large
+//! poster shrinks to a few pixels. Tiled detection additionally runs the model
+//! over overlapping tiles of the working image, maps the boxes back to image
+//! coordinates and merges duplicates with non-maximum suppression (NMS).
+
+use anyhow::anyhow;
+use candid::{CandidType, Deserialize};
+use image::RgbImage;
+use std::collections::HashMap;
+use tract_ndarray::s;
+use tract_onnx::prelude::*;
+
+use crate::onnx::{self, BoundingBox, Detector};
+use crate::preprocess::{PreprocessSpec, ResizeMode};
+
+/// Minimum confidence of a box to take part in the merge.
+const TILE_MIN_CONFIDENCE: f32 = 0.7;
+
+/// Boxes overlapping a more confident box by more than this IoU are dropped.
+const NMS_IOU_THRESHOLD: f32 = 0.3;
+
+/// Which of the loaded face detection models to use.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DetectorModel {
+    /// The face detection model (UltraFace unless replaced).
+    Photo,
+    /// The optional anime face detection model.
+    Anime,
+}
+
+/// How the outputs of a face detection model are decoded.
+pub trait DetectorFormat {
+    /// The preprocessing the model is run with unless a spec is stored for it.
+    fn default_spec(&self) -> PreprocessSpec;
+    /// Decodes the model outputs into boxes relative to the model input, with their confidence.
+    fn decode(&self, outputs: &[TValue]) -> Result<Vec<(BoundingBox, f32)>, anyhow::Error>;
+}
+
+/// UltraFace (`version-RFB-320.onnx`): `scores` (background, face) and
+/// relative corner `boxes` per prior.
+pub struct UltraFace;
+
+impl DetectorFormat for UltraFace {
+    fn default_spec(&self) -> PreprocessSpec {
+        PreprocessSpec::ultraface()
+    }
+
+    fn decode(&self, outputs: &[TValue]) -> Result<Vec<(BoundingBox, f32)>, anyhow::Error> {
+        if outputs.len() < 2 {
+            return Err(anyhow!("UltraFace expects scores and boxes outputs"));
+        }
+        let confidences = outputs[0].to_array_view::<f32>()?.slice(s![0, .., 1]).to_vec();
+        let boxes: Vec<_> = outputs[1].to_array_view::<f32>()?.iter().cloned().collect();
+        Ok(boxes.chunks(4).map(BoundingBox::new).zip(confidences).collect())
+    }
+}
+
+/// A YOLO-style detector with one output of rows `[x, y, w, h, obj, cls...]`,
+/// centers and sizes in input pixels. The input is letterboxed and scaled to
+/// 0-1 by default; a stored spec must keep the input size.
+///
+/// Without anchors the rows are expected to be decoded already (as exported
+/// by YOLOv5 and later). With anchors they are raw logits, ordered by level,
+/// anchor, grid row and grid column, and decoded the YOLOv5 way.
+pub struct Yolo {
+    pub input_width: u32,
+    pub input_height: u32,
+    /// Downsampling factor of each output level, e.g. 8, 16 and 32.
+    pub strides: Vec<u32>,
+    /// Anchor sizes (width, height) in input pixels, one list per level.
+    pub anchors: Vec<Vec<(f32, f32)>>,
+    /// Index of the face class among the class scores.
+    pub face_class: usize,
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+impl Yolo {
+    /// Returns the grid cell, stride and anchor of each raw output row.
+    fn cells(&self) -> Vec<(f32, f32, f32, (f32, f32))> {
+        let mut cells = Vec::new();
+        for (stride, anchors) in self.strides.iter().zip(&self.anchors) {
+            let (columns, rows) = (self.input_width / stride, self.input_height / stride);
+            for anchor in anchors {
+                for y in 0..rows {
+                    for x in 0..columns {
+                        cells.push((x as f32, y as f32, *stride as f32, *anchor));
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+impl DetectorFormat for Yolo {
+    fn default_spec(&self) -> PreprocessSpec {
+        PreprocessSpec {
+            resize: ResizeMode::Letterbox,
+            ..PreprocessSpec::new(self.input_width, self.input_height, [0.0; 3], [1.0; 3])
+        }
+    }
+
+    fn decode(&self, outputs: &[TValue]) -> Result<Vec<(BoundingBox, f32)>, anyhow::Error> {
+        let output = outputs.first().ok_or(anyhow!("YOLO detector has no output"))?;
+        let view = output.to_array_view::<f32>()?;
+        let columns = *view.shape().last().unwrap_or(&0);
+        if columns < 5 {
+            return Err(anyhow!("YOLO output rows need at least 5 values, got {}", columns));
+        }
+        let values: Vec<f32> = view.iter().cloned().collect();
+        let rows = values.chunks(columns);
+
+        let raw = !self.anchors.is_empty();
+        let cells = if raw { self.cells() } else { Vec::new() };
+        if raw && cells.len() != values.len() / columns {
+            return Err(anyhow!("YOLO output has {} rows, the anchors expect {}", values.len() / columns, cells.len()));
+        }
+
+        let (input_width, input_height) = (self.input_width as f32, self.input_height as f32);
+        let mut boxes = Vec::with_capacity(values.len() / columns);
+        for (index, row) in rows.enumerate() {
+            let (cx, cy, w, h, objectness, class) = if raw {
+                let (gx, gy, stride, (anchor_w, anchor_h)) = cells[index];
+                let class = row.get(5 + self.face_class).map_or(1.0, |c| sigmoid(*c));
+                (
+                    (sigmoid(row[0]) * 2.0 - 0.5 + gx) * stride,
+                    (sigmoid(row[1]) * 2.0 - 0.5 + gy) * stride,
+                    (sigmoid(row[2]) * 2.0).powi(2) * anchor_w,
+                    (sigmoid(row[3]) * 2.0).powi(2) * anchor_h,
+                    sigmoid(row[4]),
+                    class,
+                )
+            } else {
+                let class = row.get(5 + self.face_class).copied().unwrap_or(1.0);
+                (row[0], row[1], row[2], row[3], row[4], class)
+            };
+            let bbox = BoundingBox {
+                left: (cx - w / 2.0) / input_width,
+                top: (cy - h / 2.0) / input_height,
+                right: (cx + w / 2.0) / input_width,
+                bottom: (cy + h / 2.0) / input_height,
+            };
+            boxes.push((bbox, objectness * class));
+        }
+        Ok(boxes)
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(value: &str) -> Result<Vec<T>, anyhow::Error> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(|_| anyhow!("Invalid number '{}'", item)))
+        .collect()
+}
+
+/// Reads the format of a detection model from its ONNX metadata:
+///
+/// - `lain_art.detector`: `ultraface` (the default) or `yolo`
+/// - `lain_art.input_size`: YOLO input as `<width>x<height>`, 640x640 by default
+/// - `lain_art.strides`: YOLO output strides, e.g. `8,16,32`
+/// - `lain_art.anchors`: anchor sizes per stride, `;`-separated, e.g.
+///   `10,13,16,30,33,23;30,61,62,45,59,119;116,90,156,198,373,326`
+/// - `lain_art.face_class`: index of the face class, 0 by default
+pub fn format_from_metadata(metadata: &HashMap<String, String>) -> Result<Box<dyn DetectorFormat>, anyhow::Error> {
+    let kind = metadata.get("lain_art.detector").map(|kind| kind.trim().to_lowercase());
+    match kind.as_deref() {
+        None | Some("ultraface") => Ok(Box::new(UltraFace)),
+        Some("yolo") => {
+            let (input_width, input_height) = match metadata.get("lain_art.input_size") {
+                Some(size) => {
+                    let (width, height) = size.split_once('x').ok_or(anyhow!("Invalid input size '{}'", size))?;
+                    (width.trim().parse()?, height.trim().parse()?)
+                }
+                None => (640, 640),
+            };
+            let strides: Vec<u32> = metadata.get("lain_art.strides").map_or(Ok(Vec::new()), |v| parse_list(v))?;
+            let anchors = match metadata.get("lain_art.anchors") {
+                Some(anchors) => anchors
+                    .split(';')
+                    .map(|level| {
+                        let sizes: Vec<f32> = parse_list(level)?;
+                        if sizes.len() % 2 == 1 {
+                            return Err(anyhow!("Anchors need a width and a height"));
+                        }
+                        Ok(sizes.chunks(2).map(|size| (size[0], size[1])).collect())
+                    })
+                    .collect::<Result<Vec<_>, anyhow::Error>>()?,
+                None => Vec::new(),
+            };
+            if !anchors.is_empty() && anchors.len() != strides.len() {
+                return Err(anyhow!("Expected one anchor list per stride"));
+            }
+            if strides.contains(&0) {
+                return Err(anyhow!("Strides must not be zero"));
+            }
+            let face_class = metadata.get("lain_art.face_class").map_or(Ok(0), |class| class.trim().parse())?;
+            Ok(Box::new(Yolo { input_width, input_height, strides, anchors, face_class }))
+        }
+        Some(other) => Err(anyhow!("Unknown detector format '{}'", other)),
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DetectionMode {
+    /// One pass over the whole image.
+    Full,
+    /// A pass over the whole image plus one per tile. Sizes are in pixels of
+    /// the working image (at most 1280 on the long side); `overlap` is shared by
+    /// neighbouring tiles. 640x480 tiles with an overlap of 160 are a good start.
+    Tiled { tile_width: u32, tile_height: u32, overlap: u32 },
+}
+
+fn iou(a: &BoundingBox, b: &BoundingBox) -> f32 {
+    let width = (a.right.min(b.right) - a.left.max(b.left)).max(0.0);
+    let height = (a.bottom.min(b.bottom) - a.top.max(b.top)).max(0.0);
+    let intersection = width * height;
+    let area = |r: &BoundingBox| (r.right - r.left).max(0.0) * (r.bottom - r.top).max(0.0);
+    let union = area(a) + area(b) - intersection;
+    if union <= 0.0 {
+        0.0
+    } else {
+        intersection / union
+    }
+}
+
+/// Greedy non-maximum suppression: keeps the most confident of overlapping boxes.
+pub fn nms(mut boxes: Vec<(BoundingBox, f32)>, iou_threshold: f32) -> Vec<(BoundingBox, f32)> {
+    boxes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut kept: Vec<(BoundingBox, f32)> = Vec::new();
+    for candidate in boxes {
+        if kept.iter().all(|(other, _)| iou(&candidate.0, other) <= iou_threshold) {
+            kept.push(candidate);
+        }
+    }
+    kept
+}
+
+/// Returns the start offsets of tiles of `tile` pixels covering `length` pixels.
+fn tile_offsets(length: u32, tile: u32, overlap: u32) -> Vec<u32> {
+    if length <= tile {
+        return vec![0];
+    }
+    let stride = tile.saturating_sub(overlap).max(1);
+    let mut offsets: Vec<u32> = (0..length - tile).step_by(stride as usize).collect();
+    offsets.push(length - tile);
+    offsets
+}
+
+/// Maps a tile-relative box of the tile at `(x, y)` to image-relative coordinates.
+fn tile_to_image(
+    bbox: &BoundingBox,
+    (x, y): (u32, u32),
+    (tile_w, tile_h): (u32, u32),
+    (width, height): (u32, u32),
+) -> BoundingBox {
+    BoundingBox {
+        left: (x as f32 + bbox.left * tile_w as f32) / width as f32,
+        top: (y as f32 + bbox.top * tile_h as f32) / height as f32,
+        right: (x as f32 + bbox.right * tile_w as f32) / width as f32,
+        bottom: (y as f32 + bbox.bottom * tile_h as f32) / height as f32,
+    }
+}
+
+/// Detects faces over the whole image and over overlapping tiles, and returns
+/// the merged boxes in image-relative coordinates, most confident first.
+pub fn detect_tiled_with(
+    detector: &Detector,
+    image: &RgbImage,
+    tile_width: u32,
+    tile_height: u32,
+    overlap: u32,
+) -> Result<Vec<(BoundingBox, f32)>, anyhow::Error> {
+    let (width, height) = image.dimensions();
+    // Of the whole-image pass, the best box is always kept so that tiling
+    // never finds less than `DetectionMode::Full`.
+    let mut boxes = onnx::detect_all_with(detector, image)?;
+    boxes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut rank = 0;
+    boxes.retain(|(_, confidence)| {
+        rank += 1;
+        rank == 1 || *confidence >= TILE_MIN_CONFIDENCE
+    });
+
+    if width > tile_width || height > tile_height {
+        for y in tile_offsets(height, tile_height, overlap) {
+            for x in tile_offsets(width, tile_width, overlap) {
+                let tile_w = tile_width.min(width);
+                let tile_h = tile_height.min(height);
+                let tile = image::imageops::crop_imm(image, x, y, tile_w, tile_h).to_image();
+                for (bbox, confidence) in onnx::detect_all_with(detector, &tile)? {
+                    if confidence < TILE_MIN_CONFIDENCE {
+                        continue;
+                    }
+                    boxes.push((tile_to_image(&bbox, (x, y), (tile_w, tile_h), (width, height)), confidence));
+                }
+            }
+        }
+    }
+    Ok(nms(boxes, NMS_IOU_THRESHOLD))
+}
+
+/// Returns the faces in the image using the given model and mode, most
+/// confident first. Errors mean that detection could not run, e.g. because the
+/// model is not loaded; an image without a face gives no boxes.
+pub fn detect_faces(
+    which: &DetectorModel,
+    mode: &DetectionMode,
+    image: &RgbImage,
+) -> Result<Vec<(BoundingBox, f32)>, anyhow::Error> {
+    onnx::with_detector(which, |detector| match mode {
+        DetectionMode::Full => {
+            let mut boxes = onnx::detect_all_with(detector, image)?;
+            boxes.sort_by(|a, b| b.1.total_cmp(&a.1));
+            Ok(boxes)
+        }
+        DetectionMode::Tiled { tile_width, tile_height, overlap } => {
+            detect_tiled_with(detector, image, *tile_width, *tile_height, *overlap)
+        }
+    })
+}
+
+/// Returns the most confident face in the image using the given model and mode.
+pub fn detect(
+    which: &DetectorModel,
+    mode: &DetectionMode,
+    image: &RgbImage,
+) -> Result<(BoundingBox, f32), anyhow::Error> {
+    detect_faces(which, mode, image)?.into_iter().next().ok_or(anyhow!("No face detected"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbox(left: f32, top: f32, right: f32, bottom: f32) -> BoundingBox {
+        BoundingBox { left, top, right, bottom }
+    }
+
+    #[test]
+    fn iou_of_identical_disjoint_and_half_overlapping_boxes() {
+        let a = bbox(0.0, 0.0, 0.2, 0.2);
+        assert_eq!(iou(&a, &a), 1.0);
+        assert_eq!(iou(&a, &bbox(0.5, 0.5, 0.7, 0.7)), 0.0);
+        // Intersection 0.02, union 0.06
+        assert!((iou(&a, &bbox(0.1, 0.0, 0.3, 0.2)) - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(iou(&bbox(0.3, 0.3, 0.3, 0.3), &bbox(0.3, 0.3, 0.3, 0.3)), 0.0);
+    }
+
+    #[test]
+    fn nms_keeps_the_most_confident_of_boxes_overlapping_by_more_than_0_3() {
+        let best = bbox(0.0, 0.0, 0.2, 0.2);
+        // IoU 1/3 with `best`: dropped
+        let overlapping = bbox(0.1, 0.0, 0.3, 0.2);
+        // IoU 0.25 with `best`: kept
+        let neighbour = bbox(0.0, 0.12, 0.2, 0.32);
+        let distant = bbox(0.6, 0.6, 0.8, 0.8);
+        let kept = nms(
+            vec![(overlapping, 0.8), (distant, 0.7), (best.clone(), 0.9), (neighbour.clone(), 0.75)],
+            NMS_IOU_THRESHOLD,
+        );
+        assert_eq!(kept, vec![(best, 0.9), (neighbour, 0.75), (distant, 0.7)]);
+    }
+
+    #[test]
+    fn tiles_cover_the_whole_length() {
+        assert_eq!(tile_offsets(640, 640, 160), vec![0]);
+        assert_eq!(tile_offsets(300, 640, 160), vec![0]);
+        assert_eq!(tile_offsets(1280, 640, 160), vec![0, 480, 640]);
+        assert_eq!(tile_offsets(10, 4, 4), vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn tile_boxes_are_mapped_to_image_coordinates() {
+        // The right half of a 640x480 tile at (480, 0) of a 1280x960 image.
+        let mapped = tile_to_image(&bbox(0.5, 0.0, 1.0, 1.0), (480, 0), (640, 480), (1280, 960));
+        assert_eq!(mapped, bbox(0.625, 0.0, 0.875, 0.5));
+        let whole = tile_to_image(&bbox(0.0, 0.0, 1.0, 1.0), (0, 0), (1280, 960), (1280, 960));
+        assert_eq!(whole, bbox(0.0, 0.0, 1.0, 1.0));
+    }
+}
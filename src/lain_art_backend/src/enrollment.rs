@@ -0,0 +1,227 @@
+//! Batch enrollment of reference faces.
+//!
+//! `add` takes a whole image in one message, which limits the size of the
+//! image and needs one call per face. A batch enrolls many images under one
+//! identity instead: `start_enrollment` opens it, `put_enrollment_chunk`
+//! uploads the images in chunks, and `commit_enrollment` queues them. A timer
+//! then enrolls one image per message, so that every image gets the whole
+//! instruction limit, and records why an image failed, e.g. "No face
+//! detected". An image that traps is recorded as failed by a watchdog.
+//! Batches live on the heap; a batch interrupted by an upgrade is lost and has
+//! to be uploaded again.
+
+use candid::{CandidType, Deserialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::auth::is_admin;
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::onnx;
+
+/// Total size of the images of a batch.
+const MAX_BATCH_BYTES: usize = 32 * 1024 * 1024;
+
+/// Number of chunks an image may be split into.
+const MAX_CHUNKS: u32 = 1024;
+
+/// Delay after which an image that did not complete is assumed to have trapped.
+const WATCHDOG_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct EnrolledImage {
+    pub name: String,
+    pub reference_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct EnrollmentFailure {
+    pub name: String,
+    pub error: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct EnrollmentReport {
+    pub label: String,
+    pub negative: bool,
+    pub committed: bool,
+    /// Images that still have to be enrolled.
+    pub remaining: u64,
+    pub enrolled: Vec<EnrolledImage>,
+    pub failures: Vec<EnrollmentFailure>,
+}
+
+struct Batch {
+    /// Uploaded images: name -> chunks
+    images: BTreeMap<String, Vec<Vec<u8>>>,
+    size: usize,
+    report: EnrollmentReport,
+}
+
+thread_local! {
+    static BATCHES: RefCell<BTreeMap<u64, Batch>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_BATCH_ID: Cell<u64> = const { Cell::new(0) };
+    // Committed images waiting to be enrolled: (batch id, image name)
+    static QUEUE: RefCell<VecDeque<(u64, String)>> = const { RefCell::new(VecDeque::new()) };
+    // The image being enrolled, recorded as failed if it traps
+    static CURRENT: RefCell<Option<(u64, String)>> = const { RefCell::new(None) };
+    static SCHEDULED: Cell<bool> = const { Cell::new(false) };
+    static WATCHDOG: Cell<Option<ic_cdk_timers::TimerId>> = const { Cell::new(None) };
+}
+
+fn with_batch<T>(batch_id: u64, f: impl FnOnce(&mut Batch) -> Result<T, String>) -> Result<T, String> {
+    BATCHES.with_borrow_mut(|batches| {
+        let batch = batches.get_mut(&batch_id).ok_or("Enrollment batch not found")?;
+        f(batch)
+    })
+}
+
+fn record(batch_id: u64, name: String, result: Result<u64, String>) {
+    let _ = with_batch(batch_id, |batch| {
+        batch.images.remove(&name);
+        batch.report.remaining = batch.report.remaining.saturating_sub(1);
+        match result {
+            Ok(reference_id) => batch.report.enrolled.push(EnrolledImage { name, reference_id }),
+            Err(error) => batch.report.failures.push(EnrollmentFailure { name, error }),
+        }
+        Ok(())
+    });
+}
+
+/// Enrolls an uploaded image: it must decode and show a face.
+fn enroll(batch_id: u64, name: &str) -> Result<u64, String> {
+    let (label, negative, chunks) = with_batch(batch_id, |batch| {
+        let chunks = batch.images.get(name).ok_or("Image not found")?;
+        Ok((batch.report.label.clone(), batch.report.negative, chunks.clone()))
+    })?;
+    if let Some(missing) = chunks.iter().position(Vec::is_empty) {
+        return Err(format!("Chunk {} is missing", missing));
+    }
+    let image = chunks.concat();
+    let decoded = onnx::decode(&image).map_err(|err| format!("Invalid image: {}", err))?.to_rgb8();
+    let (reference_id, _) = onnx::add_decoded(label, image, &decoded, negative).map_err(|err| err.to_string())?;
+    Ok(reference_id)
+}
+
+fn schedule() {
+    if !SCHEDULED.replace(true) {
+        ic_cdk_timers::set_timer(Duration::ZERO, begin_image);
+    }
+}
+
+/// Takes the next image off the queue in its own message, so that an image
+/// that traps is not retried forever.
+fn begin_image() {
+    SCHEDULED.set(false);
+    if let Some((batch_id, name)) = CURRENT.take() {
+        record(batch_id, name, Err("Enrollment trapped".to_string()));
+    }
+    if pause::is_paused(PauseSwitch::Enrollment) {
+        return;
+    }
+    let Some(next) = QUEUE.with_borrow_mut(|queue| queue.pop_front()) else {
+        return;
+    };
+    CURRENT.set(Some(next));
+    WATCHDOG.set(Some(ic_cdk_timers::set_timer(WATCHDOG_DELAY, begin_image)));
+    ic_cdk_timers::set_timer(Duration::ZERO, run_image);
+}
+
+fn run_image() {
+    if let Some(watchdog) = WATCHDOG.take() {
+        ic_cdk_timers::clear_timer(watchdog);
+    }
+    if let Some((batch_id, name)) = CURRENT.take() {
+        let result = enroll(batch_id, &name);
+        record(batch_id, name, result);
+    }
+    if QUEUE.with_borrow(|queue| !queue.is_empty()) {
+        schedule();
+    }
+}
+
+/// Continues the committed images after enrollment is resumed.
+pub fn resume() {
+    if QUEUE.with_borrow(|queue| !queue.is_empty()) {
+        schedule();
+    }
+}
+
+/// Number of committed images that still have to be enrolled.
+pub fn pending() -> u64 {
+    QUEUE.with_borrow(|queue| queue.len() as u64) + CURRENT.with_borrow(|current| current.is_some() as u64)
+}
+
+/// Opens a batch of images to enroll under one identity and returns its ID.
+#[ic_cdk::update(guard = "is_admin")]
+fn start_enrollment(label: String, negative: bool) -> Result<u64, ServiceError> {
+    pause::check(PauseSwitch::Enrollment)?;
+    if label.trim().is_empty() {
+        return Err("The label must not be empty".into());
+    }
+    let batch_id = NEXT_BATCH_ID.get();
+    NEXT_BATCH_ID.set(batch_id + 1);
+    let report = EnrollmentReport {
+        label,
+        negative,
+        committed: false,
+        remaining: 0,
+        enrolled: Vec::new(),
+        failures: Vec::new(),
+    };
+    BATCHES.with_borrow_mut(|batches| batches.insert(batch_id, Batch { images: BTreeMap::new(), size: 0, report }));
+    Ok(batch_id)
+}
+
+/// Stores a chunk of an image of the batch. Chunks may arrive in any order.
+#[ic_cdk::update(guard = "is_admin")]
+fn put_enrollment_chunk(batch_id: u64, name: String, chunk_index: u32, chunk: Vec<u8>) -> Result<(), ServiceError> {
+    pause::check(PauseSwitch::Enrollment)?;
+    with_batch(batch_id, |batch| {
+        if batch.report.committed {
+            return Err("Enrollment batch is already committed".to_string());
+        }
+        if chunk_index >= MAX_CHUNKS {
+            return Err(format!("Images are limited to {} chunks", MAX_CHUNKS));
+        }
+        let chunks = batch.images.entry(name).or_default();
+        let index = chunk_index as usize;
+        if chunks.len() <= index {
+            chunks.resize(index + 1, Vec::new());
+        }
+        let size = batch.size - chunks[index].len() + chunk.len();
+        if size > MAX_BATCH_BYTES {
+            return Err(format!("Enrollment batches are limited to {} bytes", MAX_BATCH_BYTES));
+        }
+        chunks[index] = chunk;
+        batch.size = size;
+        Ok(())
+    })
+    .map_err(ServiceError::from)
+}
+
+/// Queues the uploaded images for enrollment. Follow the progress with
+/// `get_enrollment_report`.
+#[ic_cdk::update(guard = "is_admin")]
+fn commit_enrollment(batch_id: u64) -> Result<EnrollmentReport, ServiceError> {
+    pause::check(PauseSwitch::Enrollment)?;
+    let (names, report) = with_batch(batch_id, |batch| {
+        if batch.report.committed {
+            return Err("Enrollment batch is already committed".to_string());
+        }
+        if batch.images.is_empty() {
+            return Err("Enrollment batch has no images".to_string());
+        }
+        batch.report.committed = true;
+        batch.report.remaining = batch.images.len() as u64;
+        Ok((batch.images.keys().cloned().collect::<Vec<_>>(), batch.report.clone()))
+    })?;
+    QUEUE.with_borrow_mut(|queue| queue.extend(names.into_iter().map(|name| (batch_id, name))));
+    schedule();
+    Ok(report)
+}
+
+#[ic_cdk::query(guard = "is_admin")]
+fn get_enrollment_report(batch_id: u64) -> Option<EnrollmentReport> {
+    BATCHES.with_borrow(|batches| batches.get(&batch_id).map(|batch| batch.report.clone()))
+}
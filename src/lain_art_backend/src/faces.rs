@@ -0,0 +1,185 @@
+//! Export and import of the face database.
+//!
+//! `export_faces` returns the references page by page as a `FaceSnapshot`, the
+//! Candid format that `import_faces` takes back, so the pages can be saved as a
+//! backup or imported into another canister, e.g. for staging. Snapshots carry
+//! the embeddings with the version of the model that computed them and the
+//! SHA-256 of the reference image, but not the images themselves; imported
+//! references cannot be re-embedded.
+
+use candid::{CandidType, Deserialize};
+use std::collections::BTreeSet;
+use sha2::{Digest, Sha256};
+
+use crate::auth::is_admin;
+use crate::onnx;
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::storage::{self, FaceReference};
+
+/// Version of the snapshot format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Largest number of references per export page.
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportedFace {
+    pub id: u64,
+    pub label: String,
+    pub negative: bool,
+    pub embedding: Vec<f32>,
+    pub model_version: Option<String>,
+    /// Version of the embedding pipeline, `None` in snapshots from before it was recorded.
+    pub pipeline: Option<u32>,
+    /// SHA-256 of the reference image, if it is stored.
+    pub image_sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FaceSnapshot {
+    pub version: u32,
+    pub faces: Vec<ExportedFace>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FacePage {
+    pub snapshot: FaceSnapshot,
+    /// ID to continue the export from, if there are more references.
+    pub next: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ImportMode {
+    /// Adds the references under new IDs, skipping those already enrolled.
+    Merge,
+    /// Replaces the whole database, keeping the IDs of the snapshot.
+    Replace,
+    /// Adds the references under the IDs of the snapshot, which must be free.
+    /// Imports the pages after the first `Replace` one.
+    Append,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImportSummary {
+    pub imported: u64,
+    /// References that were already enrolled, or whose label is enrolled with
+    /// the other sign.
+    pub skipped: u64,
+}
+
+fn export(reference: FaceReference) -> ExportedFace {
+    let image_sha256 = storage::face_image(reference.id).map(|image| Sha256::digest(image).to_vec());
+    ExportedFace {
+        id: reference.id,
+        negative: reference.is_negative(),
+        label: reference.label,
+        embedding: reference.embedding,
+        model_version: reference.model_version,
+        pipeline: reference.pipeline,
+        image_sha256,
+    }
+}
+
+fn validate(snapshot: &FaceSnapshot) -> Result<(), String> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(format!("Unsupported snapshot version {}", snapshot.version));
+    }
+    let mut ids = BTreeSet::new();
+    for face in &snapshot.faces {
+        if face.label.trim().is_empty() || face.embedding.is_empty() {
+            return Err(format!("Reference {} needs a label and an embedding", face.id));
+        }
+        if !ids.insert(face.id) {
+            return Err(format!("Reference {} appears twice", face.id));
+        }
+    }
+    Ok(())
+}
+
+/// Returns up to `limit` references starting at the ID `start`.
+#[ic_cdk::query(guard = "is_admin")]
+fn export_faces(start: u64, limit: u32) -> FacePage {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut references = storage::faces_from(start, limit + 1);
+    let next = if references.len() > limit { references.pop().map(|reference| reference.id) } else { None };
+    let faces = references.into_iter().map(export).collect();
+    FacePage { snapshot: FaceSnapshot { version: SNAPSHOT_VERSION, faces }, next }
+}
+
+/// Imports a snapshot. A snapshot too large for one message can be imported
+/// page by page, keeping the IDs: the first page with `Replace`, the others
+/// with `Append`. Embeddings exported before they were normalized are
+/// normalized on import.
+#[ic_cdk::update(guard = "is_admin")]
+fn import_faces(snapshot: FaceSnapshot, mode: ImportMode) -> Result<ImportSummary, ServiceError> {
+    pause::check(PauseSwitch::Enrollment)?;
+    validate(&snapshot)?;
+    let mut snapshot = snapshot;
+    for face in &mut snapshot.faces {
+        onnx::normalize_if_needed(&mut face.embedding);
+    }
+    let mut summary = ImportSummary { imported: 0, skipped: 0 };
+    match mode {
+        ImportMode::Replace => {
+            storage::clear_face_database();
+            for face in snapshot.faces {
+                storage::insert_face_reference(FaceReference {
+                    id: face.id,
+                    label: face.label,
+                    embedding: face.embedding,
+                    negative: Some(face.negative),
+                    model_version: face.model_version,
+                    pipeline: face.pipeline,
+                });
+                summary.imported += 1;
+            }
+        }
+        ImportMode::Append => {
+            if let Some(face) = snapshot.faces.iter().find(|face| storage::get_face_reference(face.id).is_some()) {
+                return Err(format!("Reference {} already exists", face.id).into());
+            }
+            for face in snapshot.faces {
+                storage::insert_face_reference(FaceReference {
+                    id: face.id,
+                    label: face.label,
+                    embedding: face.embedding,
+                    negative: Some(face.negative),
+                    model_version: face.model_version,
+                    pipeline: face.pipeline,
+                });
+                summary.imported += 1;
+            }
+        }
+        ImportMode::Merge => {
+            let mut references = storage::get_all_faces();
+            for face in snapshot.faces {
+                let skip = references.iter().any(|reference| {
+                    reference.label == face.label
+                        && (reference.is_negative() != face.negative || reference.embedding == face.embedding)
+                });
+                if skip {
+                    summary.skipped += 1;
+                    continue;
+                }
+                let id = storage::add_face_to_database(
+                    face.label.clone(),
+                    face.embedding.clone(),
+                    face.negative,
+                    face.model_version.clone(),
+                    face.pipeline,
+                    None,
+                );
+                references.push(FaceReference {
+                    id,
+                    label: face.label,
+                    embedding: face.embedding,
+                    negative: Some(face.negative),
+                    model_version: face.model_version,
+                    pipeline: face.pipeline,
+                });
+                summary.imported += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,412 @@
+//! Submission fees and refunds.
+//!
+//! Each submission has its own fee subaccount of this canister, returned by
+//! `get_fee_invoice`. `confirm_fee` checks its balance on the ledger and marks
+//! the fee as paid, recording the account it was paid from. When a paid
+//! submission is rejected, the fee is refunded to that account according to
+//! the refund policy of the rejection reason, once the rejection is final
+//! (see `appeals`). Rejections are verdicts about the artwork; when
+//! verification cannot run, the submission is not rejected and nothing is kept.
+//!
+//! Once the fee is settled (the artwork is approved, or the refund is paid or
+//! not owed), what the canister keeps is swept from the submission's fee
+//! subaccount to the fee treasury.
+//!
+//! Refunds are retried like reward payouts: with the `created_at_time` of the
+//! first attempt, and after the ledger's deduplication window only if the
+//! ledger's ICRC-3 block log does not hold the refund already.
+
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableCell;
+use num_traits::ToPrimitive;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::appeals;
+use crate::auth::is_admin;
+use crate::backup::not_restoring;
+use crate::pause::{self, PauseSwitch};
+use crate::storage::{self, Mem, FEE_CONFIG_MEMORY_ID};
+use crate::submissions::{self, FeePayment, FeeSweep, Refund, RejectionKind, Status, Submission};
+use crate::transactions::{self, Account, FeeInvoice, TransferArg, TransferFailure};
+
+const ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+/// How much of the fee is returned for a given rejection reason.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RefundPolicy {
+    Full,
+    /// Refunds the given share of the fee, in basis points (10000 = 100%).
+    Partial { basis_points: u16 },
+    None,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RefundConfig {
+    pub invalid_image: RefundPolicy,
+    pub no_face: RefundPolicy,
+    pub not_lain: RefundPolicy,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeeConfig {
+    /// The ICRC-1 ledger the fees are paid on.
+    pub ledger: Principal,
+    /// Fee per submission, in the ledger's smallest unit. Zero disables fees.
+    pub amount: Nat,
+    pub refunds: RefundConfig,
+    /// Subaccount of this canister that collects the kept fees. `None` is the
+    /// default account.
+    pub treasury_subaccount: Option<Vec<u8>>,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        Self {
+            ledger: Principal::from_text(ICP_LEDGER).unwrap(),
+            amount: Nat::from(10_000_000u64), // 0.1 ICP in e8s
+            refunds: RefundConfig {
+                invalid_image: RefundPolicy::Full,
+                no_face: RefundPolicy::Full,
+                not_lain: RefundPolicy::Partial { basis_points: 5_000 },
+            },
+            treasury_subaccount: None,
+        }
+    }
+}
+
+impl Storable for FeeConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<FeeConfig, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(FEE_CONFIG_MEMORY_ID), FeeConfig::default())
+            .expect("Failed to init fee config")
+    );
+
+    // Submissions with a ledger call in flight (fee confirmation or refund)
+    static IN_PROGRESS: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+}
+
+pub fn config() -> FeeConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+/// Whether submissions must pay a fee before verification.
+pub fn required() -> bool {
+    config().amount != Nat::from(0u64)
+}
+
+pub fn invoice(submission_id: u64) -> FeeInvoice {
+    FeeInvoice {
+        amount: config().amount,
+        subaccount: Some(transactions::submission_subaccount(submission_id)),
+        memo: Some(Nat::from(submission_id)),
+    }
+}
+
+fn fee_account(submission_id: u64) -> Account {
+    Account::new(ic_cdk::id(), Some(transactions::submission_subaccount(submission_id)))
+}
+
+/// Marks a ledger call for a submission as in progress for as long as it is alive.
+struct LedgerGuard(u64);
+
+impl LedgerGuard {
+    fn acquire(submission_id: u64) -> Result<Self, String> {
+        IN_PROGRESS.with(|in_progress| {
+            if in_progress.borrow_mut().insert(submission_id) {
+                Ok(Self(submission_id))
+            } else {
+                Err("A ledger operation for this submission is already in progress".to_string())
+            }
+        })
+    }
+}
+
+impl Drop for LedgerGuard {
+    fn drop(&mut self) {
+        IN_PROGRESS.with(|in_progress| in_progress.borrow_mut().remove(&self.0));
+    }
+}
+
+fn refund_amount(policy: &RefundPolicy, paid: &Nat) -> Nat {
+    match policy {
+        RefundPolicy::Full => paid.clone(),
+        RefundPolicy::Partial { basis_points } => {
+            paid.clone() * Nat::from((*basis_points).min(10_000)) / Nat::from(10_000u64)
+        }
+        RefundPolicy::None => Nat::from(0u64),
+    }
+}
+
+/// The share of the fee owed back for the rejection of a submission, before
+/// the ledger fee.
+fn refund_due(sub: &Submission, config: &FeeConfig) -> Nat {
+    let (Some(payment), Some(kind)) = (&sub.fee, sub.rejection) else {
+        return Nat::from(0u64);
+    };
+    let policy = match kind {
+        RejectionKind::InvalidImage => &config.refunds.invalid_image,
+        RejectionKind::NoFace => &config.refunds.no_face,
+        RejectionKind::NotLain => &config.refunds.not_lain,
+        // Copies are never refunded.
+        RejectionKind::Duplicate => &RefundPolicy::None,
+    };
+    refund_amount(policy, &payment.amount)
+}
+
+/// Refunds the fee of a rejected submission according to the refund policy.
+/// Returns `None` if nothing is owed. Idempotent, like reward payouts.
+pub async fn refund(submission_id: u64) -> Result<Option<Refund>, String> {
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if let Some(refund) = sub.refund {
+        return Ok(Some(refund));
+    }
+    if !matches!(sub.status, Status::Rejected { .. }) {
+        return Err("Only rejected submissions can be refunded".to_string());
+    }
+    if appeals::window_remaining(&sub) > 0 {
+        return Err("The refund is held until the appeal window closes".to_string());
+    }
+    let config = config();
+    let amount = refund_due(&sub, &config);
+    let Some(payment) = sub.fee.filter(|_| amount > Nat::from(0u64)) else {
+        return Ok(None);
+    };
+    pause::check(PauseSwitch::Rewards)?;
+
+    let _guard = LedgerGuard::acquire(submission_id)?;
+
+    // The ledger fee is paid from the refunded amount.
+    let ledger_fee = transactions::icrc1_fee(config.ledger).await?;
+    if amount <= ledger_fee {
+        return Ok(None);
+    }
+
+    let created_at_time = sub.refund_created_at.unwrap_or_else(ic_cdk::api::time);
+    submissions::update(submission_id, |sub| sub.refund_created_at = Some(created_at_time));
+
+    let amount = amount - ledger_fee;
+    let transfer = |created_at_time: u64| TransferArg {
+        from_subaccount: Some(transactions::submission_subaccount(submission_id)),
+        to: payment.payer.clone(),
+        amount: amount.clone(),
+        fee: None,
+        memo: Some(transactions::submission_memo(submission_id)),
+        created_at_time: Some(created_at_time),
+    };
+    let block_index = match transactions::icrc1_transfer(config.ledger, transfer(created_at_time)).await {
+        Ok(block_index) => block_index,
+        Err(TransferFailure::TooOld) => match transactions::find_transfer(config.ledger, &transfer(created_at_time)).await? {
+            Some(block_index) => block_index,
+            None => {
+                // No earlier attempt reached the ledger, so the refund is sent again with a fresh time
+                let created_at_time = ic_cdk::api::time();
+                submissions::update(submission_id, |sub| sub.refund_created_at = Some(created_at_time));
+                transactions::icrc1_transfer(config.ledger, transfer(created_at_time)).await?
+            }
+        },
+        Err(TransferFailure::Refused(err)) => {
+            // The ledger checks for duplicates first, so no attempt was executed
+            submissions::update(submission_id, |sub| sub.refund_created_at = None);
+            return Err(err);
+        }
+        Err(failure) => return Err(failure.into()),
+    };
+
+    let refund = Refund {
+        amount,
+        block_index: block_index.0.to_string(),
+    };
+    submissions::update(submission_id, |sub| sub.refund = Some(refund.clone()));
+    ic_cdk::println!("[Refund] Submission {} refunded in block {}", submission_id, refund.block_index);
+    Ok(Some(refund))
+}
+
+/// Refunds and then sweeps the fee of a rejected submission once the
+/// rejection is final: right away if it can no longer be appealed, otherwise
+/// when the appeal window closes.
+pub fn settle_rejection(submission_id: u64) {
+    let Some(sub) = submissions::get(submission_id) else {
+        return;
+    };
+    let delay = Duration::from_nanos(appeals::window_remaining(&sub));
+    ic_cdk_timers::set_timer(delay, move || {
+        ic_cdk::spawn(async move {
+            if let Err(err) = refund(submission_id).await {
+                ic_cdk::println!("[Refund] Refund for submission {} failed: {}", submission_id, err);
+                return;
+            }
+            if let Err(err) = sweep(submission_id).await {
+                ic_cdk::println!("[Fee] Sweep of submission {} failed: {}", submission_id, err);
+            }
+        })
+    });
+}
+
+/// Schedules the settlement of rejected submissions again after an upgrade,
+/// which drops the timers of refunds held for the appeal window.
+pub fn resume_settlements() {
+    let pending = submissions::filter(|sub| {
+        matches!(sub.status, Status::Rejected { .. }) && sub.fee.is_some() && sub.fee_sweep.is_none()
+    });
+    for sub in pending {
+        settle_rejection(sub.id);
+    }
+}
+
+/// Whether the fee of a submission is settled: nothing of it is owed back anymore.
+fn settled(sub: &Submission, config: &FeeConfig) -> bool {
+    match sub.status {
+        Status::Verified { .. } | Status::Rewarded => true,
+        Status::Rejected { .. } => {
+            appeals::window_remaining(sub) == 0
+                && (sub.refund.is_some() || refund_due(sub, config) == Nat::from(0u64))
+        }
+        _ => false,
+    }
+}
+
+/// Moves the fee a submission keeps from its fee subaccount to the fee
+/// treasury, once the fee is settled. Idempotent: the whole balance of the
+/// subaccount is swept, so a repeated sweep finds nothing to move.
+pub async fn sweep(submission_id: u64) -> Result<Option<FeeSweep>, String> {
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if sub.fee_sweep.is_some() || sub.fee.is_none() {
+        return Ok(sub.fee_sweep);
+    }
+    let config = config();
+    if !settled(&sub, &config) {
+        return Err("The fee of this submission is not settled yet".to_string());
+    }
+
+    let _guard = LedgerGuard::acquire(submission_id)?;
+    let balance = transactions::icrc1_balance_of(config.ledger, fee_account(submission_id)).await?;
+    let ledger_fee = transactions::icrc1_fee(config.ledger).await?;
+    let mut sweep = FeeSweep { amount: Nat::from(0u64), block_index: None, swept_at: ic_cdk::api::time() };
+    if balance > ledger_fee {
+        let amount = balance - ledger_fee;
+        let block_index = transactions::icrc1_transfer(
+            config.ledger,
+            TransferArg {
+                from_subaccount: Some(transactions::submission_subaccount(submission_id)),
+                to: Account::new(ic_cdk::id(), config.treasury_subaccount),
+                amount: amount.clone(),
+                fee: None,
+                memo: Some(transactions::submission_memo(submission_id)),
+                created_at_time: None,
+            },
+        )
+        .await?;
+        sweep.amount = amount;
+        sweep.block_index = Some(block_index.0.to_string());
+    }
+    submissions::update(submission_id, |sub| sub.fee_sweep = Some(sweep.clone()));
+    ic_cdk::println!("[Fee] Submission {} swept {} to the treasury", submission_id, sweep.amount);
+    Ok(Some(sweep))
+}
+
+/// Returns the current fee and refund configuration.
+#[ic_cdk::query]
+fn get_fee_config() -> FeeConfig {
+    config()
+}
+
+/// Sets the fee and refund configuration. Enabled fees need a ledger that
+/// implements ICRC-3, to look up refunds, and a treasury subaccount of 32 bytes.
+#[ic_cdk::update(guard = "is_admin")]
+async fn set_fee_config(config: FeeConfig) {
+    if let Err(err) = Account::new(ic_cdk::id(), config.treasury_subaccount.clone()).validate() {
+        ic_cdk::trap(&format!("Invalid treasury subaccount: {}", err));
+    }
+    if config.amount != Nat::from(0u64) {
+        if let Err(err) = transactions::check_ledger(config.ledger).await {
+            ic_cdk::trap(&err);
+        }
+    }
+    CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store fee config"));
+    });
+}
+
+/// Checks that the fee of a submission has been paid into its fee subaccount
+/// and records the payment. `payer` is the account the fee was sent from,
+/// which refunds go back to; it defaults to the default account of the creator.
+#[ic_cdk::update(guard = "not_restoring")]
+async fn confirm_fee(submission_id: Nat, payer: Option<Account>) -> Result<(), String> {
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    if let Some(payer) = &payer {
+        payer.validate()?;
+    }
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if sub.creator != ic_cdk::caller() {
+        return Err("Only the creator can confirm the fee".to_string());
+    }
+    if sub.fee_paid {
+        return Ok(());
+    }
+
+    let config = config();
+    let _guard = LedgerGuard::acquire(submission_id)?;
+    let balance = transactions::icrc1_balance_of(config.ledger, fee_account(submission_id)).await?;
+    if balance < config.amount {
+        return Err(format!("Fee not received yet: expected {}, found {}", config.amount, balance));
+    }
+
+    submissions::update(submission_id, |sub| {
+        sub.fee_paid = true;
+        sub.fee = Some(FeePayment {
+            payer: payer.unwrap_or_else(|| Account::new(sub.creator, None)),
+            amount: balance,
+            paid_at: ic_cdk::api::time(),
+        });
+        if sub.status == Status::AwaitingFee {
+            sub.status = Status::Verifying;
+        }
+    });
+    Ok(())
+}
+
+/// Sweeps the settled fees of up to `limit` submissions to the fee treasury
+/// and returns how many were swept. Fees are also swept automatically when
+/// they are settled; this catches up on sweeps that failed.
+#[ic_cdk::update(guard = "is_admin")]
+async fn sweep_fees(limit: u32) -> u64 {
+    let config = config();
+    let pending = submissions::filter(|sub| sub.fee.is_some() && sub.fee_sweep.is_none() && settled(sub, &config));
+    let mut swept = 0;
+    for sub in pending.into_iter().take(limit as usize) {
+        match sweep(sub.id).await {
+            Ok(_) => swept += 1,
+            Err(err) => ic_cdk::println!("[Fee] Sweep of submission {} failed: {}", sub.id, err),
+        }
+    }
+    swept
+}
+
+/// Retries (or returns the already made) refund of a rejected submission.
+/// Callable by the creator of the submission and by controllers.
+#[ic_cdk::update(guard = "not_restoring")]
+async fn finalize_refund(submission_id: Nat) -> Result<Option<Refund>, String> {
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if sub.creator != ic_cdk::caller() && is_admin().is_err() {
+        return Err("Only the creator can finalize the refund".to_string());
+    }
+    refund(submission_id).await
+}
@@ -0,0 +1,69 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableBTreeMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::storage::{self, Mem, GALLERY_MEMORY_ID};
+
+/// Approved artwork that has been verified to contain Lain
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ApprovedArtwork {
+    pub id: u64,
+    pub creator: Principal,
+    pub image_data: Vec<u8>,
+    pub mime_type: String,
+    pub timestamp: u64,
+    pub recognition_score: f32,
+    /// SHA-256 of `image_data`, computed by the canister.
+    pub image_sha256: Vec<u8>,
+    /// The ICRC-7 token minted to the creator for this artwork.
+    pub nft_token_id: Option<u64>,
+    /// Perceptual hash of `image_data`, see `phash::dhash`.
+    pub phash: Option<u64>,
+    /// Scores on a 0-10000 scale, see `scoring`. Also recorded on the submission.
+    pub originality: Option<u16>,
+    pub visibility: Option<u16>,
+}
+
+impl Storable for ApprovedArtwork {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Stable gallery: artwork id -> approved artwork
+    static GALLERY: RefCell<StableBTreeMap<u64, ApprovedArtwork, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(GALLERY_MEMORY_ID))
+    );
+}
+
+/// Returns the ID the next approved artwork will get.
+pub fn next_id() -> u64 {
+    GALLERY.with(|gallery| gallery.borrow().len())
+}
+
+pub fn insert(artwork: ApprovedArtwork) {
+    GALLERY.with(|gallery| {
+        gallery.borrow_mut().insert(artwork.id, artwork);
+    });
+}
+
+pub fn get(id: u64) -> Option<ApprovedArtwork> {
+    GALLERY.with(|gallery| gallery.borrow().get(&id))
+}
+
+pub fn list() -> Vec<ApprovedArtwork> {
+    GALLERY.with(|gallery| gallery.borrow().iter().map(|(_, artwork)| artwork).collect())
+}
+
+pub fn count() -> u64 {
+    GALLERY.with(|gallery| gallery.borrow().len())
+}
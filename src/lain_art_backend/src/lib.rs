@@ -1,21 +1,25 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Decode, Deserialize, Encode};
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager},
-    DefaultMemoryImpl,
+    storable::{Bound, Storable},
+    StableBTreeMap,
 };
+use std::borrow::Cow;
 
 use num_traits::ToPrimitive;
+use sha2::{Digest, Sha256};
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use ic_cdk::caller;
-// --- Upload submission state (in-memory, not stable) ---
+// --- Upload submission state (in-memory, flushed to stable memory before an upgrade) ---
 thread_local! {
     static SUBMISSIONS: RefCell<HashMap<u64, SubmissionData>> = RefCell::new(HashMap::new());
-    static NEXT_SUBMISSION_ID: RefCell<u64> = RefCell::new(1);
-    static APPROVED_ARTWORK: RefCell<Vec<ApprovedArtwork>> = RefCell::new(Vec::new());
+    static FLUSHED_SUBMISSIONS: RefCell<StableBTreeMap<u64, SubmissionData, storage::Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::UPLOADS_MEMORY_ID))
+    );
 }
 
 #[allow(dead_code)]
+#[derive(CandidType, Deserialize, Clone)]
 struct SubmissionData {
     creator: candid::Principal,
     chunks: Vec<Vec<u8>>,
@@ -24,41 +28,137 @@ struct SubmissionData {
     sha256: Option<Vec<u8>>,
 }
 
-/// Approved artwork that has been verified to contain Lain
-#[derive(CandidType, Deserialize, Clone)]
-pub struct ApprovedArtwork {
-    pub id: u64,
-    pub creator: candid::Principal,
-    pub image_data: Vec<u8>,
-    pub mime_type: String,
-    pub timestamp: u64,
-    pub recognition_score: f32,
-}
-use onnx::{setup, BoundingBox, Embedding, Person};
+impl Storable for SubmissionData {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Whether a submission still needs its upload, i.e. it has not been decided yet.
+fn is_open(submission_id: u64) -> bool {
+    submissions::get(submission_id).is_some_and(|sub| {
+        matches!(
+            sub.status,
+            Status::PendingUpload | Status::AwaitingFee | Status::Verifying | Status::Processing { .. }
+        )
+    })
+}
+
+/// Copies the uploads of open submissions to stable memory and returns their number.
+pub(crate) fn flush_uploads() -> u64 {
+    FLUSHED_SUBMISSIONS.with(|flushed| {
+        let mut flushed = flushed.borrow_mut();
+        let ids: Vec<u64> = flushed.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            flushed.remove(&id);
+        }
+        SUBMISSIONS.with(|subs| {
+            for (id, sub) in subs.borrow().iter().filter(|(id, _)| is_open(**id)) {
+                flushed.insert(*id, sub.clone());
+            }
+        });
+        flushed.len()
+    })
+}
+
+/// Gives a submission whose upload was lost an empty upload buffer, unless it
+/// still has one.
+pub(crate) fn reopen_upload(submission_id: u64, creator: candid::Principal) {
+    SUBMISSIONS.with(|subs| {
+        subs.borrow_mut().entry(submission_id).or_insert_with(|| SubmissionData {
+            creator,
+            chunks: Vec::new(),
+            mime: None,
+            size: None,
+            sha256: None,
+        });
+    });
+}
+
+/// Moves the uploads flushed before an upgrade back into the upload buffer.
+fn restore_uploads() {
+    let uploads: Vec<(u64, SubmissionData)> = FLUSHED_SUBMISSIONS.with(|flushed| flushed.borrow().iter().collect());
+    for (id, sub) in uploads {
+        SUBMISSIONS.with(|subs| subs.borrow_mut().insert(id, sub));
+        FLUSHED_SUBMISSIONS.with(|flushed| flushed.borrow_mut().remove(&id));
+        // Older versions flushed the uploads of decided submissions as well.
+        if !is_open(id) {
+            release_upload(id);
+        }
+    }
+}
+
+/// Drops the upload of a decided submission from the upload buffer. The image
+/// of a rejection that can still be appealed is moved to the review images.
+pub(crate) fn release_upload(submission_id: u64) {
+    review::keep_for_appeal(submission_id);
+    SUBMISSIONS.with(|subs| subs.borrow_mut().remove(&submission_id));
+}
+
+pub use appeals::{Appeal, AppealOutcome};
+pub use backup::{BackupChunk, BackupManifest};
+pub use detection::{DetectionMode, DetectorModel};
+pub use enrollment::{EnrolledImage, EnrollmentFailure, EnrollmentReport};
+pub use faces::{ExportedFace, FacePage, FaceSnapshot, ImportMode, ImportSummary};
+pub use fees::{FeeConfig, RefundConfig, RefundPolicy};
+pub use gallery::ApprovedArtwork;
+pub use pause::{Pause, PauseStatus, PauseSwitch, Paused, ServiceError};
+pub use phash::{DuplicateMatch, HashMatch, ReferenceFrame};
+pub use pipeline::VerificationStep;
+pub use policy::VerificationPolicy;
+pub use preprocess::{ChannelOrder, ModelKind, PreprocessSpec, ResizeFilter, ResizeMode, TensorLayout};
+pub use reembed::{ReembedFailure, ReembedStatus};
+pub use review::{Decision, DecisionKind, Review};
+pub use rewards::RewardConfig;
+pub use submissions::{ArtMeta, FeePayment, FeeSweep, Refund, RejectionKind, RewardResult, RewardTransfer, Status, Submission};
+pub use upgrade::{SelfTest, UpgradeReadiness};
+use auth::is_admin;
+use backup::not_restoring;
+use onnx::{setup, BoundingBox, Embedding, Metric, Person, Ranking};
 use std::cell::RefCell;
 
-mod onnx;
+mod appeals;
+mod auth;
+mod backup;
+pub mod detection;
+mod enrollment;
+mod faces;
+mod fees;
+mod gallery;
+mod nft;
+pub mod onnx;
+mod pause;
+mod phash;
+mod pipeline;
+mod policy;
+pub mod preprocess;
+mod reembed;
+mod review;
+mod rewards;
+mod schema;
+mod scoring;
 mod storage;
+mod submissions;
 mod transactions;
+mod upgrade;
 // --- Fee flow ---
 #[ic_cdk::query]
 fn get_fee_invoice(submission_id: candid::Nat) -> transactions::FeeInvoice {
-    transactions::get_fee_invoice(submission_id)
+    fees::invoice(submission_id.0.to_u64().expect("submission_id too large"))
 }
 
-// WASI polyfill requires a virtual stable memory to store the file system.
-// You can replace `0` with any index up to `254`.
-const WASI_MEMORY_ID: MemoryId = MemoryId::new(0);
-
 // Files in the WASI filesystem (in the stable memory) that store the models.
 const FACE_DETECTION_FILE: &str = "face-detection.onnx";
 const FACE_RECOGNITION_FILE: &str = "face-recognition.onnx";
 
-thread_local! {
-    // The memory manager is used for simulating multiple memories.
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-}
+// Maximum number of identities returned by `recognize_topk`.
+const MAX_TOPK: usize = 20;
 
 // --- Upload flow scaffolding ---
 
@@ -102,7 +202,7 @@ fn detect(image: Vec<u8>) -> Detection {
 /// Performs face recognition and returns the name of the person whose recorded
 /// face is closest to the face in the given image. It also returns the distance
 /// between the face embeddings.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "not_restoring")]
 fn recognize(image: Vec<u8>) -> Recognition {
     let result = match onnx::recognize(image) {
         Ok(result) => Recognition::Ok(result),
@@ -111,35 +211,69 @@ fn recognize(image: Vec<u8>) -> Recognition {
     result
 }
 
+/// Ranks the enrolled identities by the distance of their closest reference
+/// to the face in the given image, and returns the first `k` (at most 20)
+/// together with the margin between the first and the second.
+#[ic_cdk::update(guard = "not_restoring")]
+fn recognize_topk(image: Vec<u8>, k: u32) -> Result<Ranking, String> {
+    let k = (k as usize).clamp(1, MAX_TOPK);
+    onnx::embedding(image)
+        .and_then(|embedding| onnx::rank(&embedding, k))
+        .map_err(|err| err.to_string())
+}
+
 /// Adds a person with the given name (label) and face (image) for future
 /// face recognition requests.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "is_admin")]
 fn add(label: String, image: Vec<u8>) -> Addition {
-    let result = match onnx::add(label, image) {
+    if let Err(paused) = pause::check(PauseSwitch::Enrollment) {
+        return Addition::Err(paused.into());
+    }
+    let result = match onnx::add(label, image, false) {
         Ok(result) => Addition::Ok(result),
         Err(err) => Addition::Err(err.to_string()),
     };
     result
 }
 
+/// Adds a negative reference: a face of someone who is not Lain, e.g. from
+/// `laindb/nonlains`. Verification rejects faces that are closer to a negative
+/// identity than to Lain by more than `onnx::NEGATIVE_MARGIN`.
+#[ic_cdk::update(guard = "is_admin")]
+fn add_negative(label: String, image: Vec<u8>) -> Result<Embedding, ServiceError> {
+    pause::check(PauseSwitch::Enrollment)?;
+    onnx::add(label, image, true).map_err(|err| ServiceError::Failed(err.to_string()))
+}
+
+/// Traps while a backup is taken. The model files of the frontend's upload
+/// endpoints cannot return an error.
+fn check_models_writable() {
+    if let Err(paused) = pause::check_backup() {
+        ic_cdk::trap(&paused.to_string());
+    }
+}
+
 /// Clears the face detection model file.
 /// This is used for incremental chunk uploading of large files.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "is_admin")]
 fn clear_face_detection_model_bytes() {
+    check_models_writable();
     storage::clear_bytes(FACE_DETECTION_FILE);
 }
 
 /// Clears the face recognition model file.
 /// This is used for incremental chunk uploading of large files.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "is_admin")]
 fn clear_face_recognition_model_bytes() {
+    check_models_writable();
     storage::clear_bytes(FACE_RECOGNITION_FILE);
 }
 
 /// Appends the given chunk to the face detection model file.
 /// This is used for incremental chunk uploading of large files.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "is_admin")]
 fn append_face_detection_model_bytes(bytes: Vec<u8>) {
+    check_models_writable();
     ic_cdk::println!("[append_face_detection_model_bytes] Received {} bytes", bytes.len());
     ic_cdk::println!("[append_face_detection_model_bytes] First 100 bytes of chunk: {:?}", &bytes[..std::cmp::min(100, bytes.len())]);
     
@@ -154,8 +288,9 @@ fn append_face_detection_model_bytes(bytes: Vec<u8>) {
 
 /// Appends the given chunk to the face recognition model file.
 /// This is used for incremental chunk uploading of large files.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "is_admin")]
 fn append_face_recognition_model_bytes(bytes: Vec<u8>) {
+    check_models_writable();
     ic_cdk::println!("[append_face_recognition_model_bytes] Received {} bytes", bytes.len());
     ic_cdk::println!("[append_face_recognition_model_bytes] First 100 bytes of chunk: {:?}", &bytes[..std::cmp::min(100, bytes.len())]);
     
@@ -168,10 +303,59 @@ fn append_face_recognition_model_bytes(bytes: Vec<u8>) {
     ic_cdk::println!("[append_face_recognition_model_bytes] First 100 bytes of stable memory: {:?}", &stable_memory_content[..std::cmp::min(100, stable_memory_content.len())]);
 }
 
+/// Clears the optional Lain classifier model file.
+#[ic_cdk::update(guard = "is_admin")]
+fn clear_lain_classifier_model_bytes() -> Result<(), ServiceError> {
+    pause::check_backup()?;
+    storage::clear_lain_classifier_bytes();
+    Ok(())
+}
+
+/// Appends the given chunk to the optional Lain classifier model file.
+#[ic_cdk::update(guard = "is_admin")]
+fn append_lain_classifier_model_bytes(bytes: Vec<u8>) -> Result<(), ServiceError> {
+    pause::check_backup()?;
+    storage::append_lain_classifier_bytes(bytes);
+    Ok(())
+}
+
+/// Returns the metric face embeddings are compared with.
+#[ic_cdk::query]
+fn get_recognition_metric() -> Metric {
+    storage::recognition_metric()
+}
+
+/// Sets the metric face embeddings are compared with. It belongs to the
+/// recognition model, so set it together with the model.
+#[ic_cdk::update(guard = "is_admin")]
+fn set_recognition_metric(metric: Metric) {
+    storage::set_recognition_metric(metric);
+}
+
+/// Clears the optional anime face detection model file.
+#[ic_cdk::update(guard = "is_admin")]
+fn clear_anime_face_detection_model_bytes() -> Result<(), ServiceError> {
+    pause::check_backup()?;
+    storage::clear_anime_face_detection_bytes();
+    Ok(())
+}
+
+/// Appends the given chunk to the optional anime face detection model file.
+#[ic_cdk::update(guard = "is_admin")]
+fn append_anime_face_detection_model_bytes(bytes: Vec<u8>) -> Result<(), ServiceError> {
+    pause::check_backup()?;
+    storage::append_anime_face_detection_bytes(bytes);
+    Ok(())
+}
+
 /// Once the model files have been incrementally uploaded,
 /// this function loads them into in-memory models.
-#[ic_cdk::update]
+/// The Lain classifier and the anime face detector are loaded too if they were uploaded.
+/// The models can be replaced while verification is paused, so that a
+/// misbehaving model can be swapped out, but not while a backup is taken.
+#[ic_cdk::update(guard = "is_admin")]
 fn setup_models() -> Result<(), String> {
+    pause::check_backup()?;
     let face_detection_bytes = storage::face_detection_bytes();
     let face_recognition_bytes = storage::face_recognition_bytes();
     
@@ -179,31 +363,67 @@ fn setup_models() -> Result<(), String> {
     ic_cdk::println!("[Debug] Setting up face recognition model. Bytes size: {}", face_recognition_bytes.len());
     
     setup(face_detection_bytes, face_recognition_bytes)
-        .map_err(|err| format!("Failed to setup model: {}", err))
+        .map_err(|err| format!("Failed to setup model: {}", err))?;
+
+    let lain_classifier_bytes = storage::lain_classifier_bytes();
+    if !lain_classifier_bytes.is_empty() {
+        onnx::setup_classifier(lain_classifier_bytes)
+            .map_err(|err| format!("Failed to setup classifier: {}", err))?;
+    }
+
+    let anime_face_detection_bytes = storage::anime_face_detection_bytes();
+    if !anime_face_detection_bytes.is_empty() {
+        onnx::setup_anime_detector(anime_face_detection_bytes)
+            .map_err(|err| format!("Failed to setup anime face detector: {}", err))?;
+    }
+    Ok(())
 }
 
 #[ic_cdk::init]
 fn init() {
-    let wasi_memory = MEMORY_MANAGER.with(|m| m.borrow().get(WASI_MEMORY_ID));
+    let wasi_memory = storage::memory(storage::WASI_MEMORY_ID);
     ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
+    schema::init();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // Uploads are kept on the heap; `prepare_upgrade` flushes them as well, but
+    // an upgrade without it must not lose them either. After a restore, the
+    // stable memory holds the uploads of the backup, which must be kept.
+    if !backup::is_restoring() {
+        flush_uploads();
+    }
 }
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
-    let wasi_memory = MEMORY_MANAGER.with(|m| m.borrow().get(WASI_MEMORY_ID));
+    let wasi_memory = storage::memory(storage::WASI_MEMORY_ID);
     ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
+    schema::migrate();
+    restore_uploads();
+    pipeline::reset_interrupted();
+    fees::resume_settlements();
+    upgrade::schedule_self_test();
+}
+
+/// Traps if submissions are paused, by a controller or for an upgrade.
+fn check_uploads_open() {
+    let open = pause::check(PauseSwitch::Submissions)
+        .map_err(String::from)
+        .and_then(|()| upgrade::check_open());
+    if let Err(err) = open {
+        ic_cdk::trap(&err);
+    }
 }
 
 /// Starts a new submission and returns its unique ID as Candid Nat.
 /// The caller becomes the creator of the submission.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "not_restoring")]
 fn start_submission() -> candid::Nat {
-    let id = NEXT_SUBMISSION_ID.with(|next| {
-        let mut n = next.borrow_mut();
-        let id = *n;
-        *n += 1;
-        id
-    });
+    check_uploads_open();
+    let id = submissions::next_id();
+    submissions::create(id, caller());
     SUBMISSIONS.with(|subs| {
         subs.borrow_mut().insert(id, SubmissionData {
             creator: caller(),
@@ -217,11 +437,22 @@ fn start_submission() -> candid::Nat {
 }
 
 /// Appends a chunk of data to the submission with the given ID.
-/// Chunks are stored in the order of their chunk_index.
-#[ic_cdk::update]
+/// Chunks are stored in the order of their chunk_index. Only the creator can
+/// upload, and only until the asset is finalized, so that the image a
+/// submission was verified or rejected with, which an appeal is decided on,
+/// cannot be replaced.
+#[ic_cdk::update(guard = "not_restoring")]
 fn put_chunk(submission_id: candid::Nat, chunk_index: candid::Nat, chunk: Vec<u8>) {
+    check_uploads_open();
     let submission_id_u64: u64 = submission_id.0.to_u64().expect("submission_id too large");
     let chunk_index_u64: u64 = chunk_index.0.to_u64().expect("chunk_index too large");
+    let record = submissions::get(submission_id_u64).unwrap_or_else(|| ic_cdk::trap("Submission ID not found"));
+    if record.creator != caller() {
+        ic_cdk::trap("Only the creator can upload to a submission");
+    }
+    if record.status != Status::PendingUpload {
+        ic_cdk::trap("The upload of this submission is finalized");
+    }
     SUBMISSIONS.with(|subs| {
         let mut subs = subs.borrow_mut();
         if let Some(sub) = subs.get_mut(&submission_id_u64) {
@@ -237,13 +468,27 @@ fn put_chunk(submission_id: candid::Nat, chunk_index: candid::Nat, chunk: Vec<u8
 /// Finalizes the asset for the given submission.
 /// Records the MIME type, size, and SHA-256 hash of the uploaded file.
 /// Typically, this is where the asset would be persisted and verified.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "not_restoring")]
 fn finalize_asset(submission_id: candid::Nat, mime: String, size: candid::Nat, sha256: Vec<u8>) {
+    check_uploads_open();
     let submission_id_u64: u64 = submission_id.0.to_u64().expect("submission_id too large");
     let size_u64: u64 = size.0.to_u64().expect("size too large");
     SUBMISSIONS.with(|subs| {
         let mut subs = subs.borrow_mut();
         if let Some(sub) = subs.get_mut(&submission_id_u64) {
+            submissions::update(submission_id_u64, |record| {
+                record.art = Some(ArtMeta {
+                    mime: mime.clone(),
+                    size: size_u64,
+                    sha256: sha256.clone(),
+                    chunks: sub.chunks.len() as u64,
+                });
+                record.status = if record.fee_paid || !fees::required() {
+                    Status::Verifying
+                } else {
+                    Status::AwaitingFee
+                };
+            });
             sub.mime = Some(mime);
             sub.size = Some(size_u64);
             sub.sha256 = Some(sha256);
@@ -252,10 +497,17 @@ fn finalize_asset(submission_id: candid::Nat, mime: String, size: candid::Nat, s
     });
 }
 
+/// Returns the record of a submission, including its status.
+#[ic_cdk::query]
+fn get_submission(submission_id: candid::Nat) -> Option<Submission> {
+    submission_id.0.to_u64().and_then(submissions::get)
+}
+
 /// Runs face detection on the uploaded image for the given submission ID.
 /// Returns the bounding box of the detected face.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "not_restoring")]
 fn run_face_detection(submission_id: candid::Nat) -> Result<BoundingBox, String> {
+    pause::check(PauseSwitch::Verification)?;
     let submission_id_u64: u64 = submission_id.0.to_u64().expect("submission_id too large");
 
     SUBMISSIONS.with(|subs| {
@@ -284,114 +536,172 @@ fn run_face_detection(submission_id: candid::Nat) -> Result<BoundingBox, String>
     })
 }
 
-/// Verifies if Lain is present in the submitted artwork and stores it if verification passes.
-/// This is the main function for the artwork submission flow.
-#[ic_cdk::update]
-fn verify_and_store_artwork(submission_id: candid::Nat) -> Result<u64, String> {
-    let submission_id_u64: u64 = submission_id.0.to_u64().expect("submission_id too large");
-
+/// Returns the uploaded image of a submission while it is in the upload buffer.
+pub(crate) fn uploaded_image(submission_id: u64) -> Option<Vec<u8>> {
     SUBMISSIONS.with(|subs| {
-        let subs = subs.borrow();
-        if let Some(sub) = subs.get(&submission_id_u64) {
-            // Concatenate all chunks into a single image
-            let image_data: Vec<u8> = sub.chunks.iter().flatten().copied().collect();
-            
-            if image_data.is_empty() {
-                return Err("No image data found".to_string());
-            }
+        subs.borrow()
+            .get(&submission_id)
+            .map(|sub| sub.chunks.iter().flatten().copied().collect::<Vec<u8>>())
+            .filter(|image| !image.is_empty())
+    })
+}
 
-            ic_cdk::println!("[Verify Artwork] Processing submission {} with {} bytes", submission_id_u64, image_data.len());
-
-            // Step 1: Detect if there's a face
-            match detect(image_data.clone()) {
-                Detection::Ok(bounding_box) => {
-                    ic_cdk::println!("[Verify Artwork] Face detected: {:?}", bounding_box);
-                    
-                    // Step 2: Recognize if the face belongs to Lain
-                    match recognize(image_data.clone()) {
-                        Recognition::Ok(person) => {
-                            ic_cdk::println!("[Verify Artwork] Recognition result: {} with score {}", person.label, person.score);
-                            
-                            // Check if the recognized person is Lain
-                            if person.label.to_lowercase() == "lain" {
-                                // Verification successful! Store the artwork
-                                let artwork_id = APPROVED_ARTWORK.with(|artwork| {
-                                    let mut artwork = artwork.borrow_mut();
-                                    let new_id = artwork.len() as u64;
-                                    
-                                    artwork.push(ApprovedArtwork {
-                                        id: new_id,
-                                        creator: sub.creator,
-                                        image_data: image_data.clone(),
-                                        mime_type: sub.mime.clone().unwrap_or_else(|| "image/jpeg".to_string()),
-                                        timestamp: ic_cdk::api::time(),
-                                        recognition_score: person.score,
-                                    });
-                                    
-                                    ic_cdk::println!("[Verify Artwork] ✓ Artwork approved and stored with ID {}", new_id);
-                                    new_id
-                                });
-                                
-                                Ok(artwork_id)
-                            } else {
-                                Err(format!("Face recognition failed: Detected '{}' instead of 'Lain'", person.label))
-                            }
-                        }
-                        Recognition::Err(err) => {
-                            ic_cdk::println!("[Verify Artwork] Recognition error: {}", err);
-                            Err(format!("Face recognition failed: {}", err))
-                        }
-                    }
-                }
-                Detection::Err(err) => {
-                    ic_cdk::println!("[Verify Artwork] Detection error: {}", err);
-                    Err(format!("Face detection failed: {}", err))
-                }
+/// Stores approved artwork in the gallery, mints its NFT, marks the submission
+/// as verified and starts the reward payout. Returns the artwork ID.
+pub(crate) fn approve_artwork(
+    sub: &Submission,
+    image_data: Vec<u8>,
+    recognition_score: f32,
+    originality: u16,
+    visibility: u16,
+) -> u64 {
+    let mut artwork = ApprovedArtwork {
+        id: gallery::next_id(),
+        creator: sub.creator,
+        image_sha256: Sha256::digest(&image_data).to_vec(),
+        image_data,
+        mime_type: sub.art.as_ref().map_or_else(|| "image/jpeg".to_string(), |art| art.mime.clone()),
+        timestamp: ic_cdk::api::time(),
+        recognition_score,
+        nft_token_id: None,
+        phash: sub.phash,
+        originality: Some(originality),
+        visibility: Some(visibility),
+    };
+    let token_id = nft::mint(&artwork);
+    artwork.nft_token_id = Some(token_id);
+    let artwork_id = artwork.id;
+    gallery::insert(artwork);
+    if let Some(phash) = sub.phash {
+        phash::index_artwork(artwork_id, phash);
+    }
+
+    submissions::update(sub.id, |record| {
+        record.status = Status::Verified { originality, visibility };
+        record.artwork_id = Some(artwork_id);
+        record.nft_token_id = Some(token_id);
+    });
+    release_upload(sub.id);
+
+    if sub.fee.is_some() {
+        let submission_id = sub.id;
+        ic_cdk::spawn(async move {
+            if let Err(err) = fees::sweep(submission_id).await {
+                ic_cdk::println!("[Fee] Sweep of submission {} failed: {}", submission_id, err);
             }
-        } else {
-            Err("Submission ID not found".to_string())
-        }
-    })
+        });
+    }
+    if rewards::enabled() {
+        let submission_id = sub.id;
+        ic_cdk::spawn(async move {
+            if let Err(err) = rewards::pay_reward(submission_id).await {
+                ic_cdk::println!("[Reward] Payout for submission {} failed: {}", submission_id, err);
+            }
+        });
+    }
+    artwork_id
+}
+
+/// Marks a submission as rejected and starts the refund of its fee, if any.
+pub(crate) fn reject_submission(submission_id: u64, kind: RejectionKind, reason: String) {
+    let fee_paid = submissions::update(submission_id, |record| {
+        record.status = Status::Rejected { reason };
+        record.rejection = Some(kind);
+        record.rejected_at = Some(ic_cdk::api::time());
+        record.fee.is_some()
+    });
+    release_upload(submission_id);
+    review::drop_final_rejections();
+    if fee_paid == Some(true) {
+        fees::settle_rejection(submission_id);
+    }
+}
+
+/// Verifies if Lain is present in the submitted artwork and stores it if verification passes.
+/// This runs the whole pipeline in one call; `trigger_verification` runs it in the
+/// background instead, which is preferable for large images.
+#[ic_cdk::update(guard = "not_restoring")]
+fn verify_and_store_artwork(submission_id: candid::Nat) -> Result<u64, String> {
+    let submission_id_u64: u64 = submission_id.0.to_u64().expect("submission_id too large");
+    pipeline::verify_now(submission_id_u64)
 }
 
 /// Returns all approved artwork.
 #[ic_cdk::query]
 fn get_approved_artwork() -> Vec<ApprovedArtwork> {
-    APPROVED_ARTWORK.with(|artwork| artwork.borrow().clone())
+    gallery::list()
 }
 
 /// Returns a specific approved artwork by ID.
 #[ic_cdk::query]
 fn get_artwork_by_id(artwork_id: u64) -> Option<ApprovedArtwork> {
-    APPROVED_ARTWORK.with(|artwork| {
-        artwork.borrow().get(artwork_id as usize).cloned()
-    })
+    gallery::get(artwork_id)
 }
 
 /// Returns the count of approved artwork.
 #[ic_cdk::query]
 fn get_artwork_count() -> u64 {
-    APPROVED_ARTWORK.with(|artwork| artwork.borrow().len() as u64)
+    gallery::count()
 }
 
 /// Returns all stored face labels in the database.
 #[ic_cdk::query]
 fn list_stored_faces() -> Vec<String> {
-    storage::get_all_faces()
+    let labels: BTreeSet<String> = storage::get_all_faces()
         .into_iter()
-        .map(|(label, _)| label)
-        .collect()
+        .map(|reference| reference.label)
+        .collect();
+    labels.into_iter().collect()
 }
 
-/// Returns the number of faces stored in the database.
+/// Returns the number of faces (references) stored in the database.
 #[ic_cdk::query]
 fn get_face_count() -> candid::Nat {
     candid::Nat::from(storage::get_face_database_size())
 }
 
-/// Removes a face from the database by label.
-#[ic_cdk::update]
+/// Removes all faces of a label from the database. Traps while enrollment is
+/// paused, since the frontend expects no result.
+#[ic_cdk::update(guard = "is_admin")]
 fn remove_face(label: String) {
+    if let Err(paused) = pause::check(PauseSwitch::Enrollment) {
+        ic_cdk::trap(&paused.to_string());
+    }
     storage::remove_face_from_database(&label);
 }
 
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use candid_parser::utils::{service_compatible, service_equal, CandidSource};
+    use std::path::Path;
+
+    /// The committed interface must match the one generated from the Rust code.
+    /// Regenerate it with `./generate_candid.sh` after changing an endpoint.
+    #[test]
+    fn candid_interface_is_up_to_date() {
+        let generated = super::__export_service();
+        let committed = Path::new(env!("CARGO_MANIFEST_DIR")).join("lain_art_backend.did");
+        service_equal(CandidSource::Text(&generated), CandidSource::File(&committed))
+            .unwrap_or_else(|err| panic!("lain_art_backend.did is out of date: {}", err));
+    }
+
+    /// The canister must stay backwards compatible with the declarations the
+    /// frontend and scripts were built against.
+    #[test]
+    fn candid_interface_is_compatible_with_declarations() {
+        let generated = super::__export_service();
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        for declarations in [
+            src.join("declarations/lain_art_backend/lain_art_backend.did"),
+            src.join("lain_art_frontend/src/declarations/lain_art_backend/lain_art_backend.did"),
+        ] {
+            service_compatible(CandidSource::Text(&generated), CandidSource::File(&declarations))
+                .unwrap_or_else(|err| {
+                    panic!("Incompatible with {}: {}", declarations.display(), err)
+                });
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! ICRC-7 collection of approved artwork.
+//!
+//! Every artwork that passes verification is minted as a token owned by its
+//! creator. The token ID is the artwork ID, and the token metadata points back
+//! to the artwork, its image hash and its recognition score.
+//!
+//! Mints and transfers are recorded in a transaction log, indexed by the
+//! transaction ID that transfers return. A transfer with a `created_at_time`
+//! that repeats one of the log within the transaction window fails with
+//! `Duplicate`, so that clients can retry transfers safely.
+
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use num_traits::ToPrimitive;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::backup::not_restoring;
+use crate::gallery::ApprovedArtwork;
+use crate::storage::{self, Mem, NFT_TOKENS_MEMORY_ID, NFT_TRANSACTIONS_MEMORY_ID, NFT_TX_COUNTER_MEMORY_ID};
+use crate::transactions::Account;
+
+const SYMBOL: &str = "LAIN";
+const NAME: &str = "lain.art";
+const DESCRIPTION: &str = "Original Lain artwork verified by the lain.art canister.";
+
+const MAX_QUERY_BATCH_SIZE: usize = 100;
+const MAX_UPDATE_BATCH_SIZE: usize = 20;
+const DEFAULT_TAKE_VALUE: usize = 100;
+const MAX_TAKE_VALUE: usize = 1000;
+const MAX_MEMO_SIZE: usize = 32;
+
+// Time window for `created_at_time`, in nanoseconds.
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+/// The ICRC-3 generic value used for ICRC-7 metadata.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferResult = Result<Nat, TransferError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Standard {
+    pub name: String,
+    pub url: String,
+}
+
+/// A minted token.
+#[derive(CandidType, Deserialize, Clone)]
+struct Token {
+    owner: Account,
+    artwork_id: u64,
+    image_sha256: Vec<u8>,
+    recognition_score: f32,
+    minted_at: u64,
+}
+
+impl Storable for Token {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// An entry of the transaction log. Mints have no `from` account.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct NftTransaction {
+    pub id: u64,
+    pub token_id: u64,
+    pub from: Option<Account>,
+    pub to: Account,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub timestamp: u64,
+}
+
+impl Storable for NftTransaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Stable token ledger: token id -> token
+    static TOKENS: RefCell<StableBTreeMap<u64, Token, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(NFT_TOKENS_MEMORY_ID))
+    );
+
+    // Index of the next mint or transfer transaction
+    static NEXT_TX_ID: RefCell<StableCell<u64, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(NFT_TX_COUNTER_MEMORY_ID), 0)
+            .expect("Failed to init NFT transaction counter")
+    );
+
+    // Transaction log: transaction id -> transaction
+    static TRANSACTIONS: RefCell<StableBTreeMap<u64, NftTransaction, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(NFT_TRANSACTIONS_MEMORY_ID))
+    );
+}
+
+fn next_tx_id() -> u64 {
+    NEXT_TX_ID.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let id = *cell.get();
+        cell.set(id + 1)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to record NFT transaction"));
+        id
+    })
+}
+
+/// Appends a transaction to the log and returns its ID.
+fn record(token_id: u64, from: Option<Account>, to: Account, memo: Option<Vec<u8>>, created_at_time: Option<u64>) -> u64 {
+    let id = next_tx_id();
+    let transaction = NftTransaction { id, token_id, from, to, memo, created_at_time, timestamp: ic_cdk::api::time() };
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().insert(id, transaction));
+    id
+}
+
+/// Returns the ID of a logged transfer that `arg`, sent by `from`, repeats.
+/// Only transfers within the transaction window are searched.
+fn find_duplicate(from: &Account, arg: &TransferArg, now: u64) -> Option<u64> {
+    let created_at_time = arg.created_at_time?;
+    let oldest = now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT);
+    let token_id = to_u64(&arg.token_id)?;
+    TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .rev()
+            .take_while(|(_, transaction)| transaction.timestamp >= oldest)
+            .find(|(_, transaction)| {
+                transaction.created_at_time == Some(created_at_time)
+                    && transaction.token_id == token_id
+                    && transaction.from.as_ref() == Some(from)
+                    && transaction.to == arg.to
+                    && transaction.memo == arg.memo
+            })
+            .map(|(id, _)| id)
+    })
+}
+
+/// Mints the token of an approved artwork to its creator and returns the token ID.
+pub fn mint(artwork: &ApprovedArtwork) -> u64 {
+    let token = Token {
+        owner: Account::new(artwork.creator, None),
+        artwork_id: artwork.id,
+        image_sha256: artwork.image_sha256.clone(),
+        recognition_score: artwork.recognition_score,
+        minted_at: ic_cdk::api::time(),
+    };
+    let owner = token.owner.clone();
+    TOKENS.with(|tokens| tokens.borrow_mut().insert(artwork.id, token));
+    record(artwork.id, None, owner, None, None);
+    artwork.id
+}
+
+fn token_metadata(token_id: u64, token: &Token) -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:name".to_string(), Value::Text(format!("{} #{}", NAME, token_id))),
+        ("lain_art:artwork_id".to_string(), Value::Nat(Nat::from(token.artwork_id))),
+        ("lain_art:image_sha256".to_string(), Value::Blob(token.image_sha256.clone())),
+        (
+            "lain_art:recognition_score".to_string(),
+            Value::Text(token.recognition_score.to_string()),
+        ),
+        ("lain_art:minted_at".to_string(), Value::Nat(Nat::from(token.minted_at))),
+    ]
+}
+
+pub fn total_supply() -> u64 {
+    TOKENS.with(|tokens| tokens.borrow().len())
+}
+
+fn to_u64(n: &Nat) -> Option<u64> {
+    n.0.to_u64()
+}
+
+fn take(take: Option<Nat>) -> usize {
+    take.and_then(|t| t.0.to_usize())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE)
+}
+
+fn start_after(prev: Option<Nat>) -> u64 {
+    prev.and_then(|p| to_u64(&p)).map_or(0, |p| p.saturating_add(1))
+}
+
+fn opt_nat(value: usize) -> Option<Nat> {
+    Some(Nat::from(value))
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:symbol".to_string(), Value::Text(SYMBOL.to_string())),
+        ("icrc7:name".to_string(), Value::Text(NAME.to_string())),
+        ("icrc7:description".to_string(), Value::Text(DESCRIPTION.to_string())),
+        ("icrc7:total_supply".to_string(), Value::Nat(Nat::from(total_supply()))),
+        ("icrc7:max_query_batch_size".to_string(), Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE))),
+        ("icrc7:max_update_batch_size".to_string(), Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE))),
+        ("icrc7:default_take_value".to_string(), Value::Nat(Nat::from(DEFAULT_TAKE_VALUE))),
+        ("icrc7:max_take_value".to_string(), Value::Nat(Nat::from(MAX_TAKE_VALUE))),
+        ("icrc7:max_memo_size".to_string(), Value::Nat(Nat::from(MAX_MEMO_SIZE))),
+        ("icrc7:tx_window".to_string(), Value::Nat(Nat::from(TX_WINDOW))),
+        ("icrc7:permitted_drift".to_string(), Value::Nat(Nat::from(PERMITTED_DRIFT))),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    NAME.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    Some(DESCRIPTION.to_string())
+}
+
+#[ic_cdk::query]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(total_supply())
+}
+
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    opt_nat(MAX_QUERY_BATCH_SIZE)
+}
+
+#[ic_cdk::query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    opt_nat(MAX_UPDATE_BATCH_SIZE)
+}
+
+#[ic_cdk::query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    opt_nat(DEFAULT_TAKE_VALUE)
+}
+
+#[ic_cdk::query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    opt_nat(MAX_TAKE_VALUE)
+}
+
+#[ic_cdk::query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    opt_nat(MAX_MEMO_SIZE)
+}
+
+#[ic_cdk::query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[ic_cdk::query]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(Nat::from(TX_WINDOW))
+}
+
+#[ic_cdk::query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(Nat::from(PERMITTED_DRIFT))
+}
+
+/// Returns the metadata of each requested token, `None` for unknown tokens.
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    TOKENS.with(|tokens| {
+        let tokens = tokens.borrow();
+        token_ids
+            .iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|id| {
+                let id = to_u64(id)?;
+                tokens.get(&id).map(|token| token_metadata(id, &token))
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    TOKENS.with(|tokens| {
+        let tokens = tokens.borrow();
+        token_ids
+            .iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|id| tokens.get(&to_u64(id)?).map(|token| token.owner))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    TOKENS.with(|tokens| {
+        let tokens = tokens.borrow();
+        accounts
+            .iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|account| {
+                let owned = tokens.iter().filter(|(_, token)| token.owner == *account).count();
+                Nat::from(owned)
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take_value: Option<Nat>) -> Vec<Nat> {
+    TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .range(start_after(prev)..)
+            .take(take(take_value))
+            .map(|(id, _)| Nat::from(id))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take_value: Option<Nat>) -> Vec<Nat> {
+    TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .range(start_after(prev)..)
+            .filter(|(_, token)| token.owner == account)
+            .take(take(take_value))
+            .map(|(id, _)| Nat::from(id))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "not_restoring")]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("At most {} transfers per batch", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+
+    let caller = ic_cdk::caller();
+    args.into_iter()
+        .map(|arg| Some(transfer(caller, arg)))
+        .collect()
+}
+
+fn transfer(caller: Principal, arg: TransferArg) -> TransferResult {
+    let now = ic_cdk::api::time();
+    if let Some(created_at_time) = arg.created_at_time {
+        if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+            return Err(TransferError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(TransferError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    if arg.memo.as_ref().map_or(0, |memo| memo.len()) > MAX_MEMO_SIZE {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(1u64),
+            message: format!("Memo exceeds {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+
+    let from = Account::new(caller, arg.from_subaccount.clone());
+    if from.validate().is_err() {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(2u64),
+            message: "The from_subaccount must have 32 bytes".to_string(),
+        });
+    }
+    if let Some(duplicate_of) = find_duplicate(&from, &arg, now) {
+        return Err(TransferError::Duplicate { duplicate_of: Nat::from(duplicate_of) });
+    }
+
+    let token_id = to_u64(&arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+    if arg.to.validate().is_err() || arg.to.owner == Principal::anonymous() || arg.to == from {
+        return Err(TransferError::InvalidRecipient);
+    }
+
+    TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        let mut token = tokens.get(&token_id).ok_or(TransferError::NonExistingTokenId)?;
+        if token.owner != from {
+            return Err(TransferError::Unauthorized);
+        }
+        token.owner = arg.to.clone();
+        tokens.insert(token_id, token);
+        Ok(())
+    })?;
+    Ok(Nat::from(record(token_id, Some(from), arg.to, arg.memo, arg.created_at_time)))
+}
+
+/// Returns the transaction log after `prev`, oldest first.
+#[ic_cdk::query]
+fn get_nft_transactions(prev: Option<Nat>, take_value: Option<Nat>) -> Vec<NftTransaction> {
+    TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .range(start_after(prev)..)
+            .take(take(take_value))
+            .map(|(_, transaction)| transaction)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<Standard> {
+    vec![
+        Standard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        Standard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
@@ -1,35 +1,89 @@
 use anyhow::anyhow;
 use bytes::Bytes;
-use candid::CandidType;
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::storable::{Bound, Storable};
+use std::borrow::Cow;
+use image::{DynamicImage, ImageReader, Limits, RgbImage};
 use prost::Message;
-use rand::Rng;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
-use tract_ndarray::s;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::thread::LocalKey;
 use tract_onnx::prelude::*;
-use crate::storage;
+use crate::detection::{self, DetectorFormat, DetectorModel};
+use crate::preprocess::{self, ModelKind, PreprocessSpec};
+use crate::{policy, storage};
 
-// The maximum distance between face embeddings of the same person.
-// Lower scores = better match. Lower threshold to be more strict.
-const THRESHOLD: f32 = 0.6;
+// The maximum distance between face embeddings of the same person, on the 0-2
+// scale of `Metric`. Lower scores = better match. Lower threshold to be more strict.
+// It applies to L2-normalized embeddings of the detected face crop: 0.6 is a
+// cosine similarity of 0.82. Re-run `lain_art_eval` when the models or the
+// embedding pipeline change and record the false accept rate (FAR) and false
+// reject rate (FRR) it reports on laindb here; `closest_non_lain_face_is_rejected`
+// keeps every face of laindb/nonlains out.
+pub const THRESHOLD: f32 = 0.6;
 
-type Model = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+// Open-set rule: a face is rejected when the closest negative (non-Lain)
+// reference is closer than the closest positive one by more than this margin.
+pub const NEGATIVE_MARGIN: f32 = 0.05;
+
+// Version of the way faces are embedded, recorded with every reference next to
+// the model version. Version 1 embedded the whole image, version 2 embeds the
+// face found by the detector; embeddings of different versions are not comparable.
+pub const EMBEDDING_PIPELINE: u32 = 2;
+pub const WHOLE_IMAGE_PIPELINE: u32 = 1;
+
+// Longest side of the working image all stages run on. Every model input is far
+// smaller, so larger images are downscaled right after decoding.
+pub const MAX_WORKING_SIDE: u32 = 1280;
+
+// Images with more pixels than this are refused before decoding.
+pub const MAX_DECODED_PIXELS: u64 = 16_000_000;
+
+// Most memory the decoder may allocate, in bytes. An 8-bit RGBA image at
+// `MAX_DECODED_PIXELS` fits, while wide pixel formats fail instead of trapping.
+const MAX_DECODE_ALLOC: u64 = 64 * 1024 * 1024;
+
+// Index of the "lain" class in the classifier output (ImageFolder order: lains=0, nonlains=1).
+const CLASSIFIER_LAIN_CLASS: usize = 0;
+
+pub type Model = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+
+/// A model together with the preprocessing of its input.
+pub struct Network {
+    pub model: Model,
+    pub spec: PreprocessSpec,
+}
+
+/// A face detection model together with its preprocessing and output format.
+pub struct Detector {
+    pub model: Model,
+    pub spec: PreprocessSpec,
+    pub format: Box<dyn DetectorFormat>,
+}
 
 thread_local! {
-    static FACE_DETECTION: RefCell<Option<Model>> = RefCell::new(None);
-    static FACE_RECOGNITION: RefCell<Option<Model>> = RefCell::new(None);
+    static FACE_DETECTION: RefCell<Option<Detector>> = RefCell::new(None);
+    static ANIME_FACE_DETECTION: RefCell<Option<Detector>> = RefCell::new(None);
+    static FACE_RECOGNITION: RefCell<Option<Network>> = RefCell::new(None);
+    static LAIN_CLASSIFIER: RefCell<Option<Network>> = RefCell::new(None);
+    // Version of the loaded face recognition model, see `model_version`
+    static FACE_RECOGNITION_VERSION: RefCell<Option<String>> = const { RefCell::new(None) };
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
+/// A face bounding box in coordinates relative to the image size (0.0 to 1.0).
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub struct BoundingBox {
-    left: f32,
-    top: f32,
-    right: f32,
-    bottom: f32,
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
 }
 
 impl BoundingBox {
-    fn new(raw: &[f32]) -> Self {
+    pub(crate) fn new(raw: &[f32]) -> Self {
         Self {
             left: raw[0],
             top: raw[1],
@@ -39,171 +93,615 @@ impl BoundingBox {
     }
 }
 
+/// How face embeddings are compared. Every metric is reported as a distance
+/// from 0 (identical) to 2 (opposite) on the scale of the Euclidean distance
+/// between unit vectors, so that `THRESHOLD` means the same for all of them.
+/// Embeddings are L2-normalized when they are computed or imported, and older
+/// references are normalized by a schema migration, so the three metrics give
+/// the same distances; `Dot` skips the normalization and is the cheapest.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Metric {
+    /// Euclidean distance between the L2-normalized embeddings.
+    #[default]
+    Euclidean,
+    /// `sqrt(2 * (1 - cos))` of the angle between the embeddings.
+    Cosine,
+    /// `sqrt(2 * (1 - a·b))` of the stored embeddings, clamped to 0-2. It
+    /// relies on them being normalized and does not normalize them itself.
+    Dot,
+}
+
+impl Storable for Metric {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Scales a vector to unit length. Zero vectors are left unchanged.
+pub fn l2_normalize(v: &mut [f32]) {
+    let norm = dot(v, v).sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Normalizes a vector that is not of unit length yet, and returns whether it
+/// changed. Unit vectors are left bit for bit as they are.
+pub fn normalize_if_needed(v: &mut [f32]) -> bool {
+    let norm = dot(v, v).sqrt();
+    if norm == 0.0 || (norm - 1.0).abs() <= 1e-4 {
+        return false;
+    }
+    l2_normalize(v);
+    true
+}
+
+impl Metric {
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Euclidean => {
+                let (mut a, mut b) = (a.to_vec(), b.to_vec());
+                l2_normalize(&mut a);
+                l2_normalize(&mut b);
+                a.iter().zip(&b).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt()
+            }
+            Metric::Cosine => {
+                let norms = (dot(a, a) * dot(b, b)).sqrt();
+                let cos = if norms > 0.0 { dot(a, b) / norms } else { 0.0 };
+                (2.0 * (1.0 - cos)).clamp(0.0, 4.0).sqrt()
+            }
+            Metric::Dot => (2.0 * (1.0 - dot(a, b))).clamp(0.0, 4.0).sqrt(),
+        }
+    }
+}
+
+/// A face embedding. Embeddings computed by the canister are L2-normalized.
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Embedding {
-    v0: Vec<f32>,
+    pub v0: Vec<f32>,
 }
 
 impl Embedding {
-    fn distance(&self, other: &Self) -> f32 {
-        let result: f32 = self
-            .v0
-            .iter()
-            .zip(other.v0.iter())
-            .map(|(a, b)| (a - b) * (a - b))
-            .sum();
-        result.sqrt()
+    /// Euclidean distance between the normalized embeddings, from 0 to 2.
+    pub fn distance(&self, other: &Self) -> f32 {
+        Metric::Euclidean.distance(&self.v0, &other.v0)
     }
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct Person {
     pub label: String,
+    /// Distance to the enrolled face under the configured `Metric`, from 0
+    /// (identical) to 2. Accepted up to `THRESHOLD`.
     pub score: f32,
 }
 
-fn setup_facedetect(bytes: Bytes) -> TractResult<()> {
-    ic_cdk::println!("[Debug] Setting up face detection model. Bytes size: {}", bytes.len());
+/// Parses and optimizes an ONNX model from its serialized bytes.
+pub fn load_model(bytes: Bytes) -> TractResult<Model> {
+    Ok(load_model_with_metadata(bytes)?.0)
+}
+
+/// Parses and optimizes an ONNX model, and returns it with the key-value
+/// metadata (`metadata_props`) of the model file.
+pub fn load_model_with_metadata(bytes: Bytes) -> TractResult<(Model, HashMap<String, String>)> {
     let proto: tract_onnx::pb::ModelProto = tract_onnx::pb::ModelProto::decode(bytes)?;
-    let ultraface = tract_onnx::onnx()
+    let metadata = proto
+        .metadata_props
+        .iter()
+        .map(|entry| (entry.key.clone(), entry.value.clone()))
+        .collect();
+    let model = tract_onnx::onnx()
         .model_for_proto_model(&proto)?
         .into_optimized()?
         .into_runnable()?;
+    Ok((model, metadata))
+}
+
+/// Loads a face detection model. Its format is read from the model metadata
+/// and defaults to UltraFace; its preprocessing is the default of the format.
+pub fn load_detector(bytes: Bytes) -> TractResult<Detector> {
+    let (model, metadata) = load_model_with_metadata(bytes)?;
+    let format = detection::format_from_metadata(&metadata)?;
+    Ok(Detector { model, spec: format.default_spec(), format })
+}
+
+fn setup_detector(kind: ModelKind, bytes: Bytes) -> TractResult<Detector> {
+    let mut detector = load_detector(bytes)?;
+    if let Some(spec) = preprocess::stored(kind) {
+        detector.spec = spec;
+    }
+    Ok(detector)
+}
+
+fn setup_network(kind: ModelKind, bytes: Bytes, default: PreprocessSpec) -> TractResult<Network> {
+    let model = load_model(bytes)?;
+    let spec = preprocess::stored(kind).unwrap_or(default);
+    Ok(Network { model, spec })
+}
+
+fn setup_facedetect(bytes: Bytes) -> TractResult<()> {
+    ic_cdk::println!("[Debug] Setting up face detection model. Bytes size: {}", bytes.len());
+    let ultraface = setup_detector(ModelKind::FaceDetection, bytes)?;
     FACE_DETECTION.with_borrow_mut(|m| {
         *m = Some(ultraface);
     });
     Ok(())
 }
 
+/// Loads the optional anime face detection model.
+pub fn setup_anime_detector(bytes: Bytes) -> TractResult<()> {
+    ic_cdk::println!("[Debug] Setting up anime face detection model. Bytes size: {}", bytes.len());
+    let detector = setup_detector(ModelKind::AnimeFaceDetection, bytes)?;
+    ANIME_FACE_DETECTION.with_borrow_mut(|m| {
+        *m = Some(detector);
+    });
+    Ok(())
+}
+
 fn setup_facerec(bytes: Bytes) -> TractResult<()> {
     ic_cdk::println!("[Debug] Setting up face recognition model. Bytes size: {}", bytes.len());
-    let proto: tract_onnx::pb::ModelProto = tract_onnx::pb::ModelProto::decode(bytes)?;
-    let facerec = tract_onnx::onnx()
-        .model_for_proto_model(&proto)?
-        .into_optimized()?
-        .into_runnable()?;
+    let version = model_version(&bytes);
+    let facerec = setup_network(ModelKind::FaceRecognition, bytes, PreprocessSpec::face_recognition())?;
     FACE_RECOGNITION.with_borrow_mut(|m| {
         *m = Some(facerec);
     });
+    FACE_RECOGNITION_VERSION.with_borrow_mut(|v| *v = Some(version));
+    Ok(())
+}
+
+/// Identifies a model by the first 8 bytes of the SHA-256 of its file, in hex.
+pub fn model_version(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the version of the loaded face recognition model.
+pub fn recognition_model_version() -> Option<String> {
+    FACE_RECOGNITION_VERSION.with_borrow(|v| v.clone())
+}
+
+/// Loads the optional Lain classifier (`lain_mobilenetv3.onnx`).
+pub fn setup_classifier(bytes: Bytes) -> TractResult<()> {
+    ic_cdk::println!("[Debug] Setting up Lain classifier. Bytes size: {}", bytes.len());
+    let classifier = setup_network(ModelKind::LainClassifier, bytes, PreprocessSpec::lain_classifier())?;
+    LAIN_CLASSIFIER.with_borrow_mut(|m| {
+        *m = Some(classifier);
+    });
     Ok(())
 }
 
+/// Returns the preprocessing spec of a loaded model.
+pub fn loaded_spec(kind: ModelKind) -> Option<PreprocessSpec> {
+    let detector_spec = |slot: &'static LocalKey<RefCell<Option<Detector>>>| {
+        slot.with_borrow(|detector| detector.as_ref().map(|detector| detector.spec.clone()))
+    };
+    let network_spec = |slot: &'static LocalKey<RefCell<Option<Network>>>| {
+        slot.with_borrow(|network| network.as_ref().map(|network| network.spec.clone()))
+    };
+    match kind {
+        ModelKind::FaceDetection => detector_spec(&FACE_DETECTION),
+        ModelKind::AnimeFaceDetection => detector_spec(&ANIME_FACE_DETECTION),
+        ModelKind::FaceRecognition => network_spec(&FACE_RECOGNITION),
+        ModelKind::LainClassifier => network_spec(&LAIN_CLASSIFIER),
+    }
+}
+
+/// Replaces the preprocessing spec of a loaded model.
+pub fn set_loaded_spec(kind: ModelKind, spec: PreprocessSpec) {
+    let set_detector = |slot: &'static LocalKey<RefCell<Option<Detector>>>, spec: PreprocessSpec| {
+        slot.with_borrow_mut(|detector| {
+            if let Some(detector) = detector {
+                detector.spec = spec;
+            }
+        })
+    };
+    let set_network = |slot: &'static LocalKey<RefCell<Option<Network>>>, spec: PreprocessSpec| {
+        slot.with_borrow_mut(|network| {
+            if let Some(network) = network {
+                network.spec = spec;
+            }
+        })
+    };
+    match kind {
+        ModelKind::FaceDetection => set_detector(&FACE_DETECTION, spec),
+        ModelKind::AnimeFaceDetection => set_detector(&ANIME_FACE_DETECTION, spec),
+        ModelKind::FaceRecognition => set_network(&FACE_RECOGNITION, spec),
+        ModelKind::LainClassifier => set_network(&LAIN_CLASSIFIER, spec),
+    }
+}
+
 pub fn setup(facedetect: Bytes, facerec: Bytes) -> TractResult<()> {
     setup_facedetect(facedetect)?;
     setup_facerec(facerec)
 }
 
+/// Decodes an image into the working image used by all pipeline stages. The
+/// dimensions are read from the header first to refuse oversized images, the
+/// decoder's allocations are capped at `MAX_DECODE_ALLOC`, and the decoded image
+/// is downsampled to at most `MAX_WORKING_SIDE` pixels per side.
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage, anyhow::Error> {
+    let (width, height) = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    if width as u64 * height as u64 > MAX_DECODED_PIXELS {
+        return Err(anyhow!("Image of {}x{} pixels is too large", width, height));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode()?;
+    if width.max(height) > MAX_WORKING_SIDE {
+        // `thumbnail` uses a fast box filter, which is much cheaper than a
+        // filtered resize at this size and good enough for the model inputs.
+        return Ok(image.thumbnail(MAX_WORKING_SIDE, MAX_WORKING_SIDE));
+    }
+    Ok(image)
+}
+
 /// Returns a bounding box around the face detected in the given image.
 pub fn detect(image: Vec<u8>) -> Result<(BoundingBox, f32), anyhow::Error> {
-    FACE_DETECTION.with_borrow(|model| {
-        let model = model.as_ref().unwrap();
-        let image = image::load_from_memory(&image)?.to_rgb8();
-
-        // The model accepts an image of size 320x240px.
-        let image =
-            image::imageops::resize(&image, 320, 240, ::image::imageops::FilterType::Triangle);
-
-        const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
-        const STD: [f32; 3] = [0.229, 0.224, 0.225];
-        let tensor = tract_ndarray::Array4::from_shape_fn((1, 3, 240, 320), |(_, c, y, x)| {
-            (image[(x as u32, y as u32)][c] as f32 / 255.0 - MEAN[c]) / STD[c]
-        });
-
-        let result = model.run(tvec!(Tensor::from(tensor).into()))?;
-
-        let confidences = result[0]
-            .to_array_view::<f32>()?
-            .slice(s![0, .., 1])
-            .to_vec();
-
-        let boxes: Vec<_> = result[1].to_array_view::<f32>()?.iter().cloned().collect();
-        let boxes: Vec<_> = boxes.chunks(4).map(BoundingBox::new).collect();
-        let boxes: Vec<_> = boxes.iter().zip(confidences.iter()).collect();
-
-        let best = boxes
-            .iter()
-            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-            .ok_or(anyhow!("No face detected"))?;
-
-        let best = (best.0.clone(), best.1.clone());
-        Ok(best)
+    let image = decode(&image)?.to_rgb8();
+    detect_image(&image)
+}
+
+/// Returns a bounding box around the face detected in an already decoded image.
+pub fn detect_image(image: &RgbImage) -> Result<(BoundingBox, f32), anyhow::Error> {
+    with_detector(&DetectorModel::Photo, |detector| detect_with(detector, image))
+}
+
+/// Runs `f` with the given face detection model, if it is loaded.
+pub fn with_detector<R>(
+    which: &DetectorModel,
+    f: impl FnOnce(&Detector) -> Result<R, anyhow::Error>,
+) -> Result<R, anyhow::Error> {
+    let (slot, name) = match which {
+        DetectorModel::Photo => (&FACE_DETECTION, "Face detection model"),
+        DetectorModel::Anime => (&ANIME_FACE_DETECTION, "Anime face detection model"),
+    };
+    slot.with_borrow(|detector| {
+        let detector = detector.as_ref().ok_or(anyhow!("{} is not loaded", name))?;
+        f(detector)
     })
 }
 
-/// Computes a face embedding corresponding to the given image of a face.
-pub fn embedding(image: Vec<u8>) -> Result<Embedding, anyhow::Error> {
-    FACE_RECOGNITION.with_borrow(|model| {
-        let model = model.as_ref().unwrap();
-        let image = image::load_from_memory(&image)?.to_rgb8();
+/// Whether the given face detection model is loaded.
+pub fn has_detector(which: &DetectorModel) -> bool {
+    with_detector(which, |_| Ok(())).is_ok()
+}
+
+/// Runs the face detection model on a decoded image and returns the most
+/// confident bounding box together with its confidence.
+pub fn detect_with(detector: &Detector, image: &RgbImage) -> Result<(BoundingBox, f32), anyhow::Error> {
+    detect_all_with(detector, image)?
+        .into_iter()
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .ok_or(anyhow!("No face detected"))
+}
 
-        // The model accepts an image of size 160x160px.
-        let image =
-            image::imageops::resize(&image, 160, 160, ::image::imageops::FilterType::Triangle);
+/// Runs the face detection model on a decoded image and returns every
+/// candidate box together with its confidence.
+pub fn detect_all_with(detector: &Detector, image: &RgbImage) -> Result<Vec<(BoundingBox, f32)>, anyhow::Error> {
+    let (input, frame) = detector.spec.apply(image);
+    let result = detector.model.run(tvec!(input.into()))?;
+    let boxes = detector.format.decode(&result)?;
+    Ok(boxes.into_iter().map(|(bbox, confidence)| (frame.to_image(&bbox), confidence)).collect())
+}
 
-        let tensor = tract_ndarray::Array4::from_shape_fn((1, 3, 160, 160), |(_, c, y, x)| {
-            image[(x as u32, y as u32)][c] as f32 / 255.0
-        });
+/// Crops the image to the given relative bounding box, clamped to the image bounds.
+pub fn crop_to_box(image: &RgbImage, bbox: &BoundingBox) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let clamp = |v: f32, max: u32| ((v.clamp(0.0, 1.0) * max as f32) as u32).min(max);
+    let left = clamp(bbox.left, width).min(width.saturating_sub(1));
+    let top = clamp(bbox.top, height).min(height.saturating_sub(1));
+    let right = clamp(bbox.right, width).max(left + 1).min(width);
+    let bottom = clamp(bbox.bottom, height).max(top + 1).min(height);
+    image::imageops::crop_imm(image, left, top, right - left, bottom - top).to_image()
+}
 
-        let result = model.run(tvec!(Tensor::from(tensor).into()))?;
+/// Computes the face embedding of the face detected in the given image.
+pub fn embedding(image: Vec<u8>) -> Result<Embedding, anyhow::Error> {
+    let image = decode(&image)?.to_rgb8();
+    face_embedding(&image)
+}
 
-        let v0 = result[0]
-            .to_array_view::<f32>()?
-            .into_iter()
-            .cloned()
-            .collect();
+/// Detects the most confident face in a decoded image with the detector of the
+/// verification policy and computes the embedding of that face alone. References
+/// and submissions are embedded the same way, so their distances are comparable.
+pub fn face_embedding(image: &RgbImage) -> Result<Embedding, anyhow::Error> {
+    let policy = policy::policy();
+    let (bbox, _) = detection::detect(&policy.detector(), &policy.detection, image)?;
+    embedding_image(&crop_to_box(image, &bbox))
+}
 
-        Ok(Embedding { v0 })
+/// Computes the face embedding of an already decoded image of a face.
+pub fn embedding_image(image: &RgbImage) -> Result<Embedding, anyhow::Error> {
+    FACE_RECOGNITION.with_borrow(|network| {
+        let network = network.as_ref().ok_or(anyhow!("Face recognition model is not loaded"))?;
+        embedding_with(network, image)
     })
 }
 
-/// Returns the person whose face embedding is the closest to the face embedding
-/// of the given image.
-pub fn recognize(image: Vec<u8>) -> Result<Person, anyhow::Error> {
-    let emb = embedding(image)?;
-    
-    // Get all faces from stable storage
-    let db = storage::get_all_faces();
-    
-    if db.is_empty() {
-        return Err(anyhow!("No faces in database"));
+/// Runs the face recognition model on a decoded image of a face.
+pub fn embedding_with(network: &Network, image: &RgbImage) -> Result<Embedding, anyhow::Error> {
+    let (input, _) = network.spec.apply(image);
+    let result = network.model.run(tvec!(input.into()))?;
+
+    let mut v0: Vec<f32> = result[0]
+        .to_array_view::<f32>()?
+        .into_iter()
+        .cloned()
+        .collect();
+    l2_normalize(&mut v0);
+
+    Ok(Embedding { v0 })
+}
+
+/// Returns the probability that the image shows Lain according to the
+/// MobileNetV3 classifier (`lain_mobilenetv3.onnx`).
+pub fn classify_with(network: &Network, image: &RgbImage) -> Result<f32, anyhow::Error> {
+    let (input, _) = network.spec.apply(image);
+    let result = network.model.run(tvec!(input.into()))?;
+    let logits: Vec<f32> = result[0].to_array_view::<f32>()?.iter().cloned().collect();
+    if logits.len() <= CLASSIFIER_LAIN_CLASS {
+        return Err(anyhow!("Unexpected classifier output of size {}", logits.len()));
     }
-    
-    let best = db
-        .iter()
+
+    // Softmax over the class logits.
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    Ok(exps[CLASSIFIER_LAIN_CLASS] / sum)
+}
+
+/// Returns the probability that a decoded image shows Lain, or `None` if no
+/// classifier is loaded.
+pub fn classify_image(image: &RgbImage) -> Option<Result<f32, anyhow::Error>> {
+    LAIN_CLASSIFIER.with_borrow(|network| network.as_ref().map(|network| classify_with(network, image)))
+}
+
+/// Returns the label and distance of the stored embedding closest to `emb`.
+pub fn nearest(db: &[(String, Vec<f32>)], emb: &Embedding, metric: Metric) -> Option<(String, f32)> {
+    db.iter()
         .map(|(label, emb_vec)| {
-            let stored_emb = Embedding { v0: emb_vec.clone() };
-            let distance = stored_emb.distance(&emb);
+            let distance = metric.distance(emb_vec, &emb.v0);
             (label.clone(), distance)
         })
         .min_by(|a, b| f32::partial_cmp(&a.1, &b.1).unwrap())
-        .ok_or(anyhow!("Unknown person"))?;
-    
-    let label = best.0;
-    let score = best.1;
-    
-    if score > THRESHOLD {
+}
+
+/// An enrolled identity and its closest reference.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IdentityMatch {
+    pub label: String,
+    /// Distance to the closest reference of the identity, as in `Person.score`.
+    pub score: f32,
+    pub reference_id: u64,
+    /// Whether the identity is a negative (non-Lain) one.
+    pub negative: bool,
+}
+
+/// Enrolled identities ranked by distance, closest first.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Ranking {
+    pub matches: Vec<IdentityMatch>,
+    /// How much farther the second identity is than the first, if there is one.
+    pub margin: Option<f32>,
+}
+
+impl Ranking {
+    /// Returns the first `k` of ranked identities.
+    pub fn top(matches: &[IdentityMatch], k: usize) -> Self {
+        let margin = match matches {
+            [first, second, ..] => Some(second.score - first.score),
+            _ => None,
+        };
+        Self { matches: matches.iter().take(k).cloned().collect(), margin }
+    }
+}
+
+/// Ranks the enrolled identities by the distance of their closest reference
+/// to `emb` and returns the first `k`.
+pub fn rank(emb: &Embedding, k: usize) -> Result<Ranking, anyhow::Error> {
+    Ok(Ranking::top(&rank_all(emb)?, k))
+}
+
+/// Ranks all enrolled identities, positive and negative, by the distance of
+/// their closest reference to `emb`.
+///
+/// References computed by another recognition model or embedding pipeline are
+/// skipped until they are re-embedded; if no other reference is left, the error
+/// reports how many are stale.
+pub fn rank_all(emb: &Embedding) -> Result<Vec<IdentityMatch>, anyhow::Error> {
+    let version = recognition_model_version();
+    let (db, stale): (Vec<_>, Vec<_>) =
+        storage::get_all_faces().into_iter().partition(|reference| reference.is_current(&version));
+    if db.is_empty() && !stale.is_empty() {
+        return Err(anyhow!(
+            "No faces in database: {} references must be re-embedded with reembed_all or enrolled again",
+            stale.len()
+        ));
+    }
+    if db.is_empty() {
+        return Err(anyhow!("No faces in database"));
+    }
+
+    let metric = storage::recognition_metric();
+    let mut best: HashMap<String, IdentityMatch> = HashMap::new();
+    for reference in db {
+        let score = metric.distance(&reference.embedding, &emb.v0);
+        let closer = best.get(&reference.label).is_none_or(|other| score < other.score);
+        if closer {
+            let label = reference.label.clone();
+            let negative = reference.is_negative();
+            best.insert(label.clone(), IdentityMatch { label, score, reference_id: reference.id, negative });
+        }
+    }
+    let mut matches: Vec<IdentityMatch> = best.into_values().collect();
+    matches.sort_by(|a, b| a.score.total_cmp(&b.score).then_with(|| a.label.cmp(&b.label)));
+    Ok(matches)
+}
+
+/// Returns the closest positive and the closest negative identity of a ranking.
+pub fn closest_identities(matches: &[IdentityMatch]) -> (Option<&IdentityMatch>, Option<&IdentityMatch>) {
+    (matches.iter().find(|m| !m.negative), matches.iter().find(|m| m.negative))
+}
+
+/// Whether the open-set rule rejects the positive match: the closest negative
+/// identity is closer by more than `NEGATIVE_MARGIN`.
+pub fn rejected_by_negative(positive: &IdentityMatch, negative: Option<&IdentityMatch>) -> bool {
+    negative.is_some_and(|negative| negative.score + NEGATIVE_MARGIN < positive.score)
+}
+
+/// Returns the enrolled person whose face embedding is the closest to the face
+/// embedding of the given image, however far it is.
+pub fn closest_person(image: Vec<u8>) -> Result<Person, anyhow::Error> {
+    closest_person_to(&embedding(image)?)
+}
+
+/// Returns the enrolled person whose face embedding is the closest to `emb`.
+/// Negative identities are not persons.
+pub fn closest_person_to(emb: &Embedding) -> Result<Person, anyhow::Error> {
+    let matches = rank_all(emb)?;
+    let best = closest_identities(&matches).0.ok_or(anyhow!("No faces in database"))?;
+    Ok(Person { label: best.label.clone(), score: best.score })
+}
+
+/// Returns the person whose face embedding is the closest to the face embedding
+/// of the given image, unless a negative identity is closer.
+pub fn recognize(image: Vec<u8>) -> Result<Person, anyhow::Error> {
+    let matches = rank_all(&embedding(image)?)?;
+    let (positive, negative) = closest_identities(&matches);
+    let positive = positive.ok_or(anyhow!("No faces in database"))?;
+
+    if positive.score > THRESHOLD || rejected_by_negative(positive, negative) {
         return Err(anyhow!("Unknown person"));
     }
-    
-    Ok(Person { label, score })
+
+    Ok(Person { label: positive.label.clone(), score: positive.score })
 }
 
 /// Records a new person with the given name and face image into the state.
-pub fn add(label: String, image: Vec<u8>) -> Result<Embedding, anyhow::Error> {
-    let emb = embedding(image)?;
+/// Negative references record faces that must not be taken for Lain.
+pub fn add(label: String, image: Vec<u8>, negative: bool) -> Result<Embedding, anyhow::Error> {
+    let decoded = decode(&image)?.to_rgb8();
+    let (_, emb) = add_decoded(label, image, &decoded, negative)?;
+    Ok(emb)
+}
+
+/// Like `add`, for an image that is already decoded. Only the detected face is
+/// embedded. Returns the ID of the new reference with its embedding.
+pub fn add_decoded(label: String, image: Vec<u8>, decoded: &RgbImage, negative: bool) -> Result<(u64, Embedding), anyhow::Error> {
+    let conflicting = storage::get_all_faces()
+        .iter()
+        .any(|reference| reference.label == label && reference.is_negative() != negative);
+    if conflicting {
+        return Err(anyhow!("'{}' is already enrolled as a {} identity", label, if negative { "positive" } else { "negative" }));
+    }
+    let emb = face_embedding(decoded)?;
     
-    // Store in stable memory
-    storage::add_face_to_database(label, emb.v0.clone());
+    // Store in stable memory, with the image to re-embed it after a model change
+    let id = storage::add_face_to_database(
+        label,
+        emb.v0.clone(),
+        negative,
+        recognition_model_version(),
+        Some(EMBEDDING_PIPELINE),
+        Some(image),
+    );
     
-    Ok(emb)
+    Ok((id, emb))
 }
 
 #[cfg(target_arch = "wasm32")]
 #[no_mangle]
 pub extern "C" fn __getrandom_custom(buf: *mut u8, len: usize) -> i32 {
+    use rand::Rng;
     let mut rng = rand::thread_rng();
     for i in 0..len {
         unsafe { *buf.add(i) = rng.gen::<u8>() }; // Generate random bytes deterministically
     }
     0 // Return 0 to indicate success
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Luma, Rgba};
+
+    fn png(image: DynamicImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn oversized_images_are_refused_before_decoding() {
+        let image = png(DynamicImage::ImageLuma8(image::ImageBuffer::from_pixel(6000, 4000, Luma([0u8]))));
+        let err = decode(&image).unwrap_err();
+        assert_eq!(err.to_string(), "Image of 6000x4000 pixels is too large");
+    }
+
+    #[test]
+    fn decoding_fails_cleanly_above_the_allocation_cap() {
+        // 9 megapixels, but 72 MB as 16-bit RGBA.
+        let image = png(DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(3000, 3000, Rgba([0u16; 4]))));
+        assert!(decode(&image).is_err());
+    }
+
+    #[test]
+    fn large_images_are_downsampled_to_the_working_size() {
+        let image = png(DynamicImage::ImageLuma8(image::ImageBuffer::from_pixel(3000, 2000, Luma([0u8]))));
+        let decoded = decode(&image).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (MAX_WORKING_SIDE, 853));
+    }
+
+    #[test]
+    fn l2_normalize_gives_unit_vectors() {
+        let mut v = [3.0, 4.0];
+        l2_normalize(&mut v);
+        assert_eq!(v, [0.6, 0.8]);
+        let mut zero = [0.0; 4];
+        l2_normalize(&mut zero);
+        assert_eq!(zero, [0.0; 4]);
+    }
+
+    #[test]
+    fn only_vectors_that_are_not_unit_are_normalized() {
+        let mut legacy = [3.0, 4.0];
+        assert!(normalize_if_needed(&mut legacy));
+        assert_eq!(legacy, [0.6, 0.8]);
+        let mut unit = [0.6, 0.8];
+        assert!(!normalize_if_needed(&mut unit));
+        assert!(!normalize_if_needed(&mut [0.0; 4]));
+    }
+
+    #[test]
+    fn metrics_range_from_0_to_2() {
+        let a = [0.6, 0.8];
+        let orthogonal = [-0.8, 0.6];
+        let opposite = [-0.6, -0.8];
+        for metric in [Metric::Euclidean, Metric::Cosine, Metric::Dot] {
+            assert!(metric.distance(&a, &a).abs() < 1e-3, "{:?}", metric);
+            assert!((metric.distance(&a, &orthogonal) - 2f32.sqrt()).abs() < 1e-6, "{:?}", metric);
+            assert!((metric.distance(&a, &opposite) - 2.0).abs() < 1e-6, "{:?}", metric);
+        }
+        // Euclidean and Cosine do not depend on the length of the vectors.
+        let long = [30.0, 40.0];
+        assert!(Metric::Euclidean.distance(&a, &long).abs() < 1e-6);
+        assert!(Metric::Cosine.distance(&a, &long).abs() < 1e-3);
+        assert!((Metric::Euclidean.distance(&long, &[-3.0, -4.0]) - 2.0).abs() < 1e-6);
+        // Dot is clamped to the same range.
+        assert_eq!(Metric::Dot.distance(&long, &long), 0.0);
+        assert_eq!(Metric::Dot.distance(&long, &[-30.0, -40.0]), 2.0);
+    }
+}
@@ -0,0 +1,208 @@
+//! Emergency pause switches.
+//!
+//! If a model misbehaves, e.g. starts approving anything, controllers can stop
+//! parts of the canister without uninstalling it. Each switch covers one area:
+//! `Submissions` (uploads), `Verification` (the pipeline, review verdicts and
+//! appeals), `Rewards` (reward and refund payouts) and `Enrollment` (adding,
+//! removing and re-embedding face references). A
+//! paused endpoint fails with the `Paused` arm of `ServiceError`; endpoints
+//! whose signature the frontend relies on fail with `Paused: <reason>` instead,
+//! or trap if they cannot return an error. Queued verifications, enrollments
+//! and re-embeddings wait while their switch is on and continue once it is
+//! resumed; automatic reward and refund payouts fail and can be retried with
+//! `finalize_reward` and `finalize_refund`. The switches are kept in stable memory, so they survive
+//! upgrades. While a backup is taken, every switch counts as paused and the
+//! model files cannot be changed.
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableCell;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::auth::is_admin;
+use crate::storage::{self, Mem, PAUSE_MEMORY_ID};
+use crate::{backup, enrollment, pipeline, reembed};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PauseSwitch {
+    Submissions,
+    Verification,
+    Rewards,
+    Enrollment,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Pause {
+    pub reason: String,
+    pub paused_by: Principal,
+    pub paused_at: u64,
+}
+
+/// The pause of each switch, `None` if it is not paused.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PauseStatus {
+    pub submissions: Option<Pause>,
+    pub verification: Option<Pause>,
+    pub rewards: Option<Pause>,
+    pub enrollment: Option<Pause>,
+}
+
+impl PauseStatus {
+    fn switch(&mut self, switch: PauseSwitch) -> &mut Option<Pause> {
+        match switch {
+            PauseSwitch::Submissions => &mut self.submissions,
+            PauseSwitch::Verification => &mut self.verification,
+            PauseSwitch::Rewards => &mut self.rewards,
+            PauseSwitch::Enrollment => &mut self.enrollment,
+        }
+    }
+}
+
+impl Storable for PauseStatus {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Error of an endpoint whose switch is paused.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Paused {
+    /// The paused switch, `None` while a backup is taken or restored.
+    pub switch: Option<PauseSwitch>,
+    pub reason: String,
+}
+
+impl fmt::Display for Paused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Paused: {}", self.reason)
+    }
+}
+
+impl From<Paused> for String {
+    fn from(paused: Paused) -> Self {
+        paused.to_string()
+    }
+}
+
+/// Error of an endpoint that can be paused, so that clients can tell a pause
+/// from a failure without parsing the message.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ServiceError {
+    Paused(Paused),
+    Failed(String),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Paused(paused) => write!(f, "{}", paused),
+            ServiceError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<Paused> for ServiceError {
+    fn from(paused: Paused) -> Self {
+        ServiceError::Paused(paused)
+    }
+}
+
+impl From<String> for ServiceError {
+    fn from(message: String) -> Self {
+        ServiceError::Failed(message)
+    }
+}
+
+impl From<&str> for ServiceError {
+    fn from(message: &str) -> Self {
+        ServiceError::Failed(message.to_string())
+    }
+}
+
+thread_local! {
+    static STATUS: RefCell<StableCell<PauseStatus, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(PAUSE_MEMORY_ID), PauseStatus::default())
+            .expect("Failed to init pause status")
+    );
+}
+
+fn status() -> PauseStatus {
+    STATUS.with(|status| status.borrow().get().clone())
+}
+
+fn set(switch: PauseSwitch, pause: Option<Pause>) {
+    STATUS.with(|cell| {
+        let mut status = cell.borrow().get().clone();
+        *status.switch(switch) = pause;
+        cell.borrow_mut()
+            .set(status)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store pause status"));
+    });
+}
+
+/// Fails while a backup is taken or restored. Writes that no switch covers, such as
+/// replacing the model files, check this instead of a switch.
+pub fn check_backup() -> Result<(), Paused> {
+    if backup::is_running() {
+        return Err(Paused { switch: None, reason: "A backup is being taken".to_string() });
+    }
+    if backup::is_restoring() {
+        return Err(Paused { switch: None, reason: "A backup is being restored".to_string() });
+    }
+    Ok(())
+}
+
+/// Fails with the reason of the pause if the switch is paused.
+pub fn check(switch: PauseSwitch) -> Result<(), Paused> {
+    check_backup()?;
+    match status().switch(switch).take() {
+        Some(pause) => Err(Paused { switch: Some(switch), reason: pause.reason }),
+        None => Ok(()),
+    }
+}
+
+/// Whether the switch is paused.
+pub fn is_paused(switch: PauseSwitch) -> bool {
+    check(switch).is_err()
+}
+
+/// Returns which switches are paused, why, by whom and since when.
+#[ic_cdk::query]
+fn get_pause_status() -> PauseStatus {
+    status()
+}
+
+/// Pauses everything covered by the switch until `resume` is called.
+#[ic_cdk::update(guard = "is_admin")]
+fn pause(switch: PauseSwitch, reason: String) -> Result<(), String> {
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    let pause = Pause { reason, paused_by: ic_cdk::caller(), paused_at: ic_cdk::api::time() };
+    ic_cdk::println!("[Pause] {:?} paused: {}", switch, pause.reason);
+    set(switch, Some(pause));
+    Ok(())
+}
+
+/// Lifts the pause of the switch and restarts the work waiting on it.
+#[ic_cdk::update(guard = "is_admin")]
+fn resume(switch: PauseSwitch) {
+    set(switch, None);
+    ic_cdk::println!("[Pause] {:?} resumed", switch);
+    match switch {
+        PauseSwitch::Verification => pipeline::resume(),
+        PauseSwitch::Enrollment => {
+            enrollment::resume();
+            reembed::resume();
+        }
+        PauseSwitch::Submissions | PauseSwitch::Rewards => {}
+    }
+}
@@ -0,0 +1,228 @@
+//! Perceptual hashing for duplicate and plagiarism detection.
+//!
+//! Every submission gets a 64-bit difference hash (dHash). Verification compares
+//! it with the hashes of the gallery and of an admin-curated set of official
+//! reference frames (e.g. screenshots of the anime) and rejects near-duplicates,
+//! i.e. hashes within `DUPLICATE_DISTANCE` bits.
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::auth::is_admin;
+use crate::storage::{self, Mem, GALLERY_PHASH_MEMORY_ID, NEXT_REFERENCE_FRAME_ID_MEMORY_ID, REFERENCE_FRAMES_MEMORY_ID};
+
+/// Maximum Hamming distance between two hashes for the images to count as near-duplicates.
+pub const DUPLICATE_DISTANCE: u32 = 10;
+
+/// What a submission was found to be a near-duplicate of.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum HashMatch {
+    Artwork(u64),
+    ReferenceFrame(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DuplicateMatch {
+    pub matched: HashMatch,
+    /// Hamming distance between the two perceptual hashes, from 0 to 64.
+    pub distance: u32,
+}
+
+impl DuplicateMatch {
+    pub fn is_duplicate(&self) -> bool {
+        self.distance <= DUPLICATE_DISTANCE
+    }
+
+    pub fn describe(&self) -> String {
+        let matched = match self.matched {
+            HashMatch::Artwork(id) => format!("artwork {}", id),
+            HashMatch::ReferenceFrame(id) => format!("reference frame {}", id),
+        };
+        format!("Near-duplicate of {} (Hamming distance {})", matched, self.distance)
+    }
+}
+
+/// An official frame that submissions must not copy.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReferenceFrame {
+    pub id: u64,
+    pub label: String,
+    pub phash: u64,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+impl Storable for ReferenceFrame {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Stable hash index of the gallery: artwork id -> perceptual hash
+    static GALLERY_HASHES: RefCell<StableBTreeMap<u64, u64, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(GALLERY_PHASH_MEMORY_ID))
+    );
+
+    // Stable reference frames: frame id -> frame
+    static REFERENCE_FRAMES: RefCell<StableBTreeMap<u64, ReferenceFrame, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(REFERENCE_FRAMES_MEMORY_ID))
+    );
+
+    // ID of the next reference frame, so that IDs of removed frames, which
+    // submissions may record in `duplicate_of`, are never given out again
+    static NEXT_FRAME_ID: RefCell<StableCell<u64, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(NEXT_REFERENCE_FRAME_ID_MEMORY_ID), 0)
+            .expect("Failed to init reference frame counter")
+    );
+}
+
+/// Computes the 64-bit difference hash of an image: the image is reduced to a
+/// 9x8 grayscale thumbnail and each bit tells whether a pixel is brighter than
+/// its right neighbour. Robust to rescaling, recompression and small edits.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let thumbnail = image::imageops::resize(&image.to_luma8(), 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumbnail[(x, y)][0] > thumbnail[(x + 1, y)][0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Records the hash of an approved artwork.
+pub fn index_artwork(artwork_id: u64, hash: u64) {
+    GALLERY_HASHES.with(|hashes| {
+        hashes.borrow_mut().insert(artwork_id, hash);
+    });
+}
+
+/// Returns the closest gallery artwork or reference frame, whatever the distance.
+pub fn nearest(hash: u64) -> Option<DuplicateMatch> {
+    let artworks = GALLERY_HASHES.with(|hashes| {
+        hashes
+            .borrow()
+            .iter()
+            .map(|(id, other)| DuplicateMatch { matched: HashMatch::Artwork(id), distance: hamming(hash, other) })
+            .min_by_key(|m| m.distance)
+    });
+    let frames = REFERENCE_FRAMES.with(|frames| {
+        frames
+            .borrow()
+            .iter()
+            .map(|(id, frame)| DuplicateMatch {
+                matched: HashMatch::ReferenceFrame(id),
+                distance: hamming(hash, frame.phash),
+            })
+            .min_by_key(|m| m.distance)
+    });
+    [artworks, frames].into_iter().flatten().min_by_key(|m| m.distance)
+}
+
+/// Adds an official reference frame to the plagiarism index and returns its ID.
+/// Only the hash of the image is kept.
+#[ic_cdk::update(guard = "is_admin")]
+fn add_reference_frame(label: String, image: Vec<u8>) -> Result<u64, String> {
+    let image = crate::onnx::decode(&image).map_err(|err| format!("Invalid image: {}", err))?;
+    let phash = dhash(&image);
+    REFERENCE_FRAMES.with(|frames| {
+        let mut frames = frames.borrow_mut();
+        // Frames added before the counter existed can be above it.
+        let next = NEXT_FRAME_ID.with(|next| *next.borrow().get());
+        let id = next.max(frames.last_key_value().map_or(0, |(id, _)| id + 1));
+        NEXT_FRAME_ID.with(|next| {
+            next.borrow_mut()
+                .set(id + 1)
+                .unwrap_or_else(|_| ic_cdk::trap("Failed to store next reference frame id"))
+        });
+        frames.insert(id, ReferenceFrame {
+            id,
+            label,
+            phash,
+            added_by: ic_cdk::caller(),
+            added_at: ic_cdk::api::time(),
+        });
+        Ok(id)
+    })
+}
+
+/// Returns the number of reference frames.
+pub fn frame_count() -> u64 {
+    REFERENCE_FRAMES.with(|frames| frames.borrow().len())
+}
+
+/// Removes a reference frame from the plagiarism index.
+#[ic_cdk::update(guard = "is_admin")]
+fn remove_reference_frame(id: u64) {
+    REFERENCE_FRAMES.with(|frames| {
+        frames.borrow_mut().remove(&id);
+    });
+}
+
+/// Returns all reference frames.
+#[ic_cdk::query]
+fn list_reference_frames() -> Vec<ReferenceFrame> {
+    REFERENCE_FRAMES.with(|frames| frames.borrow().iter().map(|(_, frame)| frame).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma};
+
+    /// A horizontal gradient that gets darker to the right.
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(ImageBuffer::from_fn(width, height, |x, _| {
+            Luma([255 - (x * 180 / width) as u8])
+        }))
+    }
+
+    #[test]
+    fn dhash_compares_each_pixel_with_its_right_neighbour() {
+        let flat = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(90, 80, Luma([128u8])));
+        assert_eq!(dhash(&flat), 0);
+        assert_eq!(dhash(&gradient(90, 80)), u64::MAX);
+        assert_eq!(dhash(&gradient(90, 80).fliph()), 0);
+    }
+
+    #[test]
+    fn dhash_is_stable_under_rescaling() {
+        assert_eq!(dhash(&gradient(90, 80)), dhash(&gradient(900, 800)));
+    }
+
+    #[test]
+    fn hamming_counts_differing_bits() {
+        assert_eq!(hamming(0, 0), 0);
+        assert_eq!(hamming(0, u64::MAX), 64);
+        assert_eq!(hamming(0b1011, 0b0110), 3);
+        assert_eq!(hamming(42, 42), 0);
+    }
+
+    #[test]
+    fn matches_within_the_duplicate_distance_are_duplicates() {
+        let at = |distance| DuplicateMatch { matched: HashMatch::Artwork(7), distance };
+        assert!(at(0).is_duplicate());
+        assert!(at(DUPLICATE_DISTANCE).is_duplicate());
+        assert!(!at(DUPLICATE_DISTANCE + 1).is_duplicate());
+        assert_eq!(at(3).describe(), "Near-duplicate of artwork 7 (Hamming distance 3)");
+    }
+}
@@ -0,0 +1,407 @@
+//! The verification pipeline.
+//!
+//! Verification is split into steps (decode, detect, embed, classify, persist)
+//! that share the intermediate results of a `Job`. `verify_and_store_artwork`
+//! runs all of them in one message; `trigger_verification` queues the
+//! submission instead, and a timer runs one step per message so that a large
+//! image never has to be processed within a single instruction limit. The
+//! current step is visible as `Status::Processing`.
+//!
+//! If a step traps, e.g. by running out of instructions, a watchdog timer
+//! retries it up to `MAX_ATTEMPTS` times. Only verdicts about the artwork
+//! reject a submission: when verification cannot run, because a model is not
+//! loaded or a step keeps trapping, the submission stays `Verifying` with the
+//! error in `verification_error` and can be verified again.
+//! Jobs live on the heap; submissions interrupted by an upgrade go back to
+//! `Verifying` and can be triggered again. While verification is paused, the
+//! queue waits.
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk_timers::TimerId;
+use image::RgbImage;
+use num_traits::ToPrimitive;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::auth::is_admin;
+use crate::backup::not_restoring;
+use crate::onnx::{self, BoundingBox, Embedding};
+use crate::phash::{self, DuplicateMatch};
+use crate::submissions::{self, RejectionKind, Status, Submission};
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::{detection, fees, policy, review, scoring, upgrade};
+
+/// Minimum classifier probability for an image to count as Lain.
+const CLASSIFIER_THRESHOLD: f32 = 0.5;
+
+/// Number of identities kept with a submission queued for review.
+const REVIEW_RANKING_SIZE: usize = 3;
+
+/// How often a trapping step is retried before the submission is rejected.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay after which a step that did not complete is assumed to have trapped.
+const WATCHDOG_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VerificationStep {
+    /// Decode and downscale the image, and check it against known artwork.
+    Decode,
+    /// Detect the face.
+    Detect,
+    /// Compute the face embedding.
+    Embed,
+    /// Run the Lain classifier, if one is loaded.
+    Classify,
+    /// Recognize the face and store the outcome.
+    Persist,
+}
+
+/// Why verification of a submission did not succeed.
+pub enum VerifyError {
+    /// The artwork was checked and rejected.
+    Rejected(RejectionKind, String),
+    /// The result is borderline and the submission waits for a reviewer.
+    Queued(String),
+    /// Verification could not run, e.g. because a model is not loaded or a
+    /// step kept trapping. This says nothing about the artwork: the submission
+    /// stays `Verifying` and can be verified again.
+    Unavailable(String),
+}
+
+/// A submission going through the pipeline, with the results of the steps so far.
+struct Job {
+    submission_id: u64,
+    step: VerificationStep,
+    attempts: u32,
+    image_data: Vec<u8>,
+    image: Option<RgbImage>,
+    nearest: Option<DuplicateMatch>,
+    detection: Option<(BoundingBox, f32)>,
+    embedding: Option<Embedding>,
+}
+
+thread_local! {
+    static QUEUE: RefCell<VecDeque<Job>> = const { RefCell::new(VecDeque::new()) };
+    // Whether the next step is already scheduled
+    static SCHEDULED: Cell<bool> = const { Cell::new(false) };
+    static WATCHDOG: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+/// Checks that the caller can verify a submission and returns a job for it.
+/// Only the creator and controllers can.
+fn prepare(submission_id: u64) -> Result<Job, String> {
+    pause::check(PauseSwitch::Verification)?;
+    upgrade::check_open()?;
+    let record = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if record.creator != ic_cdk::caller() && is_admin().is_err() {
+        return Err("Only the creator can verify a submission".to_string());
+    }
+    match record.status {
+        Status::Verified { .. } | Status::Rewarded => {
+            return Err("Submission already verified".to_string());
+        }
+        Status::Rejected { .. } => return Err("Submission was rejected".to_string()),
+        Status::UnderReview { .. } => return Err("Submission is under review".to_string()),
+        Status::Processing { .. } => return Err("Submission is already being verified".to_string()),
+        Status::PendingUpload | Status::AwaitingFee | Status::Verifying => {}
+    }
+    if fees::required() && !record.fee_paid {
+        return Err("The submission fee has not been paid".to_string());
+    }
+    let image_data = crate::uploaded_image(submission_id).ok_or("No image data found")?;
+    submissions::update(submission_id, |record| record.verification_error = None);
+    Ok(Job {
+        submission_id,
+        step: VerificationStep::Decode,
+        attempts: 0,
+        image_data,
+        image: None,
+        nearest: None,
+        detection: None,
+        embedding: None,
+    })
+}
+
+/// Runs the current step of a job and moves it to the next one. Returns the
+/// artwork ID once the artwork is approved.
+fn advance(job: &mut Job) -> Result<Option<u64>, VerifyError> {
+    let submission_id = job.submission_id;
+    match job.step {
+        VerificationStep::Decode => {
+            let image = onnx::decode(&job.image_data).map_err(|err| {
+                VerifyError::Rejected(RejectionKind::InvalidImage, format!("Invalid image: {}", err))
+            })?;
+
+            // Reject copies of approved artwork and of official frames
+            let phash = phash::dhash(&image);
+            let nearest = phash::nearest(phash);
+            let duplicate = nearest.clone().filter(DuplicateMatch::is_duplicate);
+            submissions::update(submission_id, |record| {
+                record.phash = Some(phash);
+                record.duplicate_of = duplicate.clone();
+            });
+            if let Some(duplicate) = duplicate {
+                ic_cdk::println!("[Verify Artwork] {}", duplicate.describe());
+                return Err(VerifyError::Rejected(RejectionKind::Duplicate, duplicate.describe()));
+            }
+
+            ic_cdk::println!("[Verify Artwork] Processing submission {} with {} bytes", submission_id, job.image_data.len());
+            // The working image is kept in the job for all following steps.
+            job.image = Some(image.to_rgb8());
+            job.nearest = nearest;
+            job.step = VerificationStep::Detect;
+        }
+        VerificationStep::Detect => {
+            let image = job.image.as_ref().expect("image is decoded");
+            let policy = policy::policy();
+            let faces = detection::detect_faces(&policy.detector(), &policy.detection, image).map_err(|err| {
+                ic_cdk::println!("[Verify Artwork] Detection error: {}", err);
+                VerifyError::Unavailable(format!("Face detection failed: {}", err))
+            })?;
+            let detection = faces.into_iter().next().ok_or_else(|| {
+                VerifyError::Rejected(RejectionKind::NoFace, "Face detection failed: No face detected".to_string())
+            })?;
+            ic_cdk::println!("[Verify Artwork] Face detected: {:?}", detection.0);
+            job.detection = Some(detection);
+            job.step = VerificationStep::Embed;
+        }
+        VerificationStep::Embed => {
+            let image = job.image.as_ref().expect("image is decoded");
+            let detection = job.detection.as_ref().expect("face is detected");
+            let face = onnx::crop_to_box(image, &detection.0);
+            let embedding = onnx::embedding_image(&face).map_err(|err| {
+                ic_cdk::println!("[Verify Artwork] Recognition error: {}", err);
+                VerifyError::Unavailable(format!("Face recognition failed: {}", err))
+            })?;
+            job.embedding = Some(embedding);
+            job.step = VerificationStep::Classify;
+        }
+        VerificationStep::Classify => {
+            let image = job.image.as_ref().expect("image is decoded");
+            if let Some(probability) = onnx::classify_image(image) {
+                let probability = probability.map_err(|err| {
+                    VerifyError::Unavailable(format!("Classification failed: {}", err))
+                })?;
+                ic_cdk::println!("[Verify Artwork] Classifier probability: {}", probability);
+                if probability < CLASSIFIER_THRESHOLD {
+                    return Err(VerifyError::Rejected(
+                        RejectionKind::NotLain,
+                        format!("Classification failed: Lain probability {:.2}", probability),
+                    ));
+                }
+            }
+            job.step = VerificationStep::Persist;
+        }
+        VerificationStep::Persist => {
+            let embedding = job.embedding.as_ref().expect("embedding is computed");
+            let matches = onnx::rank_all(embedding).map_err(|err| {
+                ic_cdk::println!("[Verify Artwork] Recognition error: {}", err);
+                VerifyError::Unavailable(format!("Face recognition failed: {}", err))
+            })?;
+            let ranking = onnx::Ranking::top(&matches, REVIEW_RANKING_SIZE);
+            let (person, negative) = onnx::closest_identities(&matches);
+            let person = person.ok_or_else(|| {
+                VerifyError::Unavailable("Face recognition failed: No positive identity is enrolled".to_string())
+            })?;
+            ic_cdk::println!("[Verify Artwork] Recognition result: {} with score {}", person.label, person.score);
+
+            if let Some(negative) = negative.filter(|_| onnx::rejected_by_negative(person, negative)) {
+                return Err(VerifyError::Rejected(
+                    RejectionKind::NotLain,
+                    format!(
+                        "Face recognition failed: Closer to non-Lain reference '{}' ({:.3}) than to '{}' ({:.3})",
+                        negative.label, negative.score, person.label, person.score
+                    ),
+                ));
+            }
+
+            let is_lain = person.label.to_lowercase() == "lain";
+            if is_lain && person.score <= onnx::THRESHOLD {
+                // Verification successful! Store the artwork and mint its NFT
+                let (bounding_box, confidence) = job.detection.as_ref().expect("face is detected");
+                let originality = scoring::originality(job.nearest.as_ref());
+                let visibility = scoring::visibility(bounding_box, *confidence, person.score);
+                ic_cdk::println!("[Verify Artwork] Originality {}, visibility {}", originality, visibility);
+
+                let record: Submission = submissions::get(submission_id).expect("submission exists");
+                // Artwork approved since the Decode step can be a copy of this one.
+                let duplicate = record.phash.and_then(phash::nearest).filter(DuplicateMatch::is_duplicate);
+                if let Some(duplicate) = duplicate {
+                    submissions::update(submission_id, |record| record.duplicate_of = Some(duplicate.clone()));
+                    return Err(VerifyError::Rejected(RejectionKind::Duplicate, duplicate.describe()));
+                }
+                let image_data = std::mem::take(&mut job.image_data);
+                let artwork_id = crate::approve_artwork(&record, image_data, person.score, originality, visibility);
+                ic_cdk::println!("[Verify Artwork] ✓ Artwork approved and stored with ID {}", artwork_id);
+                return Ok(Some(artwork_id));
+            } else if is_lain && review::is_borderline(person.score) {
+                let reason = format!("Borderline recognition distance {:.3}", person.score);
+                let image_data = std::mem::take(&mut job.image_data);
+                review::enqueue(submission_id, image_data, reason.clone(), Some(person.score), Some(ranking.clone()));
+                return Err(VerifyError::Queued(format!("Submission queued for human review: {}", reason)));
+            } else if person.score > onnx::THRESHOLD {
+                return Err(VerifyError::Rejected(
+                    RejectionKind::NotLain,
+                    "Face recognition failed: Unknown person".to_string(),
+                ));
+            } else {
+                return Err(VerifyError::Rejected(
+                    RejectionKind::NotLain,
+                    format!("Face recognition failed: Detected '{}' instead of 'Lain'", person.label),
+                ));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Records the failure of a job and returns its message.
+fn fail(submission_id: u64, err: VerifyError) -> String {
+    match err {
+        VerifyError::Queued(reason) => reason,
+        VerifyError::Rejected(kind, reason) => {
+            crate::reject_submission(submission_id, kind, reason.clone());
+            reason
+        }
+        VerifyError::Unavailable(reason) => {
+            ic_cdk::println!("[Verify Artwork] Submission {} could not be verified: {}", submission_id, reason);
+            submissions::update(submission_id, |record| {
+                if matches!(record.status, Status::Processing { .. }) {
+                    record.status = Status::Verifying;
+                }
+                record.verification_error = Some(reason.clone());
+            });
+            reason
+        }
+    }
+}
+
+/// Runs the whole pipeline for a submission in the current message.
+pub fn verify_now(submission_id: u64) -> Result<u64, String> {
+    let mut job = prepare(submission_id)?;
+    loop {
+        match advance(&mut job) {
+            Ok(Some(artwork_id)) => return Ok(artwork_id),
+            Ok(None) => {}
+            Err(err) => return Err(fail(submission_id, err)),
+        }
+    }
+}
+
+fn schedule() {
+    if !SCHEDULED.replace(true) {
+        ic_cdk_timers::set_timer(Duration::ZERO, begin_step);
+    }
+}
+
+/// Marks the start of the next step in its own message, so that the attempt
+/// is counted even if the step itself traps.
+fn begin_step() {
+    SCHEDULED.set(false);
+    if pause::is_paused(PauseSwitch::Verification) {
+        return;
+    }
+    let Some((submission_id, step, attempts)) = QUEUE.with(|queue| {
+        queue.borrow_mut().front_mut().map(|job| {
+            job.attempts += 1;
+            (job.submission_id, job.step, job.attempts)
+        })
+    }) else {
+        return;
+    };
+
+    if attempts > MAX_ATTEMPTS {
+        QUEUE.with(|queue| queue.borrow_mut().pop_front());
+        let reason = format!("Verification step {:?} failed {} times", step, MAX_ATTEMPTS);
+        fail(submission_id, VerifyError::Unavailable(reason));
+        schedule_if_pending();
+        return;
+    }
+
+    submissions::update(submission_id, |record| record.status = Status::Processing { step });
+    WATCHDOG.set(Some(ic_cdk_timers::set_timer(WATCHDOG_DELAY, begin_step)));
+    ic_cdk_timers::set_timer(Duration::ZERO, run_step);
+}
+
+fn run_step() {
+    if let Some(watchdog) = WATCHDOG.take() {
+        ic_cdk_timers::clear_timer(watchdog);
+    }
+    let Some(mut job) = QUEUE.with(|queue| queue.borrow_mut().pop_front()) else {
+        return;
+    };
+
+    match advance(&mut job) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            job.attempts = 0;
+            QUEUE.with(|queue| queue.borrow_mut().push_front(job));
+        }
+        Err(err) => {
+            fail(job.submission_id, err);
+        }
+    }
+    schedule_if_pending();
+}
+
+/// Number of submissions waiting in the verification queue.
+pub fn queued() -> u64 {
+    QUEUE.with(|queue| queue.borrow().len() as u64)
+}
+
+fn schedule_if_pending() {
+    if QUEUE.with(|queue| !queue.borrow().is_empty()) {
+        schedule();
+    }
+}
+
+/// Continues the queued verifications after verification is resumed.
+pub fn resume() {
+    schedule_if_pending();
+}
+
+/// Moves submissions whose verification was interrupted by an upgrade back to
+/// `Verifying`. Submissions whose image did not survive the upgrade, e.g. one
+/// without `prepare_upgrade` that ran out of instructions, go back to
+/// `PendingUpload` instead, so that the creator can upload the image again.
+pub fn reset_interrupted() {
+    let pending = submissions::filter(|sub| {
+        matches!(
+            sub.status,
+            Status::PendingUpload | Status::AwaitingFee | Status::Verifying | Status::Processing { .. }
+        )
+    });
+    for sub in pending {
+        if crate::uploaded_image(sub.id).is_some() {
+            if matches!(sub.status, Status::Processing { .. }) {
+                submissions::update(sub.id, |record| record.status = Status::Verifying);
+            }
+            continue;
+        }
+        crate::reopen_upload(sub.id, sub.creator);
+        if sub.status != Status::PendingUpload {
+            submissions::update(sub.id, |record| {
+                record.status = Status::PendingUpload;
+                record.art = None;
+                record.verification_error = Some("The uploaded image was lost in an upgrade, upload it again".to_string());
+            });
+        }
+    }
+}
+
+/// Queues the verification of a submission. The steps run in the background;
+/// follow the progress with `get_submission`.
+#[ic_cdk::update(guard = "not_restoring")]
+fn trigger_verification(submission_id: Nat) -> Result<(), ServiceError> {
+    pause::check(PauseSwitch::Verification)?;
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    let job = prepare(submission_id)?;
+    submissions::update(submission_id, |record| {
+        record.status = Status::Processing { step: VerificationStep::Decode };
+    });
+    QUEUE.with(|queue| queue.borrow_mut().push_back(job));
+    schedule();
+    Ok(())
+}
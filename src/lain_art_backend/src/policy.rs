@@ -0,0 +1,88 @@
+//! The verification policy.
+//!
+//! Settings of the verification pipeline that controllers can change without
+//! an upgrade. New settings are added as `Option` fields so that the stored
+//! policy keeps decoding.
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableCell;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::auth::is_admin;
+use crate::detection::{DetectionMode, DetectorModel};
+use crate::onnx;
+use crate::storage::{self, Mem, POLICY_MEMORY_ID};
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VerificationPolicy {
+    /// How faces are detected in the working image.
+    pub detection: DetectionMode,
+    /// Which face detection model to use, `Photo` if unset.
+    pub detector: Option<DetectorModel>,
+}
+
+impl VerificationPolicy {
+    pub fn detector(&self) -> DetectorModel {
+        self.detector.clone().unwrap_or(DetectorModel::Photo)
+    }
+}
+
+impl Default for VerificationPolicy {
+    fn default() -> Self {
+        Self { detection: DetectionMode::Full, detector: None }
+    }
+}
+
+impl Storable for VerificationPolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static POLICY: RefCell<StableCell<VerificationPolicy, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(POLICY_MEMORY_ID), VerificationPolicy::default())
+            .expect("Failed to init verification policy")
+    );
+}
+
+pub fn policy() -> VerificationPolicy {
+    POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+/// Returns the verification policy.
+#[ic_cdk::query]
+fn get_verification_policy() -> VerificationPolicy {
+    policy()
+}
+
+/// Sets the verification policy. Applies to verification steps started afterwards.
+/// Selecting the anime face detector requires it to be loaded.
+#[ic_cdk::update(guard = "is_admin")]
+fn set_verification_policy(policy: VerificationPolicy) -> Result<(), String> {
+    if let DetectionMode::Tiled { tile_width, tile_height, overlap } = policy.detection {
+        if tile_width == 0 || tile_height == 0 {
+            return Err("Tiles must not be empty".to_string());
+        }
+        if overlap >= tile_width.min(tile_height) {
+            return Err("The overlap must be smaller than the tiles".to_string());
+        }
+    }
+    if policy.detector() == DetectorModel::Anime && !onnx::has_detector(&DetectorModel::Anime) {
+        return Err("Upload the anime face detection model and call setup_models first".to_string());
+    }
+    POLICY.with(|cell| {
+        cell.borrow_mut()
+            .set(policy)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store verification policy"));
+    });
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! Model input preprocessing.
+//!
+//! Every model has a `PreprocessSpec` describing how an image becomes its input
+//! tensor: size, resize filter, stretching or letterboxing, channel order,
+//! tensor layout and normalization. Controllers can store a spec per model
+//! kind, which replaces the built-in default, so that a model can be swapped
+//! for one with different inputs without a code change.
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableBTreeMap;
+use image::imageops::FilterType;
+use image::{Rgb, RgbImage};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use tract_onnx::prelude::*;
+
+use crate::auth::is_admin;
+use crate::onnx::{self, BoundingBox};
+use crate::storage::{self, Mem, PREPROCESS_MEMORY_ID};
+
+/// Gray used to pad letterboxed inputs.
+const LETTERBOX_FILL: Rgb<u8> = Rgb([114, 114, 114]);
+
+const IMAGENET_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const IMAGENET_STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ModelKind {
+    FaceDetection,
+    FaceRecognition,
+    LainClassifier,
+    AnimeFaceDetection,
+}
+
+impl ModelKind {
+    fn key(self) -> u8 {
+        match self {
+            Self::FaceDetection => 0,
+            Self::FaceRecognition => 1,
+            Self::LainClassifier => 2,
+            Self::AnimeFaceDetection => 3,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TensorLayout {
+    /// Batch, channel, height, width.
+    Nchw,
+    /// Batch, height, width, channel.
+    Nhwc,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ResizeMode {
+    /// Resize to the input size, ignoring the aspect ratio.
+    Stretch,
+    /// Keep the aspect ratio and pad the rest of the input with gray.
+    Letterbox,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PreprocessSpec {
+    pub width: u32,
+    pub height: u32,
+    pub channels: ChannelOrder,
+    pub layout: TensorLayout,
+    /// Per-channel mean and standard deviation, in the order of `channels`.
+    /// Pixel values are scaled to 0-1 first, so raw 0-255 inputs use a mean
+    /// of 0 and a standard deviation of 1/255.
+    pub mean: Vec<f32>,
+    pub std: Vec<f32>,
+    pub filter: ResizeFilter,
+    pub resize: ResizeMode,
+}
+
+impl Storable for PreprocessSpec {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Where the image ended up in the model input, in input-relative coordinates.
+/// Maps boxes found in the input back to the image.
+#[derive(Clone, Copy, Debug)]
+pub struct InputFrame {
+    left: f32,
+    top: f32,
+    width: f32,
+    height: f32,
+}
+
+impl InputFrame {
+    pub fn to_image(&self, bbox: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            left: (bbox.left - self.left) / self.width,
+            top: (bbox.top - self.top) / self.height,
+            right: (bbox.right - self.left) / self.width,
+            bottom: (bbox.bottom - self.top) / self.height,
+        }
+    }
+}
+
+impl PreprocessSpec {
+    /// A stretched RGB NCHW input of the given size.
+    pub fn new(width: u32, height: u32, mean: [f32; 3], std: [f32; 3]) -> Self {
+        Self {
+            width,
+            height,
+            channels: ChannelOrder::Rgb,
+            layout: TensorLayout::Nchw,
+            mean: mean.to_vec(),
+            std: std.to_vec(),
+            filter: ResizeFilter::Triangle,
+            resize: ResizeMode::Stretch,
+        }
+    }
+
+    /// UltraFace (`version-RFB-320.onnx`): 320x240 with ImageNet normalization.
+    pub fn ultraface() -> Self {
+        Self::new(320, 240, IMAGENET_MEAN, IMAGENET_STD)
+    }
+
+    /// The face recognition model: 160x160 scaled to 0-1.
+    pub fn face_recognition() -> Self {
+        Self::new(160, 160, [0.0; 3], [1.0; 3])
+    }
+
+    /// The Lain classifier: 224x224 with ImageNet normalization.
+    pub fn lain_classifier() -> Self {
+        Self::new(224, 224, IMAGENET_MEAN, IMAGENET_STD)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err("The input size must not be empty".to_string());
+        }
+        if self.mean.len() != 3 || self.std.len() != 3 {
+            return Err("Mean and std need one value per channel".to_string());
+        }
+        if self.std.contains(&0.0) {
+            return Err("The std must not be zero".to_string());
+        }
+        Ok(())
+    }
+
+    /// Converts the image into the model input.
+    pub fn apply(&self, image: &RgbImage) -> (Tensor, InputFrame) {
+        let (width, height) = (self.width, self.height);
+        let filter = FilterType::from(self.filter);
+        let (input, frame) = match self.resize {
+            ResizeMode::Stretch => {
+                let frame = InputFrame { left: 0.0, top: 0.0, width: 1.0, height: 1.0 };
+                (image::imageops::resize(image, width, height, filter), frame)
+            }
+            ResizeMode::Letterbox => {
+                let (image_width, image_height) = image.dimensions();
+                let scale = (width as f32 / image_width as f32).min(height as f32 / image_height as f32);
+                let scaled_width = ((image_width as f32 * scale).round() as u32).clamp(1, width);
+                let scaled_height = ((image_height as f32 * scale).round() as u32).clamp(1, height);
+                let (left, top) = ((width - scaled_width) / 2, (height - scaled_height) / 2);
+
+                let mut canvas = RgbImage::from_pixel(width, height, LETTERBOX_FILL);
+                let scaled = image::imageops::resize(image, scaled_width, scaled_height, filter);
+                image::imageops::overlay(&mut canvas, &scaled, left as i64, top as i64);
+                let frame = InputFrame {
+                    left: left as f32 / width as f32,
+                    top: top as f32 / height as f32,
+                    width: scaled_width as f32 / width as f32,
+                    height: scaled_height as f32 / height as f32,
+                };
+                (canvas, frame)
+            }
+        };
+
+        let value = |x: usize, y: usize, c: usize| {
+            let channel = match self.channels {
+                ChannelOrder::Rgb => c,
+                ChannelOrder::Bgr => 2 - c,
+            };
+            (input[(x as u32, y as u32)][channel] as f32 / 255.0 - self.mean[c]) / self.std[c]
+        };
+        let (width, height) = (width as usize, height as usize);
+        let tensor = match self.layout {
+            TensorLayout::Nchw => {
+                tract_ndarray::Array4::from_shape_fn((1, 3, height, width), |(_, c, y, x)| value(x, y, c))
+            }
+            TensorLayout::Nhwc => {
+                tract_ndarray::Array4::from_shape_fn((1, height, width, 3), |(_, y, x, c)| value(x, y, c))
+            }
+        };
+        (tensor.into(), frame)
+    }
+}
+
+thread_local! {
+    // Stable preprocessing specs: model kind -> spec
+    static SPECS: RefCell<StableBTreeMap<u8, PreprocessSpec, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(PREPROCESS_MEMORY_ID))
+    );
+}
+
+/// Returns the spec stored for a model kind, if any.
+pub fn stored(kind: ModelKind) -> Option<PreprocessSpec> {
+    SPECS.with(|specs| specs.borrow().get(&kind.key()))
+}
+
+/// Returns the spec a model kind is run with: the stored one, or the default of the loaded model.
+#[ic_cdk::query]
+fn get_preprocess_spec(kind: ModelKind) -> Option<PreprocessSpec> {
+    stored(kind).or_else(|| onnx::loaded_spec(kind))
+}
+
+/// Stores the preprocessing spec of a model kind and applies it to the loaded
+/// model. `None` restores the default on the next `setup_models`.
+#[ic_cdk::update(guard = "is_admin")]
+fn set_preprocess_spec(kind: ModelKind, spec: Option<PreprocessSpec>) -> Result<(), String> {
+    match spec {
+        Some(spec) => {
+            spec.validate()?;
+            SPECS.with(|specs| specs.borrow_mut().insert(kind.key(), spec.clone()));
+            onnx::set_loaded_spec(kind, spec);
+        }
+        None => {
+            SPECS.with(|specs| specs.borrow_mut().remove(&kind.key()));
+        }
+    }
+    Ok(())
+}
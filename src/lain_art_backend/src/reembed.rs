@@ -0,0 +1,161 @@
+//! Re-embedding of the face references.
+//!
+//! Embeddings are only comparable when they come from the same recognition
+//! model and embedding pipeline, so every reference is tagged with both and
+//! recognition skips references of other versions. After the
+//! model is replaced, `reembed_all` recomputes the embeddings from the stored
+//! reference images. A timer handles `BATCH_SIZE` references per message, and
+//! a batch that traps is recorded as failed instead of stalling the job.
+//! References enrolled before images were kept cannot be re-embedded and have
+//! to be enrolled again. Re-embedding writes the references, so it waits while
+//! enrollment is paused.
+
+use candid::{CandidType, Deserialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::auth::is_admin;
+use crate::onnx;
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::storage;
+
+/// References re-embedded per message.
+const BATCH_SIZE: usize = 4;
+
+/// Delay after which a batch that did not complete is assumed to have trapped.
+const WATCHDOG_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReembedFailure {
+    pub reference_id: u64,
+    pub label: String,
+    pub error: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ReembedStatus {
+    /// Version of the loaded recognition model.
+    pub model_version: Option<String>,
+    pub running: bool,
+    /// References whose embedding was recomputed by the current job.
+    pub reembedded: u64,
+    /// References the current job still has to process.
+    pub remaining: u64,
+    /// References whose embedding is not from the loaded model and the current pipeline.
+    pub stale: u64,
+    /// Stale references without a stored image, which have to be enrolled again.
+    pub without_image: u64,
+    pub failures: Vec<ReembedFailure>,
+}
+
+thread_local! {
+    static PENDING: RefCell<VecDeque<u64>> = const { RefCell::new(VecDeque::new()) };
+    // The batch being processed, recorded as failed if it traps
+    static BATCH: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    static STATUS: RefCell<ReembedStatus> = RefCell::new(ReembedStatus::default());
+    static WATCHDOG: Cell<Option<ic_cdk_timers::TimerId>> = const { Cell::new(None) };
+    // Whether the job stopped because enrollment is paused
+    static WAITING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn label_of(reference_id: u64) -> String {
+    storage::get_all_faces()
+        .into_iter()
+        .find(|reference| reference.id == reference_id)
+        .map_or_else(String::new, |reference| reference.label)
+}
+
+fn record_failure(reference_id: u64, error: String) {
+    let label = label_of(reference_id);
+    STATUS.with_borrow_mut(|status| status.failures.push(ReembedFailure { reference_id, label, error }));
+}
+
+/// Takes the next batch off the queue in its own message, so that a batch
+/// that traps is not retried forever.
+fn begin_batch() {
+    for reference_id in BATCH.take() {
+        record_failure(reference_id, "Re-embedding trapped".to_string());
+    }
+    if pause::is_paused(PauseSwitch::Enrollment) {
+        WAITING.set(true);
+        return;
+    }
+    let batch: Vec<u64> = PENDING.with_borrow_mut(|pending| {
+        let size = pending.len().min(BATCH_SIZE);
+        pending.drain(..size).collect()
+    });
+    if batch.is_empty() {
+        STATUS.with_borrow_mut(|status| status.running = false);
+        return;
+    }
+    BATCH.set(batch);
+    WATCHDOG.set(Some(ic_cdk_timers::set_timer(WATCHDOG_DELAY, begin_batch)));
+    ic_cdk_timers::set_timer(Duration::ZERO, run_batch);
+}
+
+fn run_batch() {
+    if let Some(watchdog) = WATCHDOG.take() {
+        ic_cdk_timers::clear_timer(watchdog);
+    }
+    let version = onnx::recognition_model_version();
+    for reference_id in BATCH.take() {
+        let result = storage::face_image(reference_id)
+            .ok_or_else(|| "No reference image stored".to_string())
+            .and_then(|image| onnx::embedding(image).map_err(|err| err.to_string()));
+        match result {
+            Ok(embedding) => {
+                storage::update_face_embedding(reference_id, embedding.v0, version.clone());
+                STATUS.with_borrow_mut(|status| status.reembedded += 1);
+            }
+            Err(error) => record_failure(reference_id, error),
+        }
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, begin_batch);
+}
+
+/// Continues a job that waited for enrollment to be resumed.
+pub fn resume() {
+    if WAITING.take() {
+        ic_cdk_timers::set_timer(Duration::ZERO, begin_batch);
+    }
+}
+
+/// Whether a `reembed_all` job is running.
+pub fn is_running() -> bool {
+    STATUS.with_borrow(|status| status.running)
+}
+
+/// Returns the progress of the last `reembed_all` and the number of stale references.
+#[ic_cdk::query]
+fn get_reembed_status() -> ReembedStatus {
+    let model_version = onnx::recognition_model_version();
+    let stale: Vec<u64> = storage::get_all_faces()
+        .into_iter()
+        .filter(|reference| !reference.is_current(&model_version))
+        .map(|reference| reference.id)
+        .collect();
+    let without_image = stale.iter().filter(|id| storage::face_image(**id).is_none()).count() as u64;
+    let stale = stale.len() as u64;
+    let remaining = (PENDING.with_borrow(|pending| pending.len()) + BATCH.with_borrow(|batch| batch.len())) as u64;
+    STATUS.with_borrow(|status| ReembedStatus { model_version, remaining, stale, without_image, ..status.clone() })
+}
+
+/// Recomputes the embedding of every reference with the loaded recognition
+/// model, in the background. Run it after replacing the model or its
+/// preprocessing; follow the progress with `get_reembed_status`.
+#[ic_cdk::update(guard = "is_admin")]
+fn reembed_all() -> Result<ReembedStatus, ServiceError> {
+    pause::check(PauseSwitch::Enrollment)?;
+    if is_running() {
+        return Err("Re-embedding is already running".into());
+    }
+    if onnx::recognition_model_version().is_none() {
+        return Err("Face recognition model is not loaded".into());
+    }
+    let ids: VecDeque<u64> = storage::get_all_faces().iter().map(|reference| reference.id).collect();
+    PENDING.set(ids);
+    STATUS.set(ReembedStatus { running: true, ..ReembedStatus::default() });
+    ic_cdk_timers::set_timer(Duration::ZERO, begin_batch);
+    Ok(get_reembed_status())
+}
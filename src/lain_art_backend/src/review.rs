@@ -0,0 +1,235 @@
+//! Human review of borderline verification results.
+//!
+//! Submissions whose face is close to, but not within, the recognition
+//! threshold are not rejected outright: they move to `UnderReview` and their
+//! image is kept in stable memory until a reviewer scores them with
+//! `set_verdict`. Controllers can force any decision with `override_verdict`.
+//! Every decision is recorded on the submission with its author and time.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::StableBTreeMap;
+use num_traits::ToPrimitive;
+use std::cell::RefCell;
+
+use crate::appeals;
+use crate::auth::{is_admin, is_reviewer};
+use crate::onnx::{Ranking, THRESHOLD};
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::scoring::SCALE;
+use crate::storage::{self, Mem, REVIEW_IMAGES_MEMORY_ID};
+use crate::submissions::{self, RejectionKind, Status, Submission};
+
+/// Recognition distances up to `THRESHOLD + REVIEW_MARGIN` go to the review
+/// queue instead of being rejected.
+pub const REVIEW_MARGIN: f32 = 0.1;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum DecisionKind {
+    /// A reviewer's scores, on the 0-10000 scale of `scoring`.
+    Verdict { originality: u16, visibility: u16, note: String },
+    /// A controller forced the status of the submission.
+    Override { status: Status, reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Decision {
+    pub reviewer: Principal,
+    pub decided_at: u64,
+    pub kind: DecisionKind,
+}
+
+/// The review history of a submission.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Review {
+    /// Why the submission needs a human decision.
+    pub reason: String,
+    pub queued_at: u64,
+    /// Embedding distance of the closest enrolled Lain face, if one was measured.
+    pub recognition_score: Option<f32>,
+    /// The closest enrolled identities, if recognition ran.
+    pub ranking: Option<Ranking>,
+    pub decisions: Vec<Decision>,
+}
+
+thread_local! {
+    // Images of submissions awaiting review: submission id -> image
+    static IMAGES: RefCell<StableBTreeMap<u64, Vec<u8>, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(REVIEW_IMAGES_MEMORY_ID))
+    );
+}
+
+/// Whether a recognition distance is close enough to the threshold to deserve a review.
+pub fn is_borderline(distance: f32) -> bool {
+    distance > THRESHOLD && distance <= THRESHOLD + REVIEW_MARGIN
+}
+
+/// Moves a submission to the review queue and keeps its image until it is decided.
+pub fn enqueue(
+    submission_id: u64,
+    image_data: Vec<u8>,
+    reason: String,
+    recognition_score: Option<f32>,
+    ranking: Option<Ranking>,
+) {
+    IMAGES.with(|images| {
+        images.borrow_mut().insert(submission_id, image_data);
+    });
+    submissions::update(submission_id, |sub| {
+        let now = ic_cdk::api::time();
+        sub.status = Status::UnderReview { reason: reason.clone() };
+        let review = sub.review.get_or_insert_with(|| Review {
+            reason: reason.clone(),
+            queued_at: now,
+            recognition_score,
+            ranking: ranking.clone(),
+            decisions: Vec::new(),
+        });
+        review.reason = reason;
+        review.queued_at = now;
+        review.recognition_score = recognition_score.or(review.recognition_score);
+        review.ranking = ranking.or(review.ranking.take());
+    });
+    crate::release_upload(submission_id);
+    ic_cdk::println!("[Review] Submission {} queued for review", submission_id);
+}
+
+fn record_decision(submission_id: u64, kind: DecisionKind) {
+    submissions::update(submission_id, |sub| {
+        let now = ic_cdk::api::time();
+        let review = sub.review.get_or_insert_with(|| Review {
+            reason: "Decided without review".to_string(),
+            queued_at: now,
+            recognition_score: None,
+            ranking: None,
+            decisions: Vec::new(),
+        });
+        review.decisions.push(Decision {
+            reviewer: ic_cdk::caller(),
+            decided_at: now,
+            kind,
+        });
+    });
+}
+
+/// Keeps the uploaded image of a rejected submission while its rejection can be appealed.
+pub fn keep_for_appeal(submission_id: u64) {
+    let Some(sub) = submissions::get(submission_id) else { return };
+    if appeals::window_remaining(&sub) == 0 {
+        return;
+    }
+    if let Some(image_data) = crate::uploaded_image(submission_id) {
+        IMAGES.with(|images| images.borrow_mut().insert(submission_id, image_data));
+    }
+}
+
+/// Drops the images of rejected submissions that can no longer be appealed.
+pub fn drop_final_rejections() {
+    let decided = submissions::filter(|sub| {
+        matches!(sub.status, Status::Rejected { .. }) && appeals::window_remaining(sub) == 0
+    });
+    IMAGES.with(|images| {
+        let mut images = images.borrow_mut();
+        for sub in decided {
+            images.remove(&sub.id);
+        }
+    });
+}
+
+/// Returns the image of a submission: the copy kept for review or the upload buffer.
+pub fn image(submission_id: u64) -> Result<Vec<u8>, String> {
+    IMAGES
+        .with(|images| images.borrow().get(&submission_id))
+        .or_else(|| crate::uploaded_image(submission_id))
+        .ok_or_else(|| "The image of this submission is no longer available".to_string())
+}
+
+fn approve(sub: &Submission, image_data: Vec<u8>, originality: u16, visibility: u16) {
+    // Without a measured distance, record the acceptance limit.
+    let recognition_score = sub
+        .review
+        .as_ref()
+        .and_then(|review| review.recognition_score)
+        .unwrap_or(THRESHOLD);
+    crate::approve_artwork(sub, image_data, recognition_score, originality.min(SCALE), visibility.min(SCALE));
+    IMAGES.with(|images| images.borrow_mut().remove(&sub.id));
+}
+
+fn reject(sub: &Submission, kind: RejectionKind, reason: String) {
+    crate::reject_submission(sub.id, kind, reason);
+}
+
+/// Returns the submissions awaiting review, oldest first.
+#[ic_cdk::query(guard = "is_reviewer")]
+fn list_review_queue() -> Vec<Submission> {
+    submissions::filter(|sub| matches!(sub.status, Status::UnderReview { .. }))
+}
+
+/// Decides a submission in the review queue. It is approved with the given
+/// scores, unless one of them is zero: a visibility of 0 rejects it as not
+/// showing Lain, an originality of 0 as a copy. Verdicts wait while
+/// verification is paused.
+#[ic_cdk::update(guard = "is_reviewer")]
+fn set_verdict(submission_id: Nat, originality: u16, visibility: u16, note: String) -> Result<(), ServiceError> {
+    pause::check(PauseSwitch::Verification)?;
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if !matches!(sub.status, Status::UnderReview { .. }) {
+        return Err("Submission is not under review".into());
+    }
+    let (originality, visibility) = (originality.min(SCALE), visibility.min(SCALE));
+    let approved = originality > 0 && visibility > 0;
+    if approved && sub.refund.is_some() {
+        return Err("The fee of this submission was already refunded".into());
+    }
+    let image_data = if approved { Some(image(submission_id)?) } else { None };
+
+    record_decision(submission_id, DecisionKind::Verdict { originality, visibility, note: note.clone() });
+    appeals::resolve(submission_id, image_data.is_some(), note.clone());
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    match image_data {
+        Some(image_data) => approve(&sub, image_data, originality, visibility),
+        None if visibility == 0 => reject(&sub, RejectionKind::NotLain, format!("Rejected by review: {}", note)),
+        None => reject(&sub, RejectionKind::Duplicate, format!("Rejected by review: {}", note)),
+    }
+    Ok(())
+}
+
+/// Forces the outcome of a submission. Only `Verified` (which approves the
+/// artwork with the given scores) and `Rejected` are accepted, approved
+/// artwork cannot be rejected anymore and refunded submissions cannot be approved.
+/// Like verdicts, overrides wait while verification is paused.
+#[ic_cdk::update(guard = "is_admin")]
+fn override_verdict(submission_id: Nat, status: Status, reason: String) -> Result<(), ServiceError> {
+    pause::check(PauseSwitch::Verification)?;
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if sub.artwork_id.is_some() {
+        return Err("The artwork of this submission is already approved".into());
+    }
+    if matches!(sub.status, Status::Processing { .. }) {
+        return Err("Submission is being verified".into());
+    }
+    let approval = match &status {
+        Status::Verified { originality, visibility } => {
+            if sub.refund.is_some() {
+                return Err("The fee of this submission was already refunded".into());
+            }
+            Some((*originality, *visibility, image(submission_id)?))
+        }
+        Status::Rejected { .. } => None,
+        _ => return Err("Only Verified and Rejected can be set".into()),
+    };
+
+    record_decision(submission_id, DecisionKind::Override { status: status.clone(), reason: reason.clone() });
+    appeals::resolve(submission_id, approval.is_some(), reason.clone());
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    match (approval, status) {
+        (Some((originality, visibility, image_data)), _) => approve(&sub, image_data, originality, visibility),
+        (None, Status::Rejected { reason: rejection }) => {
+            let kind = sub.rejection.unwrap_or(RejectionKind::NotLain);
+            reject(&sub, kind, if rejection.is_empty() { reason } else { rejection });
+        }
+        (None, _) => return Err("Only Verified and Rejected can be set".into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! Creator reward payouts.
+//!
+//! Once a submission is verified, a configurable reward is transferred from the
+//! treasury subaccount of this canister to the creator via ICRC-1. Payouts are
+//! idempotent: a rewarded submission returns its recorded result, and retries of
+//! a failed payout replay the transfer of the first attempt, amount,
+//! `created_at_time` and memo included, so that the ledger deduplicates them.
+//! Once the ledger's deduplication window has passed, its block log is searched
+//! for the transfer before it is sent again, so the ledger must implement
+//! ICRC-3. A transfer the ledger refused is rebuilt from the current
+//! configuration on the next attempt.
+
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableCell;
+use num_traits::ToPrimitive;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::auth::is_admin;
+use crate::backup::not_restoring;
+use crate::pause::{self, PauseSwitch, ServiceError};
+use crate::storage::{self, Mem, REWARD_CONFIG_MEMORY_ID};
+use crate::submissions::{self, RewardResult, RewardTransfer, Status};
+use crate::transactions::{self, Account, TransferFailure};
+
+const ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RewardConfig {
+    /// The ICRC-1 ledger the rewards are paid on.
+    pub ledger: Principal,
+    /// Reward per verified submission, in the ledger's smallest unit. Zero disables rewards.
+    pub amount: Nat,
+    /// Subaccount of this canister that holds the reward treasury.
+    pub treasury_subaccount: Option<Vec<u8>>,
+}
+
+impl Default for RewardConfig {
+    fn default() -> Self {
+        Self {
+            ledger: Principal::from_text(ICP_LEDGER).unwrap(),
+            amount: Nat::from(0u64),
+            treasury_subaccount: None,
+        }
+    }
+}
+
+impl Storable for RewardConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<RewardConfig, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(REWARD_CONFIG_MEMORY_ID), RewardConfig::default())
+            .expect("Failed to init reward config")
+    );
+
+    // Submissions with a payout currently awaiting the ledger
+    static IN_PROGRESS: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+}
+
+pub fn config() -> RewardConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn enabled() -> bool {
+    config().amount != Nat::from(0u64)
+}
+
+/// Marks a payout as in progress for as long as it is alive.
+struct PayoutGuard(u64);
+
+impl PayoutGuard {
+    fn acquire(submission_id: u64) -> Result<Self, String> {
+        IN_PROGRESS.with(|in_progress| {
+            if in_progress.borrow_mut().insert(submission_id) {
+                Ok(Self(submission_id))
+            } else {
+                Err("A reward payout for this submission is already in progress".to_string())
+            }
+        })
+    }
+}
+
+impl Drop for PayoutGuard {
+    fn drop(&mut self) {
+        IN_PROGRESS.with(|in_progress| in_progress.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Pays the reward of a verified submission to its creator and moves it to `Rewarded`.
+pub async fn pay_reward(submission_id: u64) -> Result<RewardResult, ServiceError> {
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if let Some(reward) = sub.reward {
+        return Ok(reward);
+    }
+    if !matches!(sub.status, Status::Verified { .. }) {
+        return Err("Only verified submissions can be rewarded".into());
+    }
+    pause::check(PauseSwitch::Rewards)?;
+    let token_id = sub.nft_token_id.ok_or("No NFT was minted for this submission")?;
+
+    let config = config();
+    if config.amount == Nat::from(0u64) {
+        return Err("Rewards are not configured".into());
+    }
+
+    let _guard = PayoutGuard::acquire(submission_id)?;
+
+    let transfer = match sub.reward_transfer {
+        Some(transfer) => transfer,
+        None => {
+            let transfer = RewardTransfer {
+                ledger: config.ledger,
+                from_subaccount: config.treasury_subaccount,
+                to: Account::new(sub.creator, None),
+                amount: config.amount,
+                memo: transactions::submission_memo(submission_id),
+                created_at_time: sub.reward_created_at.unwrap_or_else(ic_cdk::api::time),
+            };
+            submissions::update(submission_id, |sub| sub.reward_transfer = Some(transfer.clone()));
+            transfer
+        }
+    };
+
+    let block_index = match transactions::icrc1_transfer(transfer.ledger, transfer.arg()).await {
+        Ok(block_index) => block_index,
+        Err(TransferFailure::TooOld) => match transactions::find_transfer(transfer.ledger, &transfer.arg()).await? {
+            Some(block_index) => block_index,
+            None => {
+                // No earlier attempt reached the ledger, so the same transfer is sent again with a fresh time
+                let transfer = RewardTransfer { created_at_time: ic_cdk::api::time(), ..transfer };
+                submissions::update(submission_id, |sub| sub.reward_transfer = Some(transfer.clone()));
+                transactions::icrc1_transfer(transfer.ledger, transfer.arg()).await.map_err(String::from)?
+            }
+        },
+        Err(TransferFailure::Refused(err)) => {
+            // The ledger checks for duplicates first, so no attempt was executed and the next one may be rebuilt
+            submissions::update(submission_id, |sub| {
+                sub.reward_transfer = None;
+                sub.reward_created_at = None;
+            });
+            return Err(err.into());
+        }
+        Err(failure) => return Err(String::from(failure).into()),
+    };
+
+    let reward = RewardResult {
+        token_id: Nat::from(token_id),
+        reward_txid: block_index.0.to_string(),
+    };
+    submissions::update(submission_id, |sub| {
+        sub.reward = Some(reward.clone());
+        sub.status = Status::Rewarded;
+    });
+    ic_cdk::println!("[Reward] Submission {} rewarded in block {}", submission_id, reward.reward_txid);
+    Ok(reward)
+}
+
+/// Returns the current reward configuration.
+#[ic_cdk::query]
+fn get_reward_config() -> RewardConfig {
+    config()
+}
+
+/// Sets the reward configuration. Enabled rewards need a ledger that
+/// implements ICRC-3 and a treasury subaccount of 32 bytes.
+#[ic_cdk::update(guard = "is_admin")]
+async fn set_reward_config(config: RewardConfig) {
+    if let Err(err) = Account::new(ic_cdk::id(), config.treasury_subaccount.clone()).validate() {
+        ic_cdk::trap(&format!("Invalid treasury subaccount: {}", err));
+    }
+    if config.amount != Nat::from(0u64) {
+        if let Err(err) = transactions::check_ledger(config.ledger).await {
+            ic_cdk::trap(&err);
+        }
+    }
+    CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store reward config"));
+    });
+}
+
+/// Pays (or returns the already paid) reward of a verified submission.
+/// Callable by the creator of the submission and by controllers.
+#[ic_cdk::update(guard = "not_restoring")]
+async fn finalize_reward(submission_id: Nat) -> Result<RewardResult, ServiceError> {
+    let submission_id = submission_id.0.to_u64().ok_or("Submission ID not found")?;
+    let sub = submissions::get(submission_id).ok_or("Submission ID not found")?;
+    if sub.creator != ic_cdk::caller() && is_admin().is_err() {
+        return Err("Only the creator can finalize the reward".into());
+    }
+    pay_reward(submission_id).await
+}
@@ -0,0 +1,99 @@
+//! Versioned stable state.
+//!
+//! Stable records are Candid-encoded and decoded with `unwrap`, so a record the
+//! code cannot decode any more traps. Fields added to a record must therefore
+//! be `Option`s; anything else, such as moving data to another structure or
+//! filling in a new field, is a migration. The schema version of the stable
+//! memory is stored next to the data. `init` stamps a new canister with
+//! `SCHEMA_VERSION`, and `post_upgrade` runs the migrations between the stored
+//! version and `SCHEMA_VERSION` in order. A wasm refuses to upgrade stable
+//! memory of a newer schema, so downgrades fail instead of corrupting state.
+//!
+//! To change the schema, add a migration with the next version and bump
+//! `SCHEMA_VERSION`.
+
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+use crate::storage::{self, Mem, SCHEMA_MEMORY_ID};
+
+/// Version of the stable state this wasm reads and writes.
+pub const SCHEMA_VERSION: u32 = 4;
+
+struct Migration {
+    /// Schema version after the migration.
+    version: u32,
+    description: &'static str,
+    run: fn(),
+}
+
+/// Migrations in order. Canisters from before schema versioning have version 0.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Move the label-keyed face database to references",
+        run: storage::migrate_legacy_faces,
+    },
+    Migration {
+        version: 2,
+        description: "Record the sign of every face reference",
+        run: storage::migrate_reference_signs,
+    },
+    Migration {
+        version: 3,
+        description: "Normalize the embeddings of older face references",
+        run: storage::migrate_normalize_embeddings,
+    },
+    Migration {
+        version: 4,
+        description: "Flag the face references embedded from the whole image",
+        run: storage::migrate_flag_whole_image_references,
+    },
+];
+
+thread_local! {
+    // Schema version of the stable memory, 0 if it predates versioning
+    static VERSION: RefCell<StableCell<u32, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(SCHEMA_MEMORY_ID), 0)
+            .expect("Failed to init schema version")
+    );
+}
+
+pub fn version() -> u32 {
+    VERSION.with(|version| *version.borrow().get())
+}
+
+fn set_version(value: u32) {
+    VERSION.with(|version| {
+        version
+            .borrow_mut()
+            .set(value)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store schema version"))
+    });
+}
+
+/// Stamps the stable memory of a new canister with the current schema.
+pub fn init() {
+    set_version(SCHEMA_VERSION);
+}
+
+/// Brings the stable memory up to the current schema.
+pub fn migrate() {
+    let stored = version();
+    if stored > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stable memory has schema version {}, this wasm supports up to {}",
+            stored, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > stored) {
+        ic_cdk::println!("[Migration] Schema {}: {}", migration.version, migration.description);
+        (migration.run)();
+        set_version(migration.version);
+    }
+}
+
+#[ic_cdk::query]
+fn get_schema_version() -> u32 {
+    version()
+}
@@ -0,0 +1,87 @@
+//! Originality and visibility scores of verified artwork.
+//!
+//! Both scores use a fixed-point scale from 0 to `SCALE` (10000 = 100.00%):
+//!
+//! - **visibility** tells how clearly Lain is shown: 30% face area (a face
+//!   covering `FULL_FACE_AREA` of the image or more scores fully), 30% detection
+//!   confidence and 40% recognition match, where an embedding distance of 0
+//!   scores fully and a distance of `onnx::THRESHOLD` scores nothing.
+//! - **originality** tells how far the image is from everything known: 0 at the
+//!   duplicate distance (`phash::DUPLICATE_DISTANCE`) and below, rising linearly
+//!   to `SCALE` at `UNRELATED_DISTANCE` bits or with nothing to compare against.
+
+use crate::onnx::{BoundingBox, THRESHOLD};
+use crate::phash::{DuplicateMatch, DUPLICATE_DISTANCE};
+
+pub const SCALE: u16 = 10_000;
+
+/// Share of the image a face has to cover to count as fully visible.
+const FULL_FACE_AREA: f32 = 0.25;
+
+/// Hamming distance from which two hashes are considered unrelated. Hashes of
+/// unrelated images differ in about half of their 64 bits.
+const UNRELATED_DISTANCE: u32 = 32;
+
+fn to_scale(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * SCALE as f32).round() as u16
+}
+
+/// Scores how visible the recognized face is.
+pub fn visibility(bbox: &BoundingBox, confidence: f32, distance: f32) -> u16 {
+    let width = (bbox.right.clamp(0.0, 1.0) - bbox.left.clamp(0.0, 1.0)).max(0.0);
+    let height = (bbox.bottom.clamp(0.0, 1.0) - bbox.top.clamp(0.0, 1.0)).max(0.0);
+    let area = (width * height / FULL_FACE_AREA).min(1.0);
+    let confidence = confidence.clamp(0.0, 1.0);
+    let matching = (1.0 - distance / THRESHOLD).clamp(0.0, 1.0);
+    to_scale(0.3 * area + 0.3 * confidence + 0.4 * matching)
+}
+
+/// Scores originality from the closest gallery artwork or reference frame.
+pub fn originality(nearest: Option<&DuplicateMatch>) -> u16 {
+    let Some(nearest) = nearest else {
+        return SCALE;
+    };
+    let distance = nearest.distance.saturating_sub(DUPLICATE_DISTANCE) as f32;
+    to_scale(distance / (UNRELATED_DISTANCE - DUPLICATE_DISTANCE) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phash::HashMatch;
+
+    fn nearest(distance: u32) -> DuplicateMatch {
+        DuplicateMatch { matched: HashMatch::Artwork(1), distance }
+    }
+
+    #[test]
+    fn visibility_ranges_from_0_to_scale() {
+        let full = BoundingBox { left: 0.0, top: 0.0, right: 1.0, bottom: 1.0 };
+        assert_eq!(visibility(&full, 1.0, 0.0), SCALE);
+        let empty = BoundingBox { left: 0.5, top: 0.5, right: 0.5, bottom: 0.5 };
+        assert_eq!(visibility(&empty, 0.0, THRESHOLD), 0);
+        // Out-of-range inputs are clamped.
+        let oversized = BoundingBox { left: -1.0, top: -1.0, right: 2.0, bottom: 2.0 };
+        assert_eq!(visibility(&oversized, 1.5, -1.0), SCALE);
+        assert_eq!(visibility(&empty, -1.0, 2.0), 0);
+    }
+
+    #[test]
+    fn visibility_weighs_area_confidence_and_match() {
+        let quarter = BoundingBox { left: 0.0, top: 0.0, right: 0.5, bottom: 0.5 };
+        assert_eq!(visibility(&quarter, 0.0, THRESHOLD), 3_000);
+        let empty = BoundingBox { left: 0.0, top: 0.0, right: 0.0, bottom: 0.0 };
+        assert_eq!(visibility(&empty, 1.0, THRESHOLD), 3_000);
+        assert_eq!(visibility(&empty, 0.0, THRESHOLD / 2.0), 2_000);
+    }
+
+    #[test]
+    fn originality_rises_from_the_duplicate_distance_to_unrelated() {
+        assert_eq!(originality(None), SCALE);
+        assert_eq!(originality(Some(&nearest(0))), 0);
+        assert_eq!(originality(Some(&nearest(DUPLICATE_DISTANCE))), 0);
+        assert_eq!(originality(Some(&nearest(21))), SCALE / 2);
+        assert_eq!(originality(Some(&nearest(UNRELATED_DISTANCE))), SCALE);
+        assert_eq!(originality(Some(&nearest(64))), SCALE);
+    }
+}
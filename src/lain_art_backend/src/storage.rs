@@ -1,16 +1,53 @@
 use bytes::Bytes;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{Memory, Ic0StableMemory, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, Memory, StableBTreeMap, StableCell};
 use ic_stable_structures::storable::{Bound, Storable};
 use std::cell::RefCell;
 use std::borrow::Cow;
 use candid::{CandidType, Decode, Encode};
 use serde::{Deserialize, Serialize};
 
+use crate::onnx::{self, Metric};
+
 // Separate memory IDs for each model to prevent overwrites
 const FACE_DETECTION_MEMORY_ID: MemoryId = MemoryId::new(0);
 const FACE_RECOGNITION_MEMORY_ID: MemoryId = MemoryId::new(1);
 const FACE_DATABASE_MEMORY_ID: MemoryId = MemoryId::new(2);
+const FACE_DETECTION_SIZE_MEMORY_ID: MemoryId = MemoryId::new(3);
+const FACE_RECOGNITION_SIZE_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const GALLERY_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const NFT_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const NFT_TX_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(7);
+pub const SUBMISSIONS_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const REWARD_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(9);
+pub const FEE_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(10);
+pub const GALLERY_PHASH_MEMORY_ID: MemoryId = MemoryId::new(11);
+pub const REFERENCE_FRAMES_MEMORY_ID: MemoryId = MemoryId::new(12);
+pub const REVIEWERS_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const REVIEW_IMAGES_MEMORY_ID: MemoryId = MemoryId::new(14);
+const LAIN_CLASSIFIER_MEMORY_ID: MemoryId = MemoryId::new(15);
+const LAIN_CLASSIFIER_SIZE_MEMORY_ID: MemoryId = MemoryId::new(16);
+pub const POLICY_MEMORY_ID: MemoryId = MemoryId::new(17);
+const ANIME_FACE_DETECTION_MEMORY_ID: MemoryId = MemoryId::new(18);
+const ANIME_FACE_DETECTION_SIZE_MEMORY_ID: MemoryId = MemoryId::new(19);
+pub const PREPROCESS_MEMORY_ID: MemoryId = MemoryId::new(20);
+const RECOGNITION_METRIC_MEMORY_ID: MemoryId = MemoryId::new(21);
+const FACE_REFERENCES_MEMORY_ID: MemoryId = MemoryId::new(22);
+const FACE_IMAGES_MEMORY_ID: MemoryId = MemoryId::new(23);
+pub const SCHEMA_MEMORY_ID: MemoryId = MemoryId::new(24);
+pub const UPGRADE_MEMORY_ID: MemoryId = MemoryId::new(25);
+pub const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(26);
+pub const PAUSE_MEMORY_ID: MemoryId = MemoryId::new(27);
+// File system of the WASI polyfill. Older versions gave it memory 0 through a
+// second memory manager, on top of the face detection model; no file was ever
+// written to it, since the models live in their own memories.
+pub const WASI_MEMORY_ID: MemoryId = MemoryId::new(28);
+const NEXT_REFERENCE_ID_MEMORY_ID: MemoryId = MemoryId::new(29);
+pub const NFT_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(30);
+pub const NEXT_REFERENCE_FRAME_ID_MEMORY_ID: MemoryId = MemoryId::new(31);
+
+pub type Mem = VirtualMemory<DefaultMemoryImpl>;
+type ModelSize = RefCell<StableCell<u64, Mem>>;
 
 // Wrapper type for String keys in BTreeMap
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -31,7 +68,7 @@ impl Storable for FaceLabel {
     };
 }
 
-// Face database entry: label -> embedding
+// Legacy face database entry: label -> embedding
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct StoredEmbedding {
     pub label: String,
@@ -50,22 +87,109 @@ impl Storable for StoredEmbedding {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+/// An enrolled face. An identity (label) can have several references.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FaceReference {
+    pub id: u64,
+    pub label: String,
+    pub embedding: Vec<f32>,
+    /// Whether the reference shows someone who is not Lain.
+    pub negative: Option<bool>,
+    /// Version of the recognition model that computed the embedding; `None`
+    /// for references enrolled before embeddings were versioned.
+    pub model_version: Option<String>,
+    /// Version of the embedding pipeline, see `onnx::EMBEDDING_PIPELINE`;
+    /// `None` for references from before it was recorded, which count as
+    /// embedded from the whole image.
+    pub pipeline: Option<u32>,
+}
+
+impl FaceReference {
+    pub fn is_negative(&self) -> bool {
+        self.negative.unwrap_or(false)
+    }
+
+    /// Whether the embedding was computed from the detected face by the given model.
+    pub fn is_current(&self, model_version: &Option<String>) -> bool {
+        self.pipeline == Some(onnx::EMBEDDING_PIPELINE) && &self.model_version == model_version
+    }
+}
+
+impl Storable for FaceReference {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<Ic0StableMemory>> =
-        RefCell::new(MemoryManager::init(Ic0StableMemory::default()));
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     
-    // Track actual data size per model
-    static FACE_DETECTION_SIZE: RefCell<u64> = RefCell::new(0);
-    static FACE_RECOGNITION_SIZE: RefCell<u64> = RefCell::new(0);
+    // Track actual data size per model (stable, so models can be reloaded after an upgrade)
+    static FACE_DETECTION_SIZE: ModelSize = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(FACE_DETECTION_SIZE_MEMORY_ID)), 0)
+            .expect("Failed to init face detection size")
+    );
+    static FACE_RECOGNITION_SIZE: ModelSize = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(FACE_RECOGNITION_SIZE_MEMORY_ID)), 0)
+            .expect("Failed to init face recognition size")
+    );
+    static LAIN_CLASSIFIER_SIZE: ModelSize = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(LAIN_CLASSIFIER_SIZE_MEMORY_ID)), 0)
+            .expect("Failed to init Lain classifier size")
+    );
+    static ANIME_FACE_DETECTION_SIZE: ModelSize = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(ANIME_FACE_DETECTION_SIZE_MEMORY_ID)), 0)
+            .expect("Failed to init anime face detection size")
+    );
     
-    // Stable face database: label -> embedding
-    static FACE_DATABASE: RefCell<StableBTreeMap<FaceLabel, StoredEmbedding, VirtualMemory<Ic0StableMemory>>> = RefCell::new(
+    // Metric the face embeddings are compared with
+    static RECOGNITION_METRIC: RefCell<StableCell<Metric, Mem>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(RECOGNITION_METRIC_MEMORY_ID)), Metric::default())
+            .expect("Failed to init recognition metric")
+    );
+
+    // Stable face database: reference id -> reference
+    static FACE_REFERENCES: RefCell<StableBTreeMap<u64, FaceReference, Mem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FACE_REFERENCES_MEMORY_ID))
+        )
+    );
+
+    // Next face reference id. It only grows, so the ids of removed references
+    // are never given out again.
+    static NEXT_REFERENCE_ID: RefCell<StableCell<u64, Mem>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(NEXT_REFERENCE_ID_MEMORY_ID)), 0)
+            .expect("Failed to init next reference id")
+    );
+
+    // Images the references were enrolled with: reference id -> image
+    static FACE_IMAGES: RefCell<StableBTreeMap<u64, Vec<u8>, Mem>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FACE_IMAGES_MEMORY_ID))
+        )
+    );
+
+    // Legacy face database: label -> embedding, moved to FACE_REFERENCES on upgrade
+    static FACE_DATABASE: RefCell<StableBTreeMap<FaceLabel, StoredEmbedding, Mem>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(FACE_DATABASE_MEMORY_ID))
         )
     );
 }
 
+/// Returns the virtual memory with the given ID, for modules that keep their
+/// own stable structures.
+pub fn memory(id: MemoryId) -> Mem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
 // Face Detection Model Storage
 pub fn face_detection_bytes() -> Bytes {
     read_model(FACE_DETECTION_MEMORY_ID, &FACE_DETECTION_SIZE, "face_detection")
@@ -92,15 +216,61 @@ pub fn clear_face_recognition_bytes() {
     clear_model(&FACE_RECOGNITION_SIZE, "face_recognition");
 }
 
+// Lain Classifier Model Storage (optional)
+pub fn lain_classifier_bytes() -> Bytes {
+    read_model(LAIN_CLASSIFIER_MEMORY_ID, &LAIN_CLASSIFIER_SIZE, "lain_classifier")
+}
+
+pub fn append_lain_classifier_bytes(bytes: Vec<u8>) {
+    append_model(LAIN_CLASSIFIER_MEMORY_ID, &LAIN_CLASSIFIER_SIZE, bytes, "lain_classifier");
+}
+
+pub fn clear_lain_classifier_bytes() {
+    clear_model(&LAIN_CLASSIFIER_SIZE, "lain_classifier");
+}
+
+// Anime Face Detection Model Storage (optional)
+pub fn anime_face_detection_bytes() -> Bytes {
+    read_model(ANIME_FACE_DETECTION_MEMORY_ID, &ANIME_FACE_DETECTION_SIZE, "anime_face_detection")
+}
+
+pub fn append_anime_face_detection_bytes(bytes: Vec<u8>) {
+    append_model(ANIME_FACE_DETECTION_MEMORY_ID, &ANIME_FACE_DETECTION_SIZE, bytes, "anime_face_detection");
+}
+
+pub fn clear_anime_face_detection_bytes() {
+    clear_model(&ANIME_FACE_DETECTION_SIZE, "anime_face_detection");
+}
+
+// Recognition Metric
+pub fn recognition_metric() -> Metric {
+    RECOGNITION_METRIC.with(|metric| *metric.borrow().get())
+}
+
+pub fn set_recognition_metric(metric: Metric) {
+    RECOGNITION_METRIC.with(|cell| {
+        cell.borrow_mut()
+            .set(metric)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store recognition metric"));
+    });
+}
+
 // Helper Functions
+/// Whether no model has been uploaded.
+pub fn models_empty() -> bool {
+    [&FACE_DETECTION_SIZE, &FACE_RECOGNITION_SIZE, &LAIN_CLASSIFIER_SIZE, &ANIME_FACE_DETECTION_SIZE]
+        .iter()
+        .all(|size_cell| size_cell.with(|size| *size.borrow().get()) == 0)
+}
+
 fn read_model(
     memory_id: MemoryId,
-    size_cell: &'static std::thread::LocalKey<RefCell<u64>>,
+    size_cell: &'static std::thread::LocalKey<ModelSize>,
     label: &str
 ) -> Bytes {
     MEMORY_MANAGER.with(|manager| {
         let memory = manager.borrow().get(memory_id);
-        let data_size = size_cell.with(|size| *size.borrow());
+        let data_size = size_cell.with(|size| *size.borrow().get());
         
         #[cfg(debug_assertions)]
         ic_cdk::println!("[Debug] Reading {} model - Size: {} bytes", label, data_size);
@@ -119,7 +289,7 @@ fn read_model(
 
 fn append_model(
     memory_id: MemoryId,
-    size_cell: &'static std::thread::LocalKey<RefCell<u64>>,
+    size_cell: &'static std::thread::LocalKey<ModelSize>,
     bytes: Vec<u8>,
     label: &str
 ) {
@@ -128,7 +298,7 @@ fn append_model(
     
     MEMORY_MANAGER.with(|manager| {
         let memory = manager.borrow().get(memory_id);
-        let current_size = size_cell.with(|size| *size.borrow());
+        let current_size = size_cell.with(|size| *size.borrow().get());
         
         let new_size = current_size + bytes.len() as u64;
         let required_pages = (new_size + 65535) / 65536;
@@ -147,7 +317,11 @@ fn append_model(
         }
         
         memory.write(current_size, &bytes);
-        size_cell.with(|size| *size.borrow_mut() = new_size);
+        size_cell.with(|size| {
+            size.borrow_mut()
+                .set(new_size)
+                .unwrap_or_else(|_| ic_cdk::trap(&format!("Failed to record size of {}", label)))
+        });
         
         #[cfg(debug_assertions)]
         ic_cdk::println!("[Debug] {}: New total size: {} bytes ({} pages)", 
@@ -155,17 +329,21 @@ fn append_model(
     });
 }
 
-fn clear_model(size_cell: &'static std::thread::LocalKey<RefCell<u64>>, label: &str) {
+fn clear_model(size_cell: &'static std::thread::LocalKey<ModelSize>, label: &str) {
     #[cfg(debug_assertions)]
     ic_cdk::println!("[Debug] Clearing {} model", label);
     
-    size_cell.with(|size| *size.borrow_mut() = 0);
+    size_cell.with(|size| {
+        size.borrow_mut()
+            .set(0)
+            .unwrap_or_else(|_| ic_cdk::trap(&format!("Failed to clear size of {}", label)))
+    });
 }
 
 // Memory Statistics
 pub fn get_stable_memory_stats() -> (u64, u64) {
-    let detection_size = FACE_DETECTION_SIZE.with(|s| *s.borrow());
-    let recognition_size = FACE_RECOGNITION_SIZE.with(|s| *s.borrow());
+    let detection_size = FACE_DETECTION_SIZE.with(|s| *s.borrow().get());
+    let recognition_size = FACE_RECOGNITION_SIZE.with(|s| *s.borrow().get());
     (detection_size, recognition_size)
 }
 
@@ -200,41 +378,139 @@ pub fn get_stable_memory_size(_file: &str) -> usize {
 }
 
 // Face Database Management
-pub fn add_face_to_database(label: String, embedding: Vec<f32>) {
-    FACE_DATABASE.with(|db| {
-        db.borrow_mut().insert(
-            FaceLabel(label.clone()),
-            StoredEmbedding {
-                label,
-                embedding,
-            }
-        );
+pub fn add_face_to_database(
+    label: String,
+    embedding: Vec<f32>,
+    negative: bool,
+    model_version: Option<String>,
+    pipeline: Option<u32>,
+    image: Option<Vec<u8>>,
+) -> u64 {
+    let id = FACE_REFERENCES.with(|db| {
+        let mut db = db.borrow_mut();
+        // References enrolled before the counter existed can be above it.
+        let next = NEXT_REFERENCE_ID.with(|next| *next.borrow().get());
+        let id = next.max(db.last_key_value().map_or(0, |(id, _)| id + 1));
+        db.insert(id, FaceReference { id, label, embedding, negative: Some(negative), model_version, pipeline });
+        id
+    });
+    reserve_reference_id(id);
+    if let Some(image) = image {
+        FACE_IMAGES.with(|images| images.borrow_mut().insert(id, image));
+    }
+    id
+}
+
+/// Replaces the embedding of a reference, e.g. after the recognition model
+/// changed. The new embedding comes from the current pipeline.
+pub fn update_face_embedding(id: u64, embedding: Vec<f32>, model_version: Option<String>) {
+    FACE_REFERENCES.with(|db| {
+        let mut db = db.borrow_mut();
+        if let Some(mut reference) = db.get(&id) {
+            reference.embedding = embedding;
+            reference.model_version = model_version;
+            reference.pipeline = Some(onnx::EMBEDDING_PIPELINE);
+            db.insert(id, reference);
+        }
     });
 }
 
-pub fn get_all_faces() -> Vec<(String, Vec<f32>)> {
-    FACE_DATABASE.with(|db| {
-        db.borrow()
-            .iter()
-            .map(|(label, stored)| (label.0, stored.embedding))
-            .collect()
-    })
+/// Returns the image a reference was enrolled with. References enrolled
+/// before images were kept have none.
+pub fn face_image(id: u64) -> Option<Vec<u8>> {
+    FACE_IMAGES.with(|images| images.borrow().get(&id))
+}
+
+pub fn get_all_faces() -> Vec<FaceReference> {
+    FACE_REFERENCES.with(|db| db.borrow().iter().map(|(_, reference)| reference).collect())
 }
 
-pub fn get_face_by_label(label: &str) -> Option<Vec<f32>> {
-    FACE_DATABASE.with(|db| {
-        db.borrow()
-            .get(&FaceLabel(label.to_string()))
-            .map(|stored| stored.embedding)
-    })
+pub fn get_face_reference(id: u64) -> Option<FaceReference> {
+    FACE_REFERENCES.with(|db| db.borrow().get(&id))
 }
 
+/// Removes every reference of the given identity.
 pub fn remove_face_from_database(label: &str) {
-    FACE_DATABASE.with(|db| {
-        db.borrow_mut().remove(&FaceLabel(label.to_string()));
+    for reference in get_all_faces().into_iter().filter(|reference| reference.label == label) {
+        remove_reference(reference.id);
+    }
+}
+
+pub fn remove_reference(id: u64) -> Option<FaceReference> {
+    FACE_IMAGES.with(|images| images.borrow_mut().remove(&id));
+    FACE_REFERENCES.with(|db| db.borrow_mut().remove(&id))
+}
+
+/// Returns up to `limit` references, starting at the ID `start`.
+pub fn faces_from(start: u64, limit: usize) -> Vec<FaceReference> {
+    FACE_REFERENCES.with(|db| db.borrow().range(start..).take(limit).map(|(_, reference)| reference).collect())
+}
+
+/// Stores a reference under its own ID, replacing any reference with that ID.
+pub fn insert_face_reference(reference: FaceReference) {
+    reserve_reference_id(reference.id);
+    FACE_IMAGES.with(|images| images.borrow_mut().remove(&reference.id));
+    FACE_REFERENCES.with(|db| db.borrow_mut().insert(reference.id, reference));
+}
+
+/// Moves the reference ID counter past the given ID.
+fn reserve_reference_id(id: u64) {
+    NEXT_REFERENCE_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        if *next.get() <= id {
+            next.set(id + 1)
+                .unwrap_or_else(|_| ic_cdk::trap("Failed to store next reference id"));
+        }
     });
 }
 
+/// Removes every reference and its image.
+pub fn clear_face_database() {
+    for reference in get_all_faces() {
+        remove_reference(reference.id);
+    }
+}
+
 pub fn get_face_database_size() -> u64 {
-    FACE_DATABASE.with(|db| db.borrow().len())
+    FACE_REFERENCES.with(|db| db.borrow().len())
+}
+
+/// Records the sign of references enrolled before negative identities existed.
+pub fn migrate_reference_signs() {
+    for mut reference in get_all_faces().into_iter().filter(|reference| reference.negative.is_none()) {
+        reference.negative = Some(false);
+        FACE_REFERENCES.with(|db| db.borrow_mut().insert(reference.id, reference));
+    }
+}
+
+/// L2-normalizes the embeddings of references enrolled before embeddings were
+/// normalized when computed, so that `Metric::Dot` can rely on unit vectors.
+pub fn migrate_normalize_embeddings() {
+    for mut reference in get_all_faces() {
+        if onnx::normalize_if_needed(&mut reference.embedding) {
+            FACE_REFERENCES.with(|db| db.borrow_mut().insert(reference.id, reference));
+        }
+    }
+}
+
+/// Flags the references enrolled before the embedding pipeline was recorded as
+/// embedded from the whole image. Most are, and any that are not are corrected
+/// by `reembed_all` since they keep their image; references without an image
+/// have to be enrolled again.
+pub fn migrate_flag_whole_image_references() {
+    for mut reference in get_all_faces().into_iter().filter(|reference| reference.pipeline.is_none()) {
+        reference.pipeline = Some(onnx::WHOLE_IMAGE_PIPELINE);
+        FACE_REFERENCES.with(|db| db.borrow_mut().insert(reference.id, reference));
+    }
+}
+
+/// Moves the faces of the label-keyed database of earlier versions to the
+/// reference database, one reference per label.
+pub fn migrate_legacy_faces() {
+    let legacy: Vec<StoredEmbedding> =
+        FACE_DATABASE.with(|db| db.borrow().iter().map(|(_, stored)| stored).collect());
+    for stored in legacy {
+        add_face_to_database(stored.label.clone(), stored.embedding, false, None, Some(onnx::WHOLE_IMAGE_PIPELINE), None);
+        FACE_DATABASE.with(|db| db.borrow_mut().remove(&FaceLabel(stored.label)));
+    }
 }
\ No newline at end of file
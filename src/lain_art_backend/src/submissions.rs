@@ -0,0 +1,238 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableBTreeMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::appeals::Appeal;
+use crate::phash::DuplicateMatch;
+use crate::pipeline::VerificationStep;
+use crate::review::Review;
+use crate::storage::{self, Mem, SUBMISSIONS_MEMORY_ID};
+use crate::transactions::{Account, TransferArg};
+
+/// The lifecycle of a submission.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Status {
+    PendingUpload,
+    AwaitingFee,
+    Verifying,
+    /// Queued with `trigger_verification`; the step that runs next.
+    Processing { step: VerificationStep },
+    /// Borderline result waiting for a reviewer, see `review`.
+    UnderReview { reason: String },
+    Verified { originality: u16, visibility: u16 },
+    Rejected { reason: String },
+    Rewarded,
+}
+
+/// Metadata of the uploaded file, as declared by `finalize_asset`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArtMeta {
+    pub mime: String,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+    pub chunks: u64,
+}
+
+/// The outcome of a reward payout.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RewardResult {
+    pub token_id: Nat,
+    /// Block index of the ICRC-1 reward transfer.
+    pub reward_txid: String,
+}
+
+/// Why a submission was rejected, used to pick the refund policy.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RejectionKind {
+    /// The image could not be read.
+    InvalidImage,
+    /// No face was detected.
+    NoFace,
+    /// A face was detected but it is not Lain.
+    NotLain,
+    /// The image is a near-duplicate of approved artwork or of a reference frame.
+    Duplicate,
+}
+
+/// The verified fee payment of a submission.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeePayment {
+    pub payer: Account,
+    pub amount: Nat,
+    pub paid_at: u64,
+}
+
+/// A refund of the fee of a rejected submission.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Refund {
+    pub amount: Nat,
+    /// Block index of the ICRC-1 refund transfer.
+    pub block_index: String,
+}
+
+/// The transfer of a settled fee from the submission's fee subaccount to the
+/// fee treasury.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeeSweep {
+    pub amount: Nat,
+    /// Block index of the ICRC-1 transfer, `None` if the balance did not cover
+    /// the ledger fee.
+    pub block_index: Option<String>,
+    pub swept_at: u64,
+}
+
+/// A reward transfer as it was first sent to the ledger. Retries replay it
+/// unchanged, so that the ledger deduplicates them even if the reward
+/// configuration changed in between.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RewardTransfer {
+    pub ledger: Principal,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub memo: Vec<u8>,
+    pub created_at_time: u64,
+}
+
+impl RewardTransfer {
+    pub fn arg(&self) -> TransferArg {
+        TransferArg {
+            from_subaccount: self.from_subaccount.clone(),
+            to: self.to.clone(),
+            amount: self.amount.clone(),
+            fee: None,
+            memo: Some(self.memo.clone()),
+            created_at_time: Some(self.created_at_time),
+        }
+    }
+}
+
+/// The persistent record of a submission. The uploaded chunks themselves are
+/// only kept in heap memory until verification.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Submission {
+    pub id: u64,
+    pub creator: Principal,
+    pub status: Status,
+    pub art: Option<ArtMeta>,
+    pub fee_paid: bool,
+    pub artwork_id: Option<u64>,
+    pub nft_token_id: Option<u64>,
+    pub reward: Option<RewardResult>,
+    /// `created_at_time` of the reward transfer of older versions, which did not
+    /// record `reward_transfer`.
+    pub reward_created_at: Option<u64>,
+    /// The pending reward transfer, see `RewardTransfer`.
+    pub reward_transfer: Option<RewardTransfer>,
+    pub fee: Option<FeePayment>,
+    pub rejection: Option<RejectionKind>,
+    /// When the submission was last rejected; the appeal window starts then.
+    pub rejected_at: Option<u64>,
+    pub refund: Option<Refund>,
+    /// `created_at_time` of the refund transfer, reused on retries.
+    pub refund_created_at: Option<u64>,
+    /// The transfer of the kept fee to the fee treasury.
+    pub fee_sweep: Option<FeeSweep>,
+    /// Perceptual hash of the image, computed during verification.
+    pub phash: Option<u64>,
+    /// The near-duplicate match that caused a `Duplicate` rejection.
+    pub duplicate_of: Option<DuplicateMatch>,
+    /// Human review, if the submission was queued for review or decided by hand.
+    pub review: Option<Review>,
+    /// The creator's appeal against a rejection, see `appeals`.
+    pub appeal: Option<Appeal>,
+    /// Why the last verification could not run, e.g. a model that is not
+    /// loaded. Cleared when verification starts again.
+    pub verification_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Submission {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Stable submission records: submission id -> submission
+    static SUBMISSIONS: RefCell<StableBTreeMap<u64, Submission, Mem>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(SUBMISSIONS_MEMORY_ID))
+    );
+}
+
+/// Returns the ID the next submission will get.
+pub fn next_id() -> u64 {
+    SUBMISSIONS.with(|subs| {
+        subs.borrow()
+            .last_key_value()
+            .map_or(1, |(id, _)| id + 1)
+    })
+}
+
+pub fn create(id: u64, creator: Principal) {
+    let now = ic_cdk::api::time();
+    SUBMISSIONS.with(|subs| {
+        subs.borrow_mut().insert(id, Submission {
+            id,
+            creator,
+            status: Status::PendingUpload,
+            art: None,
+            fee_paid: false,
+            artwork_id: None,
+            nft_token_id: None,
+            reward: None,
+            reward_created_at: None,
+            reward_transfer: None,
+            fee: None,
+            rejection: None,
+            rejected_at: None,
+            refund: None,
+            refund_created_at: None,
+            fee_sweep: None,
+            phash: None,
+            duplicate_of: None,
+            review: None,
+            appeal: None,
+            verification_error: None,
+            created_at: now,
+            updated_at: now,
+        });
+    });
+}
+
+pub fn get(id: u64) -> Option<Submission> {
+    SUBMISSIONS.with(|subs| subs.borrow().get(&id))
+}
+
+/// Returns the submissions matching `predicate`, in ID order.
+pub fn filter(predicate: impl Fn(&Submission) -> bool) -> Vec<Submission> {
+    SUBMISSIONS.with(|subs| {
+        subs.borrow()
+            .iter()
+            .map(|(_, sub)| sub)
+            .filter(|sub| predicate(sub))
+            .collect()
+    })
+}
+
+/// Applies `f` to the submission with the given ID and persists the result.
+/// Returns `None` if the submission does not exist.
+pub fn update<R>(id: u64, f: impl FnOnce(&mut Submission) -> R) -> Option<R> {
+    SUBMISSIONS.with(|subs| {
+        let mut subs = subs.borrow_mut();
+        let mut sub = subs.get(&id)?;
+        let result = f(&mut sub);
+        sub.updated_at = ic_cdk::api::time();
+        subs.insert(id, sub);
+        Some(result)
+    })
+}
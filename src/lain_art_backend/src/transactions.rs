@@ -1,4 +1,7 @@
-use candid::{CandidType, Deserialize, Nat};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use num_traits::ToPrimitive;
+
+use crate::nft::Value;
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct FeeInvoice {
@@ -7,11 +10,381 @@ pub struct FeeInvoice {
     pub memo: Option<Nat>,
 }
 
-// Dummy implementation: always returns 0.1 ICP, no subaccount, no memo
-pub fn get_fee_invoice(_submission_id: Nat) -> FeeInvoice {
-    FeeInvoice {
-        amount: Nat::from(100_000_000u64), // 0.1 ICP in e8s
-        subaccount: None,
-        memo: None,
+/// An ICRC-1 account.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn new(owner: Principal, subaccount: Option<Vec<u8>>) -> Self {
+        Self { owner, subaccount }
+    }
+
+    /// Fails unless the subaccount, if any, has exactly 32 bytes.
+    pub fn validate(&self) -> Result<(), String> {
+        self.effective_subaccount()
+            .map(|_| ())
+            .ok_or_else(|| "Subaccounts must have 32 bytes".to_string())
+    }
+
+    /// The subaccount with the all-zero default made explicit, so that accounts
+    /// can be compared regardless of how the default subaccount was written.
+    /// `None` if the subaccount does not have exactly 32 bytes.
+    pub fn effective_subaccount(&self) -> Option<[u8; 32]> {
+        match &self.subaccount {
+            Some(bytes) => bytes.as_slice().try_into().ok(),
+            None => Some([0u8; 32]),
+        }
+    }
+}
+
+impl PartialEq for Account {
+    /// Invalid subaccounts are never padded or truncated, so they only equal
+    /// the exact same bytes.
+    fn eq(&self, other: &Self) -> bool {
+        if self.owner != other.owner {
+            return false;
+        }
+        match (self.effective_subaccount(), other.effective_subaccount()) {
+            (Some(subaccount), Some(other_subaccount)) => subaccount == other_subaccount,
+            _ => self.subaccount == other.subaccount,
+        }
+    }
+}
+
+impl Eq for Account {}
+
+/// Argument of the ICRC-1 `icrc1_transfer` method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+/// Errors of the ICRC-1 `icrc1_transfer` method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Why an ICRC-1 transfer did not go through.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferFailure {
+    /// The `created_at_time` is outside the ledger's deduplication window, so
+    /// the ledger can no longer tell whether the transfer was already executed.
+    TooOld,
+    /// The ledger refused the transfer, so it was not executed.
+    Refused(String),
+    /// The ledger could not be called.
+    CallFailed(String),
+}
+
+impl From<TransferFailure> for String {
+    fn from(failure: TransferFailure) -> Self {
+        match failure {
+            TransferFailure::TooOld => format!("Ledger rejected the transfer: {:?}", TransferError::TooOld),
+            TransferFailure::Refused(message) | TransferFailure::CallFailed(message) => message,
+        }
+    }
+}
+
+/// An entry of `icrc1_supported_standards`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+/// Whether a ledger implements ICRC-3, which `find_transfer` needs. The ICP
+/// ledger, for one, does not.
+pub async fn supports_icrc3(ledger: Principal) -> Result<bool, String> {
+    let result: Result<(Vec<SupportedStandard>,), _> = ic_cdk::call(ledger, "icrc1_supported_standards", ()).await;
+    result
+        .map(|(standards,)| standards.iter().any(|standard| standard.name == "ICRC-3"))
+        .map_err(|(code, message)| format!("Failed to call the ledger: {:?} {}", code, message))
+}
+
+/// Fails unless rewards or refunds can be paid on the ledger: transfers that
+/// expired need `find_transfer` before they are sent again.
+pub async fn check_ledger(ledger: Principal) -> Result<(), String> {
+    if supports_icrc3(ledger).await? {
+        Ok(())
+    } else {
+        Err(format!("The ledger {} does not implement ICRC-3", ledger))
+    }
+}
+
+/// Transfers tokens on an ICRC-1 ledger and returns the block index.
+///
+/// A `Duplicate` error means the very same transfer was already executed, so it
+/// is reported as a success with the original block index.
+pub async fn icrc1_transfer(ledger: Principal, arg: TransferArg) -> Result<Nat, TransferFailure> {
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(ledger, "icrc1_transfer", (arg,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Ok(duplicate_of),
+        Ok((Err(TransferError::TooOld),)) => Err(TransferFailure::TooOld),
+        Ok((Err(err),)) => Err(TransferFailure::Refused(format!("Ledger rejected the transfer: {:?}", err))),
+        Err((code, message)) => Err(TransferFailure::CallFailed(format!(
+            "Failed to call the ledger: {:?} {}",
+            code, message
+        ))),
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: ArchiveCallback,
+}
+
+/// Result of the ICRC-3 `icrc3_get_blocks` method.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+candid::define_function!(pub ArchiveCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+// Blocks fetched per `icrc3_get_blocks` call and in total while searching for a transfer
+const BLOCKS_PER_PAGE: u64 = 1_000;
+const MAX_SEARCHED_BLOCKS: u64 = 200_000;
+// How far a block's timestamp may precede the `created_at_time` of its transaction, in nanoseconds
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+async fn icrc3_get_blocks(ledger: Principal, args: Vec<GetBlocksArgs>) -> Result<GetBlocksResult, String> {
+    let result: Result<(GetBlocksResult,), _> = ic_cdk::call(ledger, "icrc3_get_blocks", (args,)).await;
+    result
+        .map(|(blocks,)| blocks)
+        .map_err(|(code, message)| format!("Failed to get blocks from the ledger: {:?} {}", code, message))
+}
+
+/// Returns the blocks in `start..start + length`, including archived ones.
+async fn blocks_in_range(ledger: Principal, start: u64, length: u64) -> Result<Vec<BlockWithId>, String> {
+    let args = vec![GetBlocksArgs { start: Nat::from(start), length: Nat::from(length) }];
+    let result = icrc3_get_blocks(ledger, args).await?;
+    let mut blocks = result.blocks;
+    for archived in result.archived_blocks {
+        let archive: Result<(GetBlocksResult,), _> =
+            ic_cdk::call(archived.callback.0.principal, &archived.callback.0.method, (archived.args,)).await;
+        let (archive,) = archive
+            .map_err(|(code, message)| format!("Failed to get blocks from the archive: {:?} {}", code, message))?;
+        blocks.extend(archive.blocks);
+    }
+    Ok(blocks)
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Map(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+        _ => None,
+    }
+}
+
+fn nat_field(value: &Value, key: &str) -> Option<u64> {
+    match field(value, key) {
+        Some(Value::Nat(nat)) => nat.0.to_u64(),
+        _ => None,
+    }
+}
+
+/// Decodes an ICRC-3 account: the owner, followed by the subaccount if it has one.
+fn account_field(value: &Value, key: &str) -> Option<Account> {
+    let Some(Value::Array(parts)) = field(value, key) else {
+        return None;
+    };
+    match parts.as_slice() {
+        [Value::Blob(owner)] => Some(Account::new(Principal::try_from_slice(owner).ok()?, None)),
+        [Value::Blob(owner), Value::Blob(subaccount)] => {
+            Some(Account::new(Principal::try_from_slice(owner).ok()?, Some(subaccount.clone())))
+        }
+        _ => None,
+    }
+}
+
+/// Whether an ICRC-3 block holds the transaction of the transfer from `from`.
+fn is_transfer(block: &Value, from: &Account, arg: &TransferArg) -> bool {
+    let Some(tx) = field(block, "tx") else {
+        return false;
+    };
+    let memo = match field(tx, "memo") {
+        Some(Value::Blob(memo)) => Some(memo),
+        _ => None,
+    };
+    let amount = match field(tx, "amt") {
+        Some(Value::Nat(amount)) => Some(amount),
+        _ => None,
+    };
+    memo == arg.memo.as_ref()
+        && amount == Some(&arg.amount)
+        && nat_field(tx, "ts") == arg.created_at_time
+        && account_field(tx, "from").as_ref() == Some(from)
+        && account_field(tx, "to").as_ref() == Some(&arg.to)
+}
+
+/// Searches the ledger's ICRC-3 block log for a transfer of this canister with
+/// the accounts, memo, amount and `created_at_time` of `arg` and returns its
+/// block index.
+///
+/// Meant for transfers that failed with `TooOld`: the ledger no longer
+/// deduplicates them, so an earlier attempt has to be looked up before the
+/// transfer is sent again. The log is searched backwards from its end until the
+/// blocks are older than the transfer.
+pub async fn find_transfer(ledger: Principal, arg: &TransferArg) -> Result<Option<Nat>, String> {
+    let created_at_time = arg.created_at_time.ok_or("The transfer has no created_at_time")?;
+    let from = Account::new(ic_cdk::id(), arg.from_subaccount.clone());
+    let log_length = icrc3_get_blocks(ledger, vec![])
+        .await?
+        .log_length
+        .0
+        .to_u64()
+        .ok_or("Invalid ledger log length")?;
+
+    let mut end = log_length;
+    while end > 0 {
+        if log_length - end >= MAX_SEARCHED_BLOCKS {
+            return Err(format!("The transfer was not found in the last {} blocks", MAX_SEARCHED_BLOCKS));
+        }
+        let start = end.saturating_sub(BLOCKS_PER_PAGE);
+        let blocks = blocks_in_range(ledger, start, end - start).await?;
+        if let Some(found) = blocks.iter().find(|found| is_transfer(&found.block, &from, arg)) {
+            return Ok(Some(found.id.clone()));
+        }
+        let oldest = blocks.iter().filter_map(|found| nat_field(&found.block, "ts")).min();
+        if oldest.is_some_and(|ts| ts.saturating_add(PERMITTED_DRIFT) < created_at_time) {
+            break;
+        }
+        end = start;
+    }
+    Ok(None)
+}
+
+/// Returns the ICRC-1 balance of an account.
+pub async fn icrc1_balance_of(ledger: Principal, account: Account) -> Result<Nat, String> {
+    let result: Result<(Nat,), _> = ic_cdk::call(ledger, "icrc1_balance_of", (account,)).await;
+    result
+        .map(|(balance,)| balance)
+        .map_err(|(code, message)| format!("Failed to call the ledger: {:?} {}", code, message))
+}
+
+/// Returns the transfer fee of an ICRC-1 ledger.
+pub async fn icrc1_fee(ledger: Principal) -> Result<Nat, String> {
+    let result: Result<(Nat,), _> = ic_cdk::call(ledger, "icrc1_fee", ()).await;
+    result
+        .map(|(fee,)| fee)
+        .map_err(|(code, message)| format!("Failed to call the ledger: {:?} {}", code, message))
+}
+
+/// Returns the subaccount of this canister that holds the fee of a submission.
+pub fn submission_subaccount(submission_id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0u8; 32];
+    subaccount[..3].copy_from_slice(b"fee");
+    subaccount[24..].copy_from_slice(&submission_id.to_be_bytes());
+    subaccount
+}
+
+/// Returns the memo identifying a submission in ledger transfers.
+pub fn submission_memo(submission_id: u64) -> Vec<u8> {
+    submission_id.to_be_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(account: &Account) -> Value {
+        let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+        parts.extend(account.subaccount.clone().map(Value::Blob));
+        Value::Array(parts)
+    }
+
+    fn transfer_block(from: &Account, to: &Account, memo: &[u8], amount: u64, created_at_time: u64) -> Value {
+        Value::Map(vec![
+            ("ts".to_string(), Value::Nat(Nat::from(created_at_time + 1))),
+            (
+                "tx".to_string(),
+                Value::Map(vec![
+                    ("op".to_string(), Value::Text("xfer".to_string())),
+                    ("from".to_string(), account(from)),
+                    ("to".to_string(), account(to)),
+                    ("amt".to_string(), Value::Nat(Nat::from(amount))),
+                    ("memo".to_string(), Value::Blob(memo.to_vec())),
+                    ("ts".to_string(), Value::Nat(Nat::from(created_at_time))),
+                ]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn transfers_are_matched_by_accounts_memo_amount_and_time() {
+        let canister = Principal::from_slice(&[1; 29]);
+        let from = Account::new(canister, Some(submission_subaccount(7)));
+        let to = Account::new(Principal::from_slice(&[2; 29]), None);
+        let arg = TransferArg {
+            from_subaccount: from.subaccount.clone(),
+            to: to.clone(),
+            amount: Nat::from(100u64),
+            fee: None,
+            memo: Some(submission_memo(7)),
+            created_at_time: Some(42),
+        };
+        let memo = submission_memo(7);
+        assert!(is_transfer(&transfer_block(&from, &to, &memo, 100, 42), &from, &arg));
+        // The default subaccount may be written out.
+        let explicit_to = Account::new(to.owner, Some(vec![0; 32]));
+        assert!(is_transfer(&transfer_block(&from, &explicit_to, &memo, 100, 42), &from, &arg));
+
+        assert!(!is_transfer(&transfer_block(&from, &to, &submission_memo(8), 100, 42), &from, &arg));
+        assert!(!is_transfer(&transfer_block(&from, &to, &memo, 99, 42), &from, &arg));
+        assert!(!is_transfer(&transfer_block(&from, &to, &memo, 100, 43), &from, &arg));
+        let other_from = Account::new(canister, Some(submission_subaccount(8)));
+        assert!(!is_transfer(&transfer_block(&other_from, &to, &memo, 100, 42), &from, &arg));
+        let other_to = Account::new(Principal::from_slice(&[3; 29]), None);
+        assert!(!is_transfer(&transfer_block(&from, &other_to, &memo, 100, 42), &from, &arg));
+    }
+
+    #[test]
+    fn subaccounts_must_have_32_bytes() {
+        let owner = Principal::anonymous();
+        let default = Account::new(owner, None);
+        assert_eq!(default, Account::new(owner, Some(vec![0; 32])));
+        assert!(default.validate().is_ok());
+
+        let short = Account::new(owner, Some(vec![0; 31]));
+        let long = Account::new(owner, Some(vec![0; 33]));
+        assert!(short.validate().is_err());
+        assert!(long.validate().is_err());
+        assert_eq!(short.effective_subaccount(), None);
+        assert_ne!(short, default);
+        assert_ne!(long, default);
+        assert_eq!(short, short.clone());
     }
 }
@@ -0,0 +1,223 @@
+//! Upgrade safety.
+//!
+//! Before an upgrade, a controller calls `prepare_upgrade` until it reports
+//! `ready`: it pauses new submissions and uploads, lets the verification queue
+//! drain, flushes the upload buffer to stable memory and lists what would
+//! still be lost, such as background jobs that have not finished. Calling it
+//! followed by `run_self_test` is a dry run of an upgrade; `cancel_upgrade`
+//! resumes submissions. The pause lives on the heap, so an upgrade lifts it.
+//!
+//! After every upgrade a timer loads the models and runs recognition on the
+//! canary image, a face that a controller stored together with the label it
+//! must be recognized as. The outcome is kept in stable memory and returned
+//! by `get_self_test`.
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::StableCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+use crate::auth::is_admin;
+use crate::storage::{self, Mem, UPGRADE_MEMORY_ID};
+use crate::{enrollment, onnx, pipeline, reembed, schema};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct UpgradeReadiness {
+    pub submissions_paused: bool,
+    pub queued_verifications: u64,
+    /// Uploads in progress, written to stable memory and restored after the upgrade.
+    pub flushed_uploads: u64,
+    /// What would be lost or interrupted by upgrading now.
+    pub blockers: Vec<String>,
+    pub ready: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct SelfTest {
+    pub ran_at: u64,
+    pub schema_version: u32,
+    pub passed: bool,
+    /// What the canary image was recognized as, if there is a canary.
+    pub label: Option<String>,
+    pub score: Option<f32>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct UpgradeState {
+    canary_image: Option<Vec<u8>>,
+    canary_label: Option<String>,
+    self_test: Option<SelfTest>,
+}
+
+impl Storable for UpgradeState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Whether submissions are paused for an upgrade
+    static PREPARING: Cell<bool> = const { Cell::new(false) };
+    static STATE: RefCell<StableCell<UpgradeState, Mem>> = RefCell::new(
+        StableCell::init(storage::memory(UPGRADE_MEMORY_ID), UpgradeState::default())
+            .expect("Failed to init upgrade state")
+    );
+}
+
+fn state() -> UpgradeState {
+    STATE.with(|state| state.borrow().get().clone())
+}
+
+fn update_state(f: impl FnOnce(&mut UpgradeState)) {
+    STATE.with(|cell| {
+        let mut state = cell.borrow().get().clone();
+        f(&mut state);
+        cell.borrow_mut()
+            .set(state)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store upgrade state"));
+    });
+}
+
+/// Fails while submissions are paused for an upgrade.
+pub fn check_open() -> Result<(), String> {
+    if PREPARING.get() {
+        return Err("Submissions are paused for an upgrade".to_string());
+    }
+    Ok(())
+}
+
+/// Loads the models and recognizes the canary image.
+fn self_test() -> SelfTest {
+    let mut result = SelfTest {
+        ran_at: ic_cdk::api::time(),
+        schema_version: schema::version(),
+        passed: false,
+        label: None,
+        score: None,
+        error: None,
+    };
+    if let Err(err) = crate::setup_models() {
+        result.error = Some(err);
+        return result;
+    }
+    let state = state();
+    let (Some(image), Some(expected)) = (state.canary_image, state.canary_label) else {
+        result.passed = true;
+        result.error = Some("No canary image".to_string());
+        return result;
+    };
+    match onnx::recognize(image) {
+        Ok(person) => {
+            result.passed = person.label == expected;
+            if !result.passed {
+                result.error = Some(format!("Expected '{}'", expected));
+            }
+            result.label = Some(person.label);
+            result.score = Some(person.score);
+        }
+        Err(err) => result.error = Some(err.to_string()),
+    }
+    result
+}
+
+fn record_self_test() {
+    let result = self_test();
+    ic_cdk::println!("[Self-test] passed: {}, error: {:?}", result.passed, result.error);
+    update_state(|state| state.self_test = Some(result));
+}
+
+/// Runs the self-test after an upgrade. A failure is first recorded in its own
+/// message, so that a self-test that traps is reported too.
+pub fn schedule_self_test() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        update_state(|state| {
+            state.self_test = Some(SelfTest {
+                ran_at: ic_cdk::api::time(),
+                schema_version: schema::version(),
+                passed: false,
+                label: None,
+                score: None,
+                error: Some("The self-test did not complete".to_string()),
+            })
+        });
+        ic_cdk_timers::set_timer(Duration::ZERO, record_self_test);
+    });
+}
+
+/// Describes the background jobs that are still running: queued
+/// verifications, re-embedding and enrollment.
+pub fn background_jobs() -> Vec<String> {
+    let mut jobs = Vec::new();
+    let queued_verifications = pipeline::queued();
+    if queued_verifications > 0 {
+        jobs.push(format!("{} verifications are queued", queued_verifications));
+    }
+    if reembed::is_running() {
+        jobs.push("Re-embedding is running".to_string());
+    }
+    let enrolling = enrollment::pending();
+    if enrolling > 0 {
+        jobs.push(format!("{} images are waiting to be enrolled", enrolling));
+    }
+    jobs
+}
+
+/// Pauses submissions and reports whether the canister can be upgraded. Call
+/// it again until `ready`.
+#[ic_cdk::update(guard = "is_admin")]
+fn prepare_upgrade() -> UpgradeReadiness {
+    PREPARING.set(true);
+    let flushed_uploads = crate::flush_uploads();
+    let queued_verifications = pipeline::queued();
+    let blockers = background_jobs();
+    UpgradeReadiness {
+        submissions_paused: true,
+        queued_verifications,
+        flushed_uploads,
+        ready: blockers.is_empty(),
+        blockers,
+    }
+}
+
+/// Resumes submissions after `prepare_upgrade` without upgrading.
+#[ic_cdk::update(guard = "is_admin")]
+fn cancel_upgrade() {
+    PREPARING.set(false);
+}
+
+/// Stores the canary image and the label it must be recognized as. The canary
+/// has to pass with the current models.
+#[ic_cdk::update(guard = "is_admin")]
+fn set_canary(image: Vec<u8>, label: String) -> Result<(), String> {
+    let person = onnx::recognize(image.clone()).map_err(|err| err.to_string())?;
+    if person.label != label {
+        return Err(format!("The canary is recognized as '{}'", person.label));
+    }
+    update_state(|state| {
+        state.canary_image = Some(image);
+        state.canary_label = Some(label);
+    });
+    Ok(())
+}
+
+/// Runs the self-test now and records it.
+#[ic_cdk::update(guard = "is_admin")]
+fn run_self_test() -> SelfTest {
+    record_self_test();
+    get_self_test().expect("self-test is recorded")
+}
+
+/// Returns the outcome of the last self-test.
+#[ic_cdk::query]
+fn get_self_test() -> Option<SelfTest> {
+    state().self_test
+}
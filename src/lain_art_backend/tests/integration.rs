@@ -0,0 +1,1593 @@
+//! PocketIC integration tests for the lain_art_backend canister.
+//!
+//! The tests deploy the release wasm and the ONNX models from the repository root,
+//! and the test ledger for fees and rewards, so build both canisters first and
+//! point `POCKET_IC_BIN` at a PocketIC server:
+//!
+//! ```bash
+//! cargo build --target wasm32-unknown-unknown --release -p lain_art_backend -p lain_art_test_ledger
+//! POCKET_IC_BIN=/path/to/pocket-ic cargo test -p lain_art_backend --test integration
+//! ```
+//!
+//! `LAIN_ART_BACKEND_WASM` and `LAIN_ART_TEST_LEDGER_WASM` override the paths
+//! of the wasm modules.
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Int, Nat, Principal};
+use lain_art_backend::onnx::{BoundingBox, Embedding, Metric, Person, Ranking, THRESHOLD};
+use lain_art_backend::{
+    ApprovedArtwork, BackupChunk, BackupManifest, DecisionKind, DetectionMode, DetectorModel, DuplicateMatch,
+    EnrollmentReport, FacePage, FaceSnapshot, FeeConfig, HashMatch, ImportMode, ImportSummary, ModelKind,
+    PauseStatus, PauseSwitch, Paused, PreprocessSpec, ReembedStatus, Refund, RefundConfig, RefundPolicy, RejectionKind, RewardConfig, RewardResult,
+    SelfTest, ServiceError, Status, Submission, UpgradeReadiness, VerificationPolicy, VerificationStep,
+};
+use pocket_ic::{PocketIc, WasmResult};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+// Model chunks have to stay below the 2MB ingress message limit.
+const MODEL_CHUNK_SIZE: usize = 1_000_000;
+// Small artwork chunks so that the tests exercise multi-chunk submissions.
+const ARTWORK_CHUNK_SIZE: usize = 2_048;
+
+const REFERENCE_IMAGE: &str = "laindb/02.png";
+const NON_LAIN_IMAGE: &str = "laindb/nonlains/01.png";
+
+fn repo_path(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../..").join(path)
+}
+
+fn read(path: &str) -> Vec<u8> {
+    let path = repo_path(path);
+    std::fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+}
+
+/// The wasm of the previous release, built from an earlier commit, to test
+/// upgrades of its stable memory.
+fn previous_backend_wasm() -> Vec<u8> {
+    let path = std::env::var("LAIN_ART_BACKEND_PREVIOUS_WASM")
+        .expect("Set LAIN_ART_BACKEND_PREVIOUS_WASM to the wasm of the previous release");
+    std::fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err))
+}
+
+fn backend_wasm() -> Vec<u8> {
+    let path = std::env::var("LAIN_ART_BACKEND_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            repo_path("target/wasm32-unknown-unknown/release/lain_art_backend.wasm")
+        });
+    std::fs::read(&path).unwrap_or_else(|_| {
+        panic!(
+            "Missing {}; build it with `cargo build --target wasm32-unknown-unknown --release -p lain_art_backend`",
+            path.display()
+        )
+    })
+}
+
+fn ledger_wasm() -> Vec<u8> {
+    let path = std::env::var("LAIN_ART_TEST_LEDGER_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| repo_path("target/wasm32-unknown-unknown/release/lain_art_test_ledger.wasm"));
+    std::fs::read(&path).unwrap_or_else(|_| {
+        panic!(
+            "Missing {}; build it with `cargo build --target wasm32-unknown-unknown --release -p lain_art_test_ledger`",
+            path.display()
+        )
+    })
+}
+
+fn controller() -> Principal {
+    Principal::from_slice(&[1; 29])
+}
+
+fn creator() -> Principal {
+    Principal::from_slice(&[2; 29])
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FeeInvoice {
+    amount: Nat,
+    subaccount: Option<Vec<u8>>,
+    memo: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+struct NftTransaction {
+    id: u64,
+    token_id: u64,
+    from: Option<Account>,
+    to: Account,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+struct Canister {
+    pic: PocketIc,
+    id: Principal,
+}
+
+impl Canister {
+    /// Installs the canister with submission fees disabled, since there is no
+    /// ledger in the test environment.
+    fn install() -> Self {
+        let canister = Self::install_with_fees();
+        let config = FeeConfig { amount: Nat::from(0u64), ..FeeConfig::default() };
+        canister.update_unit(controller(), "set_fee_config", encode_one(config).unwrap());
+        canister
+    }
+
+    fn install_with_fees() -> Self {
+        Self::install_wasm(backend_wasm())
+    }
+
+    fn install_wasm(wasm: Vec<u8>) -> Self {
+        let pic = PocketIc::new();
+        let id = pic.create_canister_with_settings(Some(controller()), None);
+        pic.add_cycles(id, 100_000_000_000_000);
+        pic.install_canister(id, wasm, encode_args(()).unwrap(), Some(controller()));
+        Self { pic, id }
+    }
+
+    fn upgrade(&self) {
+        self.pic
+            .upgrade_canister(self.id, backend_wasm(), encode_args(()).unwrap(), Some(controller()))
+            .expect("Failed to upgrade canister");
+    }
+
+    fn reply(&self, method: &str, result: Result<WasmResult, pocket_ic::UserError>) -> Vec<u8> {
+        match result {
+            Ok(WasmResult::Reply(bytes)) => bytes,
+            Ok(WasmResult::Reject(message)) => panic!("{} was rejected: {}", method, message),
+            Err(err) => panic!("{} failed: {}", method, err),
+        }
+    }
+
+    fn update<T: CandidType + DeserializeOwned>(&self, sender: Principal, method: &str, args: Vec<u8>) -> T {
+        let bytes = self.reply(method, self.pic.update_call(self.id, sender, method, args));
+        decode_one(&bytes).unwrap()
+    }
+
+    fn update_unit(&self, sender: Principal, method: &str, args: Vec<u8>) {
+        self.reply(method, self.pic.update_call(self.id, sender, method, args));
+    }
+
+    /// Installs a test ledger next to the canister; `icrc3` sets whether it
+    /// implements ICRC-3.
+    fn install_ledger(&self, icrc3: bool) -> Principal {
+        let ledger = self.pic.create_canister_with_settings(Some(controller()), None);
+        self.pic.add_cycles(ledger, 100_000_000_000_000);
+        self.pic.install_canister(ledger, ledger_wasm(), encode_one(icrc3).unwrap(), Some(controller()));
+        ledger
+    }
+
+    fn ledger_update<T: CandidType + DeserializeOwned>(&self, ledger: Principal, method: &str, args: Vec<u8>) -> T {
+        let bytes = self.reply(method, self.pic.update_call(ledger, controller(), method, args));
+        decode_one(&bytes).unwrap()
+    }
+
+    fn mint(&self, ledger: Principal, to: Account, amount: u64) {
+        let _: Nat = self.ledger_update(ledger, "mint", encode_args((to, Nat::from(amount))).unwrap());
+    }
+
+    fn balance(&self, ledger: Principal, account: Account) -> Nat {
+        self.ledger_update(ledger, "icrc1_balance_of", encode_one(account).unwrap())
+    }
+
+    /// Makes the ledger trap on transfers, so that the canister cannot tell
+    /// whether they went through.
+    fn fail_transfers(&self, ledger: Principal, failing: bool) {
+        self.reply("set_failing", self.pic.update_call(ledger, controller(), "set_failing", encode_one(failing).unwrap()));
+    }
+
+    fn query<T: CandidType + DeserializeOwned>(&self, method: &str, args: Vec<u8>) -> T {
+        let bytes = self.reply(method, self.pic.query_call(self.id, Principal::anonymous(), method, args));
+        decode_one(&bytes).unwrap()
+    }
+
+    /// Uploads both models in chunks, the same way `upload_model_to_canister.sh` does.
+    fn upload_models(&self) {
+        for (model, file) in [
+            ("face_detection", "version-RFB-320.onnx"),
+            ("face_recognition", "face-recognition.onnx"),
+        ] {
+            self.update_unit(controller(), &format!("clear_{}_model_bytes", model), encode_args(()).unwrap());
+            for chunk in read(file).chunks(MODEL_CHUNK_SIZE) {
+                self.update_unit(
+                    controller(),
+                    &format!("append_{}_model_bytes", model),
+                    encode_one(chunk.to_vec()).unwrap(),
+                );
+            }
+        }
+        self.setup_models();
+    }
+
+    fn setup_models(&self) {
+        let result: Result<(), String> = self.update(controller(), "setup_models", encode_args(()).unwrap());
+        result.expect("setup_models failed");
+    }
+
+    fn enroll(&self, label: &str, path: &str) -> Embedding {
+        let result: Result<Embedding, String> =
+            self.update(controller(), "add", encode_args((label, read(path))).unwrap());
+        result.unwrap_or_else(|err| panic!("Failed to enroll {}: {}", path, err))
+    }
+
+    /// Runs the upload flow for the given image and returns the submission ID.
+    fn submit(&self, sender: Principal, path: &str) -> Nat {
+        let image = read(path);
+        let id: Nat = self.update(sender, "start_submission", encode_args(()).unwrap());
+        for (index, chunk) in image.chunks(ARTWORK_CHUNK_SIZE).enumerate() {
+            self.update_unit(
+                sender,
+                "put_chunk",
+                encode_args((id.clone(), Nat::from(index), chunk.to_vec())).unwrap(),
+            );
+        }
+        let sha256 = Sha256::digest(&image).to_vec();
+        self.update_unit(
+            sender,
+            "finalize_asset",
+            encode_args((id.clone(), "image/png", Nat::from(image.len()), sha256)).unwrap(),
+        );
+        id
+    }
+
+    fn verify(&self, sender: Principal, id: Nat) -> Result<u64, String> {
+        self.update(sender, "verify_and_store_artwork", encode_one(id).unwrap())
+    }
+}
+
+#[test]
+fn lain_submission_is_verified_and_added_to_gallery() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let detection: Result<BoundingBox, String> = canister.query("detect", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    detection.expect("No face detected in the reference image");
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let artwork_id = canister.verify(creator(), id).expect("Verification failed");
+
+    let count: u64 = canister.query("get_artwork_count", encode_args(()).unwrap());
+    assert_eq!(count, 1);
+
+    let artwork: Option<ApprovedArtwork> = canister.query("get_artwork_by_id", encode_one(artwork_id).unwrap());
+    let artwork = artwork.expect("Approved artwork missing from gallery");
+    assert_eq!(artwork.creator, creator());
+    assert_eq!(artwork.image_data, read(REFERENCE_IMAGE));
+    assert_eq!(artwork.mime_type, "image/png");
+
+    let gallery: Vec<ApprovedArtwork> = canister.query("get_approved_artwork", encode_args(()).unwrap());
+    assert_eq!(gallery.len(), 1);
+    assert_eq!(gallery[0].id, artwork_id);
+}
+
+#[test]
+fn missing_models_and_references_do_not_reject_submissions() {
+    let canister = Canister::install();
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let submission = || -> Submission {
+        canister
+            .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+            .unwrap()
+    };
+
+    let result = canister.verify(creator(), id.clone());
+    assert_eq!(result, Err("Face detection failed: Face detection model is not loaded".to_string()));
+    assert_eq!(submission().status, Status::Verifying);
+    assert_eq!(submission().rejection, None);
+    assert_eq!(submission().verification_error, result.err());
+
+    // Models without references: recognition cannot run either.
+    canister.upload_models();
+    let result: Result<(), ServiceError> = canister.update(creator(), "trigger_verification", encode_one(id.clone()).unwrap());
+    assert_eq!(result, Ok(()));
+    for _ in 0..20 {
+        canister.pic.tick();
+    }
+    assert_eq!(submission().status, Status::Verifying);
+    assert_eq!(submission().rejection, None);
+    assert_eq!(
+        submission().verification_error,
+        Some("Face recognition failed: No faces in database".to_string())
+    );
+
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    assert!(canister.verify(creator(), id).is_ok());
+    assert!(matches!(submission().status, Status::Verified { .. }));
+    assert_eq!(submission().verification_error, None);
+}
+
+#[test]
+fn approved_artwork_mints_an_nft_to_the_creator() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let artwork_id = canister.verify(creator(), id).expect("Verification failed");
+    let artwork: Option<ApprovedArtwork> = canister.query("get_artwork_by_id", encode_one(artwork_id).unwrap());
+    let token_id = artwork.unwrap().nft_token_id.expect("No NFT minted");
+
+    let owners: Vec<Option<Account>> =
+        canister.query("icrc7_owner_of", encode_one(vec![Nat::from(token_id)]).unwrap());
+    assert_eq!(owners, vec![Some(Account { owner: creator(), subaccount: None })]);
+
+    let metadata: Vec<Option<Vec<(String, Value)>>> =
+        canister.query("icrc7_token_metadata", encode_one(vec![Nat::from(token_id)]).unwrap());
+    let metadata = metadata[0].clone().expect("Token metadata missing");
+    assert!(metadata.contains(&("lain_art:artwork_id".to_string(), Value::Nat(Nat::from(artwork_id)))));
+    assert!(metadata.contains(&(
+        "lain_art:image_sha256".to_string(),
+        Value::Blob(Sha256::digest(read(REFERENCE_IMAGE)).to_vec())
+    )));
+
+    let supply: Nat = canister.query("icrc7_total_supply", encode_args(()).unwrap());
+    assert_eq!(supply, Nat::from(1u64));
+}
+
+#[test]
+fn nft_transfers_are_deduplicated_and_logged() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let token_id = canister.verify(creator(), id).expect("Verification failed");
+
+    let now = canister.pic.get_time().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos() as u64;
+    let recipient = Account { owner: Principal::from_slice(&[3; 29]), subaccount: None };
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: recipient.clone(),
+        token_id: Nat::from(token_id),
+        memo: Some(b"gift".to_vec()),
+        created_at_time: Some(now),
+    };
+    let transfer = |arg: &TransferArg| -> Vec<Option<Result<Nat, TransferError>>> {
+        canister.update(creator(), "icrc7_transfer", encode_one(vec![arg.clone()]).unwrap())
+    };
+
+    // Subaccounts are not padded or truncated to 32 bytes.
+    let invalid_recipient = TransferArg {
+        to: Account { owner: recipient.owner, subaccount: Some(vec![1; 8]) },
+        ..arg.clone()
+    };
+    assert_eq!(transfer(&invalid_recipient), vec![Some(Err(TransferError::InvalidRecipient))]);
+    let invalid_sender = TransferArg { from_subaccount: Some(vec![0; 31]), ..arg.clone() };
+    assert!(matches!(transfer(&invalid_sender).as_slice(), [Some(Err(TransferError::GenericError { .. }))]));
+
+    let tx_id = match transfer(&arg).as_slice() {
+        [Some(Ok(tx_id))] => tx_id.clone(),
+        other => panic!("Transfer failed: {:?}", other),
+    };
+    // A retry of the same transfer is recognized instead of failing as unauthorized.
+    assert_eq!(transfer(&arg), vec![Some(Err(TransferError::Duplicate { duplicate_of: tx_id.clone() }))]);
+    let owners: Vec<Option<Account>> =
+        canister.query("icrc7_owner_of", encode_one(vec![Nat::from(token_id)]).unwrap());
+    assert_eq!(owners, vec![Some(recipient.clone())]);
+
+    let log: Vec<NftTransaction> = canister.query("get_nft_transactions", encode_args((None::<Nat>, None::<Nat>)).unwrap());
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[0].from, None);
+    assert_eq!(log[0].to, Account { owner: creator(), subaccount: None });
+    assert_eq!(Nat::from(log[1].id), tx_id);
+    assert_eq!(log[1].from, Some(Account { owner: creator(), subaccount: None }));
+    assert_eq!(log[1].to, recipient);
+    assert_eq!(log[1].created_at_time, Some(now));
+}
+
+#[test]
+fn non_lain_submission_is_rejected() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), NON_LAIN_IMAGE);
+    assert!(canister.verify(creator(), id.clone()).is_err());
+
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id).unwrap())
+        .unwrap();
+    assert!(matches!(submission.status, Status::Rejected { .. }));
+    assert!(matches!(submission.rejection, Some(RejectionKind::NotLain | RejectionKind::NoFace)));
+    // No fee was paid, so there is nothing to refund.
+    assert_eq!(submission.refund, None);
+
+    let count: u64 = canister.query("get_artwork_count", encode_args(()).unwrap());
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn verified_submission_is_recorded_and_reward_requires_configuration() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let submission: Option<Submission> = canister.query("get_submission", encode_one(id.clone()).unwrap());
+    assert_eq!(submission.unwrap().status, Status::Verifying);
+
+    let artwork_id = canister.verify(creator(), id.clone()).expect("Verification failed");
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+        .unwrap();
+    let Status::Verified { originality, visibility } = submission.status else {
+        panic!("Unexpected status {:?}", submission.status);
+    };
+    // Nothing to compare against in an empty gallery.
+    assert_eq!(originality, 10_000);
+    assert!(visibility > 0 && visibility <= 10_000);
+    assert_eq!(submission.artwork_id, Some(artwork_id));
+    assert_eq!(submission.nft_token_id, Some(artwork_id));
+
+    // Verifying twice must not store the artwork twice.
+    assert!(canister.verify(creator(), id.clone()).is_err());
+
+    let reward: Result<RewardResult, ServiceError> = canister.update(creator(), "finalize_reward", encode_one(id).unwrap());
+    assert_eq!(reward, Err(ServiceError::Failed("Rewards are not configured".to_string())));
+}
+
+#[test]
+fn reward_config_is_admin_only() {
+    let canister = Canister::install();
+    let config = RewardConfig {
+        ledger: Principal::anonymous(),
+        amount: Nat::from(1_000u64),
+        treasury_subaccount: None,
+    };
+    let result = canister.pic.update_call(canister.id, creator(), "set_reward_config", encode_one(config).unwrap());
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+}
+
+#[test]
+fn reward_config_needs_an_icrc3_ledger_and_a_valid_treasury() {
+    let canister = Canister::install();
+    let plain = canister.install_ledger(false);
+    let icrc3 = canister.install_ledger(true);
+    let set = |ledger: Principal, amount: u64, treasury_subaccount: Option<Vec<u8>>| {
+        let config = RewardConfig { ledger, amount: Nat::from(amount), treasury_subaccount };
+        let result = canister.pic.update_call(canister.id, controller(), "set_reward_config", encode_one(config).unwrap());
+        matches!(result, Ok(WasmResult::Reply(_)))
+    };
+    assert!(!set(plain, 1_000, None), "Accepted a ledger without ICRC-3");
+    assert!(!set(icrc3, 1_000, Some(vec![1; 31])), "Accepted a treasury subaccount of 31 bytes");
+    assert!(set(icrc3, 1_000, Some(vec![1; 32])));
+    // Disabling rewards does not need the ledger.
+    assert!(set(plain, 0, None));
+}
+
+#[test]
+fn rewards_are_sent_again_after_the_deduplication_window() {
+    let canister = Canister::install();
+    let ledger = canister.install_ledger(true);
+    canister.mint(ledger, Account { owner: canister.id, subaccount: None }, 1_000_000);
+    let config = RewardConfig { ledger, amount: Nat::from(1_000u64), treasury_subaccount: None };
+    canister.update_unit(controller(), "set_reward_config", encode_one(config).unwrap());
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    // The payout after approval does not know whether its transfer went through.
+    canister.fail_transfers(ledger, true);
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    assert!(canister.verify(creator(), id.clone()).is_ok());
+    for _ in 0..5 {
+        canister.pic.tick();
+    }
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+        .unwrap();
+    assert!(submission.reward.is_none());
+
+    // Past the ledger's deduplication window, the transfer is looked up and sent again.
+    canister.fail_transfers(ledger, false);
+    canister.pic.advance_time(std::time::Duration::from_secs(25 * 60 * 60));
+    let reward: Result<RewardResult, ServiceError> = canister.update(creator(), "finalize_reward", encode_one(id.clone()).unwrap());
+    assert!(reward.is_ok(), "{:?}", reward);
+    let again: Result<RewardResult, ServiceError> = canister.update(creator(), "finalize_reward", encode_one(id).unwrap());
+    assert_eq!(again, reward);
+    assert_eq!(canister.balance(ledger, Account { owner: creator(), subaccount: None }), Nat::from(1_000u64));
+}
+
+#[test]
+fn face_database_and_models_are_admin_only() {
+    let canister = Canister::install();
+    let calls = [
+        ("add", encode_args(("Lain", read(REFERENCE_IMAGE))).unwrap()),
+        ("remove_face", encode_one("Lain").unwrap()),
+        ("clear_face_detection_model_bytes", encode_args(()).unwrap()),
+        ("append_face_recognition_model_bytes", encode_one(vec![0u8; 4]).unwrap()),
+        ("setup_models", encode_args(()).unwrap()),
+    ];
+    for (method, arg) in calls {
+        let result = canister.pic.update_call(canister.id, creator(), method, arg);
+        assert!(!matches!(result, Ok(WasmResult::Reply(_))), "{} is not guarded", method);
+    }
+}
+
+#[test]
+fn verification_requires_the_fee_to_be_paid() {
+    let canister = Canister::install_with_fees();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let submission: Option<Submission> = canister.query("get_submission", encode_one(id.clone()).unwrap());
+    assert_eq!(submission.unwrap().status, Status::AwaitingFee);
+
+    let result = canister.verify(creator(), id.clone());
+    assert_eq!(result, Err("The submission fee has not been paid".to_string()));
+
+    let result: Result<(), String> = canister.update(controller(), "confirm_fee", encode_one(id.clone()).unwrap());
+    assert_eq!(result, Err("Only the creator can confirm the fee".to_string()));
+
+    // Refunds go to the paying account, which must be a valid ICRC-1 account.
+    let payer = Account { owner: creator(), subaccount: Some(vec![1; 5]) };
+    let result: Result<(), String> = canister.update(creator(), "confirm_fee", encode_args((id, Some(payer))).unwrap());
+    assert_eq!(result, Err("Subaccounts must have 32 bytes".to_string()));
+}
+
+#[test]
+fn refunds_are_sent_again_after_the_deduplication_window() {
+    let canister = Canister::install_with_fees();
+    let ledger = canister.install_ledger(true);
+    let half = RefundPolicy::Partial { basis_points: 5_000 };
+    let refunds = RefundConfig { invalid_image: half.clone(), no_face: half.clone(), not_lain: half };
+    let config = FeeConfig { ledger, amount: Nat::from(100_000u64), refunds, ..FeeConfig::default() };
+    canister.update_unit(controller(), "set_fee_config", encode_one(config).unwrap());
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), NON_LAIN_IMAGE);
+    let invoice: FeeInvoice = canister.query("get_fee_invoice", encode_one(id.clone()).unwrap());
+    canister.mint(ledger, Account { owner: canister.id, subaccount: invoice.subaccount }, 100_000);
+    let result: Result<(), String> = canister.update(creator(), "confirm_fee", encode_args((id.clone(), None::<Account>)).unwrap());
+    assert_eq!(result, Ok(()));
+    assert!(canister.verify(creator(), id.clone()).is_err());
+
+    // Once the appeal window closes, the refund does not know whether its transfer went through.
+    canister.fail_transfers(ledger, true);
+    canister.pic.advance_time(std::time::Duration::from_secs(3 * 24 * 60 * 60 + 1));
+    for _ in 0..5 {
+        canister.pic.tick();
+    }
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+        .unwrap();
+    assert!(submission.refund.is_none());
+
+    // Past the ledger's deduplication window, the refund is looked up and sent again.
+    canister.fail_transfers(ledger, false);
+    canister.pic.advance_time(std::time::Duration::from_secs(25 * 60 * 60));
+    // Refunds are payouts and wait while rewards are paused.
+    let result: Result<(), String> =
+        canister.update(controller(), "pause", encode_args((PauseSwitch::Rewards, "Ledger incident")).unwrap());
+    assert_eq!(result, Ok(()));
+    let refund: Result<Option<Refund>, String> = canister.update(creator(), "finalize_refund", encode_one(id.clone()).unwrap());
+    assert_eq!(refund, Err("Paused: Ledger incident".to_string()));
+    canister.update_unit(controller(), "resume", encode_one(PauseSwitch::Rewards).unwrap());
+    let refund: Result<Option<Refund>, String> = canister.update(creator(), "finalize_refund", encode_one(id.clone()).unwrap());
+    let refund = refund.expect("Refund failed").expect("No refund owed");
+    // Half of the fee, minus the ledger fee.
+    assert_eq!(refund.amount, Nat::from(40_000u64));
+    let again: Result<Option<Refund>, String> = canister.update(creator(), "finalize_refund", encode_one(id).unwrap());
+    assert_eq!(again, Ok(Some(refund)));
+    assert_eq!(canister.balance(ledger, Account { owner: creator(), subaccount: None }), Nat::from(40_000u64));
+}
+
+#[test]
+fn resubmitted_artwork_is_rejected_as_duplicate() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let first = canister.submit(creator(), REFERENCE_IMAGE);
+    let artwork_id = canister.verify(creator(), first).expect("Verification failed");
+
+    let second = canister.submit(creator(), REFERENCE_IMAGE);
+    let result = canister.verify(creator(), second.clone());
+    assert_eq!(
+        result,
+        Err(format!("Near-duplicate of artwork {} (Hamming distance 0)", artwork_id))
+    );
+
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(second).unwrap())
+        .unwrap();
+    assert_eq!(submission.rejection, Some(RejectionKind::Duplicate));
+    assert_eq!(
+        submission.duplicate_of,
+        Some(DuplicateMatch { matched: HashMatch::Artwork(artwork_id), distance: 0 })
+    );
+}
+
+#[test]
+fn copies_of_reference_frames_are_rejected() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let frame: Result<u64, String> = canister.update(
+        controller(),
+        "add_reference_frame",
+        encode_args(("episode 1", read(REFERENCE_IMAGE))).unwrap(),
+    );
+    let frame = frame.expect("Failed to add reference frame");
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let result = canister.verify(creator(), id);
+    assert_eq!(
+        result,
+        Err(format!("Near-duplicate of reference frame {} (Hamming distance 0)", frame))
+    );
+
+    let count: u64 = canister.query("get_artwork_count", encode_args(()).unwrap());
+    assert_eq!(count, 0);
+
+    // The submission keeps pointing at the frame, so its ID is never reused.
+    canister.update_unit(controller(), "remove_reference_frame", encode_one(frame).unwrap());
+    canister.upgrade();
+    let next: Result<u64, String> = canister.update(
+        controller(),
+        "add_reference_frame",
+        encode_args(("episode 2", read(NON_LAIN_IMAGE))).unwrap(),
+    );
+    assert_eq!(next, Ok(frame + 1));
+}
+
+fn reviewer() -> Principal {
+    Principal::from_slice(&[3; 29])
+}
+
+#[test]
+fn review_endpoints_require_the_reviewer_role() {
+    let canister = Canister::install();
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+
+    let result = canister.pic.query_call(canister.id, reviewer(), "list_review_queue", encode_args(()).unwrap());
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+
+    canister.update_unit(controller(), "add_reviewer", encode_one(reviewer()).unwrap());
+    let queue: Vec<Submission> = canister.update(reviewer(), "list_review_queue", encode_args(()).unwrap());
+    assert!(queue.is_empty());
+
+    let result: Result<(), ServiceError> = canister.update(
+        reviewer(),
+        "set_verdict",
+        encode_args((id, 5_000u16, 5_000u16, "looks fine")).unwrap(),
+    );
+    assert_eq!(result, Err(ServiceError::Failed("Submission is not under review".to_string())));
+}
+
+#[test]
+fn override_verdict_approves_a_rejected_submission() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), NON_LAIN_IMAGE);
+    assert!(canister.verify(creator(), id.clone()).is_err());
+
+    let status = Status::Verified { originality: 8_000, visibility: 6_000 };
+    let result: Result<(), ServiceError> = canister.update(
+        controller(),
+        "override_verdict",
+        encode_args((id.clone(), status.clone(), "Lain in disguise")).unwrap(),
+    );
+    assert_eq!(result, Ok(()));
+
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id).unwrap())
+        .unwrap();
+    assert_eq!(submission.status, status);
+    let decisions = submission.review.expect("Decision not recorded").decisions;
+    assert_eq!(decisions.len(), 1);
+    assert_eq!(decisions[0].reviewer, controller());
+    assert!(matches!(decisions[0].kind, DecisionKind::Override { .. }));
+
+    let artwork: Option<ApprovedArtwork> =
+        canister.query("get_artwork_by_id", encode_one(submission.artwork_id.unwrap()).unwrap());
+    let artwork = artwork.expect("Approved artwork missing from gallery");
+    assert_eq!((artwork.originality, artwork.visibility), (Some(8_000), Some(6_000)));
+}
+
+#[test]
+fn rejected_submission_can_be_appealed_once() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    canister.update_unit(controller(), "add_reviewer", encode_one(reviewer()).unwrap());
+
+    let id = canister.submit(creator(), NON_LAIN_IMAGE);
+    assert!(canister.verify(creator(), id.clone()).is_err());
+
+    let appeal = |sender: Principal| -> Result<(), ServiceError> {
+        canister.update(sender, "appeal_rejection", encode_args((id.clone(), "It is Lain")).unwrap())
+    };
+    assert_eq!(appeal(reviewer()), Err(ServiceError::Failed("Only the creator can appeal".to_string())));
+    assert_eq!(appeal(creator()), Ok(()));
+    assert_eq!(appeal(creator()), Err(ServiceError::Failed("Only rejected submissions can be appealed".to_string())));
+
+    let queue: Vec<Submission> = canister.update(reviewer(), "list_review_queue", encode_args(()).unwrap());
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].status, Status::UnderReview { reason: "Appeal: It is Lain".to_string() });
+
+    let result: Result<(), ServiceError> = canister.update(
+        reviewer(),
+        "set_verdict",
+        encode_args((id.clone(), 9_000u16, 0u16, "No Lain here")).unwrap(),
+    );
+    assert_eq!(result, Ok(()));
+
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id).unwrap())
+        .unwrap();
+    assert!(matches!(submission.status, Status::Rejected { .. }));
+    let outcome = submission.appeal.expect("Appeal not recorded").outcome.expect("Appeal not decided");
+    assert!(!outcome.approved);
+    assert_eq!(outcome.reviewer, reviewer());
+    assert_eq!(outcome.note, "No Lain here");
+    assert_eq!(appeal(creator()), Err(ServiceError::Failed("This submission was already appealed".to_string())));
+}
+
+#[test]
+fn only_the_creator_can_upload_until_the_asset_is_finalized() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let put_chunk = |sender: Principal, id: &Nat| {
+        canister.pic.update_call(
+            canister.id,
+            sender,
+            "put_chunk",
+            encode_args((id.clone(), Nat::from(0u64), read(REFERENCE_IMAGE))).unwrap(),
+        )
+    };
+
+    let id: Nat = canister.update(creator(), "start_submission", encode_args(()).unwrap());
+    assert!(!matches!(put_chunk(reviewer(), &id), Ok(WasmResult::Reply(_))));
+    assert!(!matches!(put_chunk(creator(), &Nat::from(42u64)), Ok(WasmResult::Reply(_))));
+
+    // The image a rejection was decided on is the one an appeal is reviewed with.
+    let id = canister.submit(creator(), NON_LAIN_IMAGE);
+    assert!(canister.verify(creator(), id.clone()).is_err());
+    assert!(!matches!(put_chunk(creator(), &id), Ok(WasmResult::Reply(_))));
+    let result: Result<(), ServiceError> =
+        canister.update(creator(), "appeal_rejection", encode_args((id.clone(), "It is Lain")).unwrap());
+    assert_eq!(result, Ok(()));
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id).unwrap())
+        .unwrap();
+    assert!(matches!(submission.status, Status::UnderReview { .. }));
+}
+
+#[test]
+fn rejections_can_only_be_appealed_within_the_window() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), NON_LAIN_IMAGE);
+    assert!(canister.verify(creator(), id.clone()).is_err());
+
+    canister.pic.advance_time(std::time::Duration::from_secs(3 * 24 * 60 * 60 + 1));
+    let result: Result<(), ServiceError> =
+        canister.update(creator(), "appeal_rejection", encode_args((id, "It is Lain")).unwrap());
+    assert_eq!(result, Err(ServiceError::Failed("The appeal window of this submission has closed".to_string())));
+}
+
+#[test]
+fn triggered_verification_runs_in_steps() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let result: Result<(), ServiceError> = canister.update(reviewer(), "trigger_verification", encode_one(id.clone()).unwrap());
+    assert_eq!(result, Err(ServiceError::Failed("Only the creator can verify a submission".to_string())));
+    let result: Result<(), ServiceError> = canister.update(creator(), "trigger_verification", encode_one(id.clone()).unwrap());
+    assert_eq!(result, Ok(()));
+
+    let submission = || -> Submission {
+        canister
+            .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+            .unwrap()
+    };
+    assert_eq!(submission().status, Status::Processing { step: VerificationStep::Decode });
+    assert_eq!(
+        canister.verify(creator(), id.clone()),
+        Err("Submission is already being verified".to_string())
+    );
+
+    let mut steps = Vec::new();
+    for _ in 0..20 {
+        canister.pic.tick();
+        match submission().status {
+            Status::Processing { step } if steps.last() != Some(&step) => steps.push(step),
+            Status::Processing { .. } => {}
+            _ => break,
+        }
+    }
+    assert!(matches!(submission().status, Status::Verified { .. }), "{:?}", submission().status);
+    assert!(steps.len() > 1, "All steps ran in one message: {:?}", steps);
+    assert!(submission().artwork_id.is_some());
+}
+
+#[test]
+fn copies_approved_during_verification_are_rejected_on_persist() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let submission = |id: &Nat| -> Submission {
+        canister
+            .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+            .unwrap()
+    };
+
+    // The queued submission passes the duplicate check of the Decode step first.
+    let queued = canister.submit(creator(), REFERENCE_IMAGE);
+    let result: Result<(), ServiceError> = canister.update(creator(), "trigger_verification", encode_one(queued.clone()).unwrap());
+    assert_eq!(result, Ok(()));
+    for _ in 0..10 {
+        canister.pic.tick();
+        if !matches!(submission(&queued).status, Status::Processing { step: VerificationStep::Decode }) {
+            break;
+        }
+    }
+    assert_eq!(submission(&queued).status, Status::Processing { step: VerificationStep::Detect });
+
+    let copy = canister.submit(creator(), REFERENCE_IMAGE);
+    assert!(canister.verify(creator(), copy).is_ok());
+    for _ in 0..20 {
+        canister.pic.tick();
+    }
+    let queued = submission(&queued);
+    assert!(matches!(queued.status, Status::Rejected { .. }), "{:?}", queued.status);
+    assert_eq!(queued.rejection, Some(RejectionKind::Duplicate));
+    assert!(queued.artwork_id.is_none());
+}
+
+#[test]
+fn tiled_detection_verifies_lain() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let policy = VerificationPolicy {
+        detection: DetectionMode::Tiled { tile_width: 320, tile_height: 240, overlap: 80 },
+        detector: None,
+    };
+    let result = canister.pic.update_call(
+        canister.id,
+        creator(),
+        "set_verification_policy",
+        encode_one(policy.clone()).unwrap(),
+    );
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+    let result: Result<(), String> =
+        canister.update(controller(), "set_verification_policy", encode_one(policy).unwrap());
+    assert_eq!(result, Ok(()));
+    let policy: VerificationPolicy = canister.query("get_verification_policy", encode_args(()).unwrap());
+    assert_eq!(policy.detection, DetectionMode::Tiled { tile_width: 320, tile_height: 240, overlap: 80 });
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    assert!(canister.verify(creator(), id).is_ok());
+}
+
+#[test]
+fn anime_detector_must_be_loaded_to_be_selected() {
+    let canister = Canister::install();
+    canister.upload_models();
+
+    let policy = VerificationPolicy { detection: DetectionMode::Full, detector: Some(DetectorModel::Anime) };
+    let result: Result<(), String> =
+        canister.update(controller(), "set_verification_policy", encode_one(policy).unwrap());
+    assert!(result.is_err());
+    let policy: VerificationPolicy = canister.query("get_verification_policy", encode_args(()).unwrap());
+    assert_eq!(policy.detector, None);
+}
+
+#[test]
+fn preprocess_spec_can_be_replaced() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let spec: Option<PreprocessSpec> =
+        canister.query("get_preprocess_spec", encode_one(ModelKind::FaceRecognition).unwrap());
+    let spec = spec.expect("Loaded models have a spec");
+    assert_eq!((spec.width, spec.height), (160, 160));
+
+    let invalid = PreprocessSpec { std: vec![0.0; 3], ..spec.clone() };
+    let result: Result<(), String> = canister.update(
+        controller(),
+        "set_preprocess_spec",
+        encode_args((ModelKind::FaceRecognition, Some(invalid))).unwrap(),
+    );
+    assert!(result.is_err());
+
+    // The same spec, stored explicitly, still recognizes the reference.
+    let result: Result<(), String> = canister.update(
+        controller(),
+        "set_preprocess_spec",
+        encode_args((ModelKind::FaceRecognition, Some(spec))).unwrap(),
+    );
+    assert_eq!(result, Ok(()));
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    assert!(canister.verify(creator(), id).is_ok());
+}
+
+#[test]
+fn recognition_scores_are_distances_between_0_and_2() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let metric: Metric = canister.query("get_recognition_metric", encode_args(()).unwrap());
+    assert_eq!(metric, Metric::Euclidean);
+
+    for metric in [Metric::Euclidean, Metric::Cosine, Metric::Dot] {
+        canister.update_unit(controller(), "set_recognition_metric", encode_one(metric).unwrap());
+        for image in [REFERENCE_IMAGE, NON_LAIN_IMAGE] {
+            let person: Result<Person, String> = canister.update(creator(), "recognize", encode_one(read(image)).unwrap());
+            if let Ok(person) = person {
+                assert!((0.0..=2.0).contains(&person.score), "{:?}: {}", metric, person.score);
+            }
+        }
+        let person: Result<Person, String> =
+            canister.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+        assert!(person.expect("Reference not recognized").score < 0.01);
+    }
+}
+
+#[test]
+fn recognize_topk_ranks_identities() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    canister.enroll("Other", NON_LAIN_IMAGE);
+    canister.enroll("Lain", NON_LAIN_IMAGE);
+
+    let faces: Vec<String> = canister.query("list_stored_faces", encode_args(()).unwrap());
+    assert_eq!(faces, vec!["Lain".to_string(), "Other".to_string()]);
+
+    let ranking: Result<Ranking, String> =
+        canister.update(creator(), "recognize_topk", encode_args((read(REFERENCE_IMAGE), 5u32)).unwrap());
+    let ranking = ranking.expect("Ranking failed");
+    let labels: Vec<&str> = ranking.matches.iter().map(|m| m.label.as_str()).collect();
+    assert_eq!(labels, vec!["Lain", "Other"]);
+    assert_eq!(ranking.matches[0].reference_id, 0);
+    let margin = ranking.margin.expect("Two identities have a margin");
+    assert!((margin - (ranking.matches[1].score - ranking.matches[0].score)).abs() < 1e-6);
+
+    let ranking: Result<Ranking, String> =
+        canister.update(creator(), "recognize_topk", encode_args((read(REFERENCE_IMAGE), 1u32)).unwrap());
+    assert_eq!(ranking.expect("Ranking failed").matches.len(), 1);
+}
+
+#[test]
+fn negative_references_reject_closer_faces() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let negative: Result<Embedding, ServiceError> =
+        canister.update(controller(), "add_negative", encode_args(("Not Lain", read(NON_LAIN_IMAGE))).unwrap());
+    negative.expect("Failed to add the negative reference");
+
+    let conflict: Result<Embedding, String> =
+        canister.update(controller(), "add", encode_args(("Not Lain", read(REFERENCE_IMAGE))).unwrap());
+    assert!(conflict.is_err());
+
+    let ranking: Result<Ranking, String> =
+        canister.update(creator(), "recognize_topk", encode_args((read(NON_LAIN_IMAGE), 2u32)).unwrap());
+    let ranking = ranking.expect("Ranking failed");
+    assert_eq!(ranking.matches[0].label, "Not Lain");
+    assert!(ranking.matches[0].negative);
+    assert!(!ranking.matches[1].negative);
+
+    let person: Result<Person, String> =
+        canister.update(creator(), "recognize", encode_one(read(NON_LAIN_IMAGE)).unwrap());
+    assert_eq!(person.err(), Some("Unknown person".to_string()));
+    let person: Result<Person, String> =
+        canister.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    assert_eq!(person.expect("Recognition failed").label, "Lain");
+}
+
+#[test]
+fn closest_non_lain_face_is_rejected() {
+    let canister = Canister::install();
+    canister.upload_models();
+    let mut references: Vec<PathBuf> = std::fs::read_dir(repo_path("laindb"))
+        .expect("Failed to list laindb")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    references.sort();
+    for path in references {
+        let _: Result<Embedding, String> =
+            canister.update(controller(), "add", encode_args(("Lain", std::fs::read(path).unwrap())).unwrap());
+    }
+
+    // The non-Lain face closest to the references is the one nearest the
+    // threshold; it must still be outside of it.
+    let closest = std::fs::read_dir(repo_path("laindb/nonlains"))
+        .expect("Failed to list laindb/nonlains")
+        .filter_map(|entry| {
+            let image = std::fs::read(entry.unwrap().path()).unwrap();
+            let ranking: Result<Ranking, String> =
+                canister.update(creator(), "recognize_topk", encode_args((image.clone(), 1u32)).unwrap());
+            ranking.ok().map(|ranking| (ranking.matches[0].score, image))
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0));
+    let (score, image) = closest.expect("No face found in laindb/nonlains");
+    assert!(score > THRESHOLD, "A non-Lain face is at {} of the references", score);
+    let person: Result<Person, String> = canister.update(creator(), "recognize", encode_one(image).unwrap());
+    assert_eq!(person.err(), Some("Unknown person".to_string()));
+}
+
+#[test]
+fn reembed_all_recomputes_references_in_batches() {
+    let canister = Canister::install();
+    canister.upload_models();
+    for _ in 0..5 {
+        canister.enroll("Lain", REFERENCE_IMAGE);
+    }
+
+    let status: ReembedStatus = canister.query("get_reembed_status", encode_args(()).unwrap());
+    assert_eq!(status.stale, 0);
+    assert!(status.model_version.is_some());
+
+    let started: Result<ReembedStatus, ServiceError> = canister.update(controller(), "reembed_all", encode_args(()).unwrap());
+    let started = started.expect("Re-embedding did not start");
+    assert!(started.running);
+    assert_eq!(started.remaining, 5);
+    let again: Result<ReembedStatus, ServiceError> = canister.update(controller(), "reembed_all", encode_args(()).unwrap());
+    assert!(again.is_err());
+
+    for _ in 0..20 {
+        canister.pic.tick();
+    }
+    let status: ReembedStatus = canister.query("get_reembed_status", encode_args(()).unwrap());
+    assert!(!status.running);
+    assert_eq!(status.reembedded, 5);
+    assert_eq!(status.remaining, 0);
+    assert!(status.failures.is_empty(), "{:?}", status.failures);
+
+    let person: Result<Person, String> =
+        canister.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    assert_eq!(person.expect("Recognition failed after re-embedding").label, "Lain");
+}
+
+#[test]
+fn whole_image_references_are_not_compared() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let page: FacePage = canister.update(controller(), "export_faces", encode_args((0u64, 10u32)).unwrap());
+    assert_eq!(page.snapshot.faces[0].pipeline, Some(2));
+
+    // A snapshot from before the pipeline was recorded, without the image.
+    let mut faces = page.snapshot.faces;
+    faces[0].pipeline = None;
+    let snapshot = FaceSnapshot { version: 1, faces };
+    let summary: Result<ImportSummary, ServiceError> =
+        canister.update(controller(), "import_faces", encode_args((&snapshot, ImportMode::Replace)).unwrap());
+    assert_eq!(summary, Ok(ImportSummary { imported: 1, skipped: 0 }));
+
+    let status: ReembedStatus = canister.query("get_reembed_status", encode_args(()).unwrap());
+    assert_eq!((status.stale, status.without_image), (1, 1));
+    let person: Result<Person, String> =
+        canister.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    let err = person.expect_err("A whole-image reference was compared");
+    assert!(err.contains("1 references must be re-embedded"), "{}", err);
+}
+
+/// A uniformly gray PNG, which shows no face.
+fn blank_png() -> Vec<u8> {
+    let image = image::RgbImage::from_pixel(320, 240, image::Rgb([128, 128, 128]));
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png).unwrap();
+    png.into_inner()
+}
+
+#[test]
+fn batch_enrollment_reports_failures_per_image() {
+    let canister = Canister::install();
+    canister.upload_models();
+
+    let batch: Result<u64, ServiceError> = canister.update(controller(), "start_enrollment", encode_args(("Lain", false)).unwrap());
+    let batch = batch.expect("Failed to start the batch");
+    let images = [
+        ("02.png", read(REFERENCE_IMAGE)),
+        ("blank.png", blank_png()),
+        ("broken.png", b"not an image".to_vec()),
+    ];
+    for (name, image) in &images {
+        for (index, chunk) in image.chunks(ARTWORK_CHUNK_SIZE).enumerate() {
+            let result: Result<(), ServiceError> = canister.update(
+                controller(),
+                "put_enrollment_chunk",
+                encode_args((batch, *name, index as u32, chunk.to_vec())).unwrap(),
+            );
+            result.expect("Failed to upload a chunk");
+        }
+    }
+    let committed: Result<EnrollmentReport, ServiceError> =
+        canister.update(controller(), "commit_enrollment", encode_one(batch).unwrap());
+    assert_eq!(committed.expect("Failed to commit").remaining, 3);
+
+    for _ in 0..20 {
+        canister.pic.tick();
+    }
+    let report: Option<EnrollmentReport> =
+        canister.update(controller(), "get_enrollment_report", encode_one(batch).unwrap());
+    let report = report.expect("Batch not found");
+    assert_eq!(report.remaining, 0);
+    assert_eq!(report.enrolled.len(), 1);
+    assert_eq!(report.enrolled[0].name, "02.png");
+    let failures: Vec<(&str, &str)> =
+        report.failures.iter().map(|failure| (failure.name.as_str(), failure.error.as_str())).collect();
+    assert_eq!(failures.len(), 2, "{:?}", failures);
+    assert!(failures.iter().any(|(name, error)| *name == "blank.png" && error.contains("No face detected")));
+    assert!(failures.iter().any(|(name, error)| *name == "broken.png" && error.starts_with("Invalid image")));
+
+    let count: Nat = canister.query("get_face_count", encode_args(()).unwrap());
+    assert_eq!(count, Nat::from(1u64));
+}
+
+#[test]
+fn face_snapshots_round_trip() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    canister.enroll("Lain", NON_LAIN_IMAGE);
+    let negative: Result<Embedding, ServiceError> =
+        canister.update(controller(), "add_negative", encode_args(("Not Lain", read(NON_LAIN_IMAGE))).unwrap());
+    negative.expect("Failed to add the negative reference");
+
+    let mut faces = Vec::new();
+    let mut start = Some(0u64);
+    let mut pages = 0;
+    while let Some(from) = start {
+        let page: FacePage = canister.update(controller(), "export_faces", encode_args((from, 2u32)).unwrap());
+        faces.extend(page.snapshot.faces);
+        start = page.next;
+        pages += 1;
+    }
+    assert_eq!(pages, 2);
+    assert_eq!(faces.len(), 3);
+    assert!(faces.iter().all(|face| face.model_version.is_some() && face.image_sha256.is_some()));
+    assert_eq!(faces[0].image_sha256.as_deref(), Some(Sha256::digest(read(REFERENCE_IMAGE)).as_slice()));
+    assert!(faces[2].negative);
+
+    let snapshot = FaceSnapshot { version: 1, faces };
+    let bytes = encode_one(&snapshot).unwrap();
+    let decoded: FaceSnapshot = decode_one(&bytes).unwrap();
+    assert_eq!(decoded, snapshot);
+
+    let summary: Result<ImportSummary, ServiceError> =
+        canister.update(controller(), "import_faces", encode_args((&snapshot, ImportMode::Merge)).unwrap());
+    assert_eq!(summary, Ok(ImportSummary { imported: 0, skipped: 3 }));
+
+    let staging = Canister::install();
+    staging.upload_models();
+    // Imported page by page, the references keep their IDs.
+    let first = FaceSnapshot { version: 1, faces: decoded.faces[..2].to_vec() };
+    let rest = FaceSnapshot { version: 1, faces: decoded.faces[2..].to_vec() };
+    let summary: Result<ImportSummary, ServiceError> =
+        staging.update(controller(), "import_faces", encode_args((&first, ImportMode::Replace)).unwrap());
+    assert_eq!(summary, Ok(ImportSummary { imported: 2, skipped: 0 }));
+    let summary: Result<ImportSummary, ServiceError> =
+        staging.update(controller(), "import_faces", encode_args((&rest, ImportMode::Append)).unwrap());
+    assert_eq!(summary, Ok(ImportSummary { imported: 1, skipped: 0 }));
+    let summary: Result<ImportSummary, ServiceError> =
+        staging.update(controller(), "import_faces", encode_args((&rest, ImportMode::Append)).unwrap());
+    assert_eq!(summary, Err(ServiceError::Failed(format!("Reference {} already exists", rest.faces[0].id))));
+    let page: FacePage = staging.update(controller(), "export_faces", encode_args((0u64, 10u32)).unwrap());
+    let imported: Vec<_> = page.snapshot.faces.iter().map(|face| (face.id, &face.label, face.negative, &face.embedding)).collect();
+    let exported: Vec<_> = snapshot.faces.iter().map(|face| (face.id, &face.label, face.negative, &face.embedding)).collect();
+    assert_eq!(imported, exported);
+
+    let person: Result<Person, String> =
+        staging.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    assert_eq!(person.expect("Recognition failed after import").label, "Lain");
+}
+
+#[test]
+fn reference_ids_of_removed_faces_are_not_reused() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    canister.enroll("Other", REFERENCE_IMAGE);
+    canister.update_unit(controller(), "remove_face", encode_one("Other").unwrap());
+    canister.enroll("Another", REFERENCE_IMAGE);
+
+    let page: FacePage = canister.update(controller(), "export_faces", encode_args((0u64, 10u32)).unwrap());
+    let ids: Vec<(u64, String)> = page.snapshot.faces.into_iter().map(|face| (face.id, face.label)).collect();
+    assert_eq!(ids, vec![(0, "Lain".to_string()), (2, "Another".to_string())]);
+}
+
+#[test]
+fn backup_restores_into_a_fresh_canister() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let artwork_id = canister.verify(creator(), id).expect("Verification failed");
+
+    let manifest: Result<BackupManifest, String> =
+        canister.update(controller(), "start_backup", encode_args(()).unwrap());
+    let manifest = manifest.expect("Failed to start the backup");
+    assert_eq!(manifest.chunks, manifest.size.div_ceil(manifest.chunk_size));
+    assert_eq!(manifest.checksums.len() as u64, manifest.chunks);
+
+    // Writes are paused while the backup runs.
+    let result = canister.pic.update_call(canister.id, creator(), "start_submission", encode_args(()).unwrap());
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+
+    let chunks: Vec<BackupChunk> = (0..manifest.chunks)
+        .map(|index| {
+            let chunk: Result<BackupChunk, String> = canister.update(
+                controller(),
+                "get_backup_chunk",
+                encode_args((manifest.backup_id, index)).unwrap(),
+            );
+            chunk.expect("Failed to read a chunk")
+        })
+        .collect();
+    let result: Result<(), String> =
+        canister.update(controller(), "finish_backup", encode_one(manifest.backup_id).unwrap());
+    assert_eq!(result, Ok(()));
+    canister.submit(creator(), REFERENCE_IMAGE);
+
+    // A restore needs a canister without any state, models included.
+    let not_empty = Canister::install();
+    not_empty.upload_models();
+    let result: Result<(), String> = not_empty.update(controller(), "start_restore", encode_one(&manifest).unwrap());
+    assert_eq!(result, Err("Backups can only be restored into an empty canister".to_string()));
+
+    let restored = Canister::install();
+    let result: Result<(), String> = restored.update(controller(), "start_restore", encode_one(&manifest).unwrap());
+    assert_eq!(result, Ok(()));
+    let mut corrupted = chunks[0].clone();
+    corrupted.data[0] ^= 0xff;
+    let result: Result<(), String> = restored.update(controller(), "restore_chunk", encode_one(corrupted).unwrap());
+    assert_eq!(result, Err("Checksum mismatch in chunk 0".to_string()));
+    let result: Result<u64, String> = restored.update(controller(), "finish_restore", encode_args(()).unwrap());
+    assert!(result.is_err());
+
+    // Nothing else may write until the restored state is loaded.
+    let result = restored.pic.update_call(restored.id, creator(), "start_submission", encode_args(()).unwrap());
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+    let result =
+        restored.pic.update_call(restored.id, controller(), "add_reviewer", encode_one(reviewer()).unwrap());
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+
+    for chunk in chunks.iter().cloned() {
+        let result: Result<(), String> = restored.update(controller(), "restore_chunk", encode_one(chunk).unwrap());
+        assert_eq!(result, Ok(()));
+    }
+    let result: Result<u64, String> = restored.update(controller(), "finish_restore", encode_args(()).unwrap());
+    assert_eq!(result, Ok(manifest.chunks));
+    let result: Result<u64, String> = restored.update(controller(), "finish_restore", encode_args(()).unwrap());
+    assert_eq!(result, Err("The restore is finished; upgrade the canister to load the restored state".to_string()));
+    let result: Result<(), String> =
+        restored.update(controller(), "restore_chunk", encode_one(chunks[0].clone()).unwrap());
+    assert!(result.is_err());
+    let result = restored.pic.update_call(restored.id, creator(), "start_submission", encode_args(()).unwrap());
+    assert!(!matches!(result, Ok(WasmResult::Reply(_))));
+    restored.upgrade();
+
+    let artwork: Option<ApprovedArtwork> = restored.query("get_artwork_by_id", encode_one(artwork_id).unwrap());
+    assert_eq!(artwork.expect("Gallery was not restored").creator, creator());
+    let faces: Vec<String> = restored.query("list_stored_faces", encode_args(()).unwrap());
+    assert_eq!(faces, vec!["Lain".to_string()]);
+    restored.setup_models();
+    let person: Result<Person, String> =
+        restored.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    assert_eq!(person.expect("Recognition failed after restore").label, "Lain");
+    restored.submit(creator(), REFERENCE_IMAGE);
+
+    let result: Result<(), String> = restored.update(controller(), "start_restore", encode_one(&manifest).unwrap());
+    assert!(result.is_err(), "Restored into a canister with state");
+}
+
+#[test]
+fn new_canisters_have_the_current_schema() {
+    let canister = Canister::install();
+    let version: u32 = canister.query("get_schema_version", encode_args(()).unwrap());
+    assert_eq!(version, 4);
+
+    canister.upgrade();
+    let version: u32 = canister.query("get_schema_version", encode_args(()).unwrap());
+    assert_eq!(version, 4);
+}
+
+#[test]
+#[ignore = "needs LAIN_ART_BACKEND_PREVIOUS_WASM"]
+fn upgrade_from_the_previous_release_migrates_state() {
+    let canister = Canister::install_wasm(previous_backend_wasm());
+    let config = FeeConfig { amount: Nat::from(0u64), ..FeeConfig::default() };
+    canister.update_unit(controller(), "set_fee_config", encode_one(config).unwrap());
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let artwork_id = canister.verify(creator(), id).expect("Verification failed");
+
+    canister.upgrade();
+    let version: u32 = canister.query("get_schema_version", encode_args(()).unwrap());
+    assert_eq!(version, 4);
+    let artwork: Option<ApprovedArtwork> = canister.query("get_artwork_by_id", encode_one(artwork_id).unwrap());
+    assert_eq!(artwork.expect("Gallery lost on upgrade").creator, creator());
+    let faces: Vec<String> = canister.query("list_stored_faces", encode_args(()).unwrap());
+    assert_eq!(faces, vec!["Lain".to_string()]);
+
+    // Migrations run once; a second upgrade finds the current schema.
+    canister.upgrade();
+    let count: Nat = canister.query("get_face_count", encode_args(()).unwrap());
+    assert_eq!(count, Nat::from(1u64));
+    canister.setup_models();
+
+    // The reference of the previous release is flagged until it is re-embedded.
+    let status: ReembedStatus = canister.query("get_reembed_status", encode_args(()).unwrap());
+    assert_eq!((status.stale, status.without_image), (1, 0));
+    let started: Result<ReembedStatus, ServiceError> = canister.update(controller(), "reembed_all", encode_args(()).unwrap());
+    assert!(started.is_ok(), "{:?}", started);
+    for _ in 0..10 {
+        canister.pic.tick();
+    }
+    let person: Result<Person, String> =
+        canister.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    assert_eq!(person.expect("Recognition failed after upgrade").label, "Lain");
+}
+
+#[test]
+fn prepare_upgrade_pauses_submissions_and_canary_passes_after_upgrade() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let canary: Result<(), String> =
+        canister.update(controller(), "set_canary", encode_args((read(REFERENCE_IMAGE), "Lain")).unwrap());
+    assert_eq!(canary, Ok(()));
+    let canary: Result<(), String> =
+        canister.update(controller(), "set_canary", encode_args((read(REFERENCE_IMAGE), "Other")).unwrap());
+    assert!(canary.is_err());
+
+    // Uploaded but not yet verified: the image only lives in the upload buffer.
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+
+    let readiness: UpgradeReadiness = canister.update(controller(), "prepare_upgrade", encode_args(()).unwrap());
+    assert!(readiness.submissions_paused);
+    assert_eq!(readiness.flushed_uploads, 1);
+    assert!(readiness.ready, "{:?}", readiness.blockers);
+    assert!(canister.pic.update_call(canister.id, creator(), "start_submission", encode_args(()).unwrap()).is_err());
+    assert_eq!(canister.verify(creator(), id.clone()), Err("Submissions are paused for an upgrade".to_string()));
+
+    canister.upgrade();
+    for _ in 0..5 {
+        canister.pic.tick();
+    }
+    let self_test: Option<SelfTest> = canister.query("get_self_test", encode_args(()).unwrap());
+    let self_test = self_test.expect("No self-test after the upgrade");
+    assert!(self_test.passed, "{:?}", self_test.error);
+    assert_eq!(self_test.label.as_deref(), Some("Lain"));
+
+    // Submissions resume and the flushed upload is still there.
+    let artwork_id = canister.verify(creator(), id);
+    assert!(artwork_id.is_ok(), "{:?}", artwork_id);
+}
+
+#[test]
+fn uploads_survive_an_upgrade_without_preparation() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+
+    canister.upgrade();
+    canister.setup_models();
+
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+        .unwrap();
+    assert_eq!(submission.status, Status::Verifying);
+    let artwork_id = canister.verify(creator(), id);
+    assert!(artwork_id.is_ok(), "{:?}", artwork_id);
+}
+
+#[test]
+fn only_open_uploads_are_flushed() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+
+    let status = Status::Verified { originality: 5_000, visibility: 5_000 };
+    for _ in 0..5 {
+        let id = canister.submit(creator(), REFERENCE_IMAGE);
+        let result: Result<(), ServiceError> =
+            canister.update(controller(), "override_verdict", encode_args((id, status.clone(), "approved")).unwrap());
+        assert_eq!(result, Ok(()));
+    }
+    // The image of a rejection is kept for an appeal outside the upload buffer.
+    let rejected = canister.submit(creator(), NON_LAIN_IMAGE);
+    assert!(canister.verify(creator(), rejected.clone()).is_err());
+    let open = canister.submit(creator(), REFERENCE_IMAGE);
+
+    let readiness: UpgradeReadiness = canister.update(controller(), "prepare_upgrade", encode_args(()).unwrap());
+    assert_eq!(readiness.flushed_uploads, 1);
+
+    canister.upgrade();
+    canister.setup_models();
+    let result: Result<(), ServiceError> =
+        canister.update(creator(), "appeal_rejection", encode_args((rejected, "It is Lain")).unwrap());
+    assert_eq!(result, Ok(()));
+    let submission: Submission = canister
+        .query::<Option<Submission>>("get_submission", encode_one(open).unwrap())
+        .unwrap();
+    assert_eq!(submission.status, Status::Verifying);
+}
+
+#[test]
+fn pause_switches_stop_endpoints_until_resumed() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let pause = |switch: PauseSwitch, reason: &str| {
+        let result: Result<(), String> = canister.update(controller(), "pause", encode_args((switch, reason)).unwrap());
+        assert_eq!(result, Ok(()));
+    };
+    let not_allowed = canister.pic.update_call(
+        canister.id,
+        creator(),
+        "pause",
+        encode_args((PauseSwitch::Rewards, "No")).unwrap(),
+    );
+    assert!(!matches!(not_allowed, Ok(WasmResult::Reply(_))));
+
+    // A queued verification waits while verification is paused.
+    let queued = canister.submit(creator(), REFERENCE_IMAGE);
+    let result: Result<(), ServiceError> = canister.update(creator(), "trigger_verification", encode_one(queued.clone()).unwrap());
+    assert_eq!(result, Ok(()));
+    pause(PauseSwitch::Verification, "The model approves everything");
+    for _ in 0..5 {
+        canister.pic.tick();
+    }
+    let status = |id: &Nat| {
+        canister
+            .query::<Option<Submission>>("get_submission", encode_one(id.clone()).unwrap())
+            .unwrap()
+            .status
+    };
+    assert_eq!(status(&queued), Status::Processing { step: VerificationStep::Decode });
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    assert_eq!(canister.verify(creator(), id.clone()), Err("Paused: The model approves everything".to_string()));
+    let result: Result<(), ServiceError> = canister.update(creator(), "trigger_verification", encode_one(id.clone()).unwrap());
+    let paused = Paused { switch: Some(PauseSwitch::Verification), reason: "The model approves everything".to_string() };
+    assert_eq!(result, Err(ServiceError::Paused(paused.clone())));
+    // Review verdicts and appeals, which approve artwork and trigger payouts, wait as well.
+    let result: Result<(), ServiceError> = canister.update(
+        controller(),
+        "set_verdict",
+        encode_args((id.clone(), 5_000u16, 5_000u16, "Lain")).unwrap(),
+    );
+    assert_eq!(result, Err(ServiceError::Paused(paused.clone())));
+    let approved = Status::Verified { originality: 5_000, visibility: 5_000 };
+    let result: Result<(), ServiceError> =
+        canister.update(controller(), "override_verdict", encode_args((id.clone(), approved, "Lain")).unwrap());
+    assert_eq!(result, Err(ServiceError::Paused(paused.clone())));
+    let result: Result<(), ServiceError> =
+        canister.update(creator(), "appeal_rejection", encode_args((id.clone(), "It is Lain")).unwrap());
+    assert_eq!(result, Err(ServiceError::Paused(paused)));
+
+    pause(PauseSwitch::Submissions, "Maintenance");
+    assert!(canister.pic.update_call(canister.id, creator(), "start_submission", encode_args(()).unwrap()).is_err());
+    pause(PauseSwitch::Enrollment, "Maintenance");
+    let added: Result<Embedding, String> =
+        canister.update(controller(), "add", encode_args(("Lain", read(REFERENCE_IMAGE))).unwrap());
+    assert_eq!(added.err(), Some("Paused: Maintenance".to_string()));
+    let paused = ServiceError::Paused(Paused { switch: Some(PauseSwitch::Enrollment), reason: "Maintenance".to_string() });
+    let negative: Result<Embedding, ServiceError> =
+        canister.update(controller(), "add_negative", encode_args(("Not Lain", read(NON_LAIN_IMAGE))).unwrap());
+    assert_eq!(negative.err(), Some(paused.clone()));
+    let reembed: Result<ReembedStatus, ServiceError> = canister.update(controller(), "reembed_all", encode_args(()).unwrap());
+    assert_eq!(reembed.err(), Some(paused));
+    let removed = canister.pic.update_call(canister.id, controller(), "remove_face", encode_one("Lain").unwrap());
+    assert!(removed.is_err());
+    assert_eq!(canister.query::<Nat>("get_face_count", encode_args(()).unwrap()), Nat::from(1u64));
+
+    // The switches survive upgrades.
+    canister.upgrade();
+    let pauses: PauseStatus = canister.query("get_pause_status", encode_args(()).unwrap());
+    assert_eq!(pauses.verification.map(|pause| pause.reason), Some("The model approves everything".to_string()));
+    assert!(pauses.submissions.is_some() && pauses.enrollment.is_some());
+    assert_eq!(pauses.rewards, None);
+
+    for switch in [PauseSwitch::Submissions, PauseSwitch::Verification, PauseSwitch::Enrollment] {
+        canister.update_unit(controller(), "resume", encode_one(switch).unwrap());
+    }
+    assert_eq!(canister.query::<PauseStatus>("get_pause_status", encode_args(()).unwrap()), PauseStatus::default());
+    // The upgrade moved the waiting submission back to `Verifying`.
+    let result: Result<(), ServiceError> = canister.update(creator(), "trigger_verification", encode_one(queued.clone()).unwrap());
+    assert_eq!(result, Ok(()));
+    for _ in 0..20 {
+        canister.pic.tick();
+    }
+    assert!(matches!(status(&queued), Status::Verified { .. }), "{:?}", status(&queued));
+}
+
+#[test]
+fn unknown_submission_is_rejected() {
+    let canister = Canister::install();
+
+    let result = canister.verify(creator(), Nat::from(42u64));
+    assert_eq!(result, Err("Submission ID not found".to_string()));
+}
+
+#[test]
+fn face_database_models_and_gallery_survive_upgrade() {
+    let canister = Canister::install();
+    canister.upload_models();
+    canister.enroll("Lain", REFERENCE_IMAGE);
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let artwork_id = canister.verify(creator(), id).expect("Verification failed");
+
+    canister.upgrade();
+
+    let artwork: Option<ApprovedArtwork> = canister.query("get_artwork_by_id", encode_one(artwork_id).unwrap());
+    assert_eq!(artwork.expect("Gallery lost on upgrade").creator, creator());
+
+    let faces: Vec<String> = canister.query("list_stored_faces", encode_args(()).unwrap());
+    assert_eq!(faces, vec!["Lain".to_string()]);
+    let count: Nat = canister.query("get_face_count", encode_args(()).unwrap());
+    assert_eq!(count, Nat::from(1u64));
+
+    // Models live in stable memory and only need to be loaded again.
+    canister.setup_models();
+    let person: Result<Person, String> =
+        canister.update(creator(), "recognize", encode_one(read(REFERENCE_IMAGE)).unwrap());
+    assert_eq!(person.expect("Recognition failed after upgrade").label, "Lain");
+
+    let id = canister.submit(creator(), REFERENCE_IMAGE);
+    let second_id = canister.verify(creator(), id).expect("Verification failed after upgrade");
+    assert_ne!(second_id, artwork_id);
+}
@@ -0,0 +1,251 @@
+//! Bulk enrollment of reference faces.
+//!
+//! Enrolls every image of a directory under one identity with the chunked
+//! batch enrollment API of the canister (`start_enrollment`,
+//! `put_enrollment_chunk`, `commit_enrollment`) and reports the images that
+//! could not be enrolled, e.g. because no face was detected.
+//!
+//! ```bash
+//! dfx identity export <your-id> > identity.pem
+//! cargo run --release -p lain_art_enroll -- --identity identity.pem --label Lain laindb/lains
+//! ```
+
+use anyhow::{anyhow, Context};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+use ic_agent::{Agent, Identity};
+use lain_art_backend::EnrollmentReport;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: lain_art_enroll [OPTIONS] --identity <PEM> <DIR>
+
+Enrolls the PNG and JPEG images of <DIR> as references of one identity.
+
+Options:
+  --identity <PEM>      Controller identity (`dfx identity export <id>`)
+  --label <LABEL>       Identity to enroll the images under [default: Lain]
+  --negative            Enroll the images as a negative (non-Lain) identity
+  --canister <ID>       Backend canister [default: kfp4o-2qaaa-aaaab-qcmsa-cai]
+  --url <URL>           Replica URL [default: https://ic0.app]
+  --batch-size <N>      Images per enrollment batch [default: 16]
+  -h, --help            Print this help
+";
+
+// Chunks have to stay below the 2MB ingress message limit.
+const CHUNK_SIZE: usize = 1_000_000;
+
+// Mirrors the limit of the canister on the size of a batch.
+const MAX_BATCH_BYTES: usize = 32 * 1024 * 1024;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Args {
+    identity: PathBuf,
+    label: String,
+    negative: bool,
+    canister: String,
+    url: String,
+    batch_size: usize,
+    dir: PathBuf,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut identity = None;
+        let mut dir = None;
+        let mut args = Args {
+            identity: PathBuf::new(),
+            label: "Lain".to_string(),
+            negative: false,
+            canister: "kfp4o-2qaaa-aaaab-qcmsa-cai".to_string(),
+            url: "https://ic0.app".to_string(),
+            batch_size: 16,
+            dir: PathBuf::new(),
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(flag) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .ok_or_else(|| anyhow!("Missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "--identity" => identity = Some(value()?.into()),
+                "--label" => args.label = value()?,
+                "--negative" => args.negative = true,
+                "--canister" => args.canister = value()?,
+                "--url" => args.url = value()?,
+                "--batch-size" => {
+                    args.batch_size = value()?.parse().context("Invalid batch size")?;
+                    if args.batch_size == 0 {
+                        return Err(anyhow!("The batch size must not be zero"));
+                    }
+                }
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                other if other.starts_with('-') => return Err(anyhow!("Unknown argument '{}'\n\n{}", other, USAGE)),
+                other => dir = Some(other.into()),
+            }
+        }
+        args.identity = identity.ok_or_else(|| anyhow!("Missing --identity\n\n{}", USAGE))?;
+        args.dir = dir.ok_or_else(|| anyhow!("Missing the image directory\n\n{}", USAGE))?;
+        Ok(args)
+    }
+}
+
+/// Loads a PEM identity as exported by dfx: secp256k1 or Ed25519.
+fn load_identity(path: &Path) -> anyhow::Result<Box<dyn Identity>> {
+    if let Ok(identity) = Secp256k1Identity::from_pem_file(path) {
+        return Ok(Box::new(identity));
+    }
+    let identity = BasicIdentity::from_pem_file(path)
+        .map_err(|err| anyhow!("Failed to load the identity {}: {}", path.display(), err))?;
+    Ok(Box::new(identity))
+}
+
+/// Returns the image files of a directory, sorted by name.
+fn image_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+                .unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Splits the images into batches of at most `batch_size` images and `MAX_BATCH_BYTES`.
+fn batches(images: Vec<(String, Vec<u8>)>, batch_size: usize) -> Vec<Vec<(String, Vec<u8>)>> {
+    let mut batches: Vec<Vec<(String, Vec<u8>)>> = Vec::new();
+    let mut size = 0;
+    for image in images {
+        let full = batches
+            .last()
+            .is_none_or(|batch| batch.len() >= batch_size || size + image.1.len() > MAX_BATCH_BYTES);
+        if full {
+            batches.push(Vec::new());
+            size = 0;
+        }
+        size += image.1.len();
+        batches.last_mut().expect("a batch is open").push(image);
+    }
+    batches
+}
+
+struct Backend {
+    agent: Agent,
+    canister: Principal,
+}
+
+impl Backend {
+    async fn update<T: CandidType + DeserializeOwned>(&self, method: &str, args: Vec<u8>) -> anyhow::Result<T> {
+        let reply = self
+            .agent
+            .update(&self.canister, method)
+            .with_arg(args)
+            .call_and_wait()
+            .await
+            .with_context(|| format!("{} failed", method))?;
+        Ok(Decode!(&reply, T)?)
+    }
+
+    async fn query<T: CandidType + DeserializeOwned>(&self, method: &str, args: Vec<u8>) -> anyhow::Result<T> {
+        let reply = self
+            .agent
+            .query(&self.canister, method)
+            .with_arg(args)
+            .call()
+            .await
+            .with_context(|| format!("{} failed", method))?;
+        Ok(Decode!(&reply, T)?)
+    }
+
+    /// Uploads and commits one batch, and waits until all its images are processed.
+    async fn enroll(&self, args: &Args, images: &[(String, Vec<u8>)]) -> anyhow::Result<EnrollmentReport> {
+        let batch: Result<u64, String> =
+            self.update("start_enrollment", Encode!(&args.label, &args.negative)?).await?;
+        let batch = batch.map_err(|err| anyhow!(err))?;
+        for (name, image) in images {
+            for (index, chunk) in image.chunks(CHUNK_SIZE).enumerate() {
+                let result: Result<(), String> = self
+                    .update("put_enrollment_chunk", Encode!(&batch, name, &(index as u32), &chunk.to_vec())?)
+                    .await?;
+                result.map_err(|err| anyhow!("{}: {}", name, err))?;
+            }
+        }
+        let committed: Result<EnrollmentReport, String> =
+            self.update("commit_enrollment", Encode!(&batch)?).await?;
+        committed.map_err(|err| anyhow!(err))?;
+
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let report: Option<EnrollmentReport> = self.query("get_enrollment_report", Encode!(&batch)?).await?;
+            let report = report.ok_or_else(|| anyhow!("Enrollment batch {} was lost", batch))?;
+            if report.remaining == 0 {
+                return Ok(report);
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+
+    let agent = Agent::builder()
+        .with_url(&args.url)
+        .with_boxed_identity(load_identity(&args.identity)?)
+        .build()?;
+    if !args.url.starts_with("https://ic0.app") && !args.url.starts_with("https://icp0.io") {
+        agent.fetch_root_key().await.context("Failed to fetch the root key of the replica")?;
+    }
+    let canister = Principal::from_text(&args.canister).context("Invalid canister ID")?;
+    let backend = Backend { agent, canister };
+
+    let mut images = Vec::new();
+    let mut failed = 0;
+    for path in image_files(&args.dir)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        match fs::read(&path) {
+            Ok(bytes) => images.push((name, bytes)),
+            Err(err) => {
+                println!("✗ {}: {}", name, err);
+                failed += 1;
+            }
+        }
+    }
+    let total = images.len() + failed;
+    if total == 0 {
+        return Err(anyhow!("No images in {}", args.dir.display()));
+    }
+
+    let mut enrolled = 0;
+    for batch in batches(images, args.batch_size) {
+        let report = backend.enroll(&args, &batch).await?;
+        for image in &report.enrolled {
+            println!("✓ {}: reference {}", image.name, image.reference_id);
+        }
+        for failure in &report.failures {
+            println!("✗ {}: {}", failure.name, failure.error);
+        }
+        enrolled += report.enrolled.len();
+        failed += report.failures.len();
+    }
+
+    println!("\nEnrolled {} of {} images as '{}'", enrolled, total, args.label);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,331 @@
+//! Offline evaluation of the lain.art verification pipeline.
+//!
+//! Runs the same detection, embedding and matching code as the canister on the
+//! `laindb` folders and reports accuracy, precision/recall, the ROC curve of the
+//! recognition distance and a recommended `THRESHOLD`. Like the canister, only
+//! the detected face is embedded, and faces closer to a negative reference than
+//! to the Lain references by more than `NEGATIVE_MARGIN` are rejected.
+//!
+//! ```bash
+//! cargo run --release -p lain_art_eval -- \
+//!     --references laindb --positives laindb/lains --negatives laindb/nonlains \
+//!     --classifier lain_mobilenetv3.onnx --roc roc.csv
+//! ```
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use image::RgbImage;
+use lain_art_backend::onnx::{self, Detector, IdentityMatch, Metric, Network, THRESHOLD};
+use lain_art_backend::preprocess::PreprocessSpec;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod metrics;
+
+use metrics::Sample;
+
+const USAGE: &str = "\
+Usage: lain_art_eval [OPTIONS]
+
+Options:
+  --detector <FILE>     Face detection model [default: version-RFB-320.onnx]
+  --recognizer <FILE>   Face recognition model [default: face-recognition.onnx]
+  --classifier <FILE>   Optional Lain classifier model (e.g. lain_mobilenetv3.onnx)
+  --references <DIR>    Reference faces enrolled as \"Lain\" [default: laindb]
+  --positives <DIR>     Images that must be recognized [default: laindb/lains]
+  --negatives <DIR>     Images that must be rejected [default: laindb/nonlains]
+  --negative-references <DIR>
+                        Faces enrolled as negative (non-Lain) references [default: none]
+  --threshold <FLOAT>   Threshold to report metrics for [default: canister THRESHOLD]
+  --metric <METRIC>     Embedding metric: euclidean, cosine or dot [default: euclidean]
+  --roc <FILE>          Write the ROC curve as CSV
+  -h, --help            Print this help
+";
+
+struct Args {
+    detector: PathBuf,
+    recognizer: PathBuf,
+    classifier: Option<PathBuf>,
+    references: PathBuf,
+    positives: PathBuf,
+    negatives: PathBuf,
+    negative_references: Option<PathBuf>,
+    threshold: f32,
+    metric: Metric,
+    roc: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut args = Args {
+            detector: "version-RFB-320.onnx".into(),
+            recognizer: "face-recognition.onnx".into(),
+            classifier: None,
+            references: "laindb".into(),
+            positives: "laindb/lains".into(),
+            negatives: "laindb/nonlains".into(),
+            negative_references: None,
+            threshold: THRESHOLD,
+            metric: Metric::default(),
+            roc: None,
+        };
+
+        let mut iter = std::env::args().skip(1);
+        while let Some(flag) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .ok_or_else(|| anyhow!("Missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "--detector" => args.detector = value()?.into(),
+                "--recognizer" => args.recognizer = value()?.into(),
+                "--classifier" => args.classifier = Some(value()?.into()),
+                "--references" => args.references = value()?.into(),
+                "--positives" => args.positives = value()?.into(),
+                "--negatives" => args.negatives = value()?.into(),
+                "--negative-references" => args.negative_references = Some(value()?.into()),
+                "--threshold" => args.threshold = value()?.parse().context("Invalid threshold")?,
+                "--metric" => {
+                    args.metric = match value()?.as_str() {
+                        "euclidean" => Metric::Euclidean,
+                        "cosine" => Metric::Cosine,
+                        "dot" => Metric::Dot,
+                        other => return Err(anyhow!("Unknown metric '{}'", other)),
+                    }
+                }
+                "--roc" => args.roc = Some(value()?.into()),
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                other => return Err(anyhow!("Unknown argument '{}'\n\n{}", other, USAGE)),
+            }
+        }
+        Ok(args)
+    }
+}
+
+struct Models {
+    detector: Detector,
+    recognizer: Network,
+    classifier: Option<Network>,
+    metric: Metric,
+}
+
+/// A reference face, kept with its source bytes so that an image is never
+/// matched against itself (the reference set overlaps with `laindb/lains`).
+struct Reference {
+    bytes: Vec<u8>,
+    embedding: Vec<f32>,
+    negative: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+
+    let models = Models {
+        detector: load_detector(&args.detector)?,
+        recognizer: load_network(&args.recognizer, PreprocessSpec::face_recognition())?,
+        classifier: args
+            .classifier
+            .as_deref()
+            .map(|path| load_network(path, PreprocessSpec::lain_classifier()))
+            .transpose()?,
+        metric: args.metric,
+    };
+
+    let mut references = enroll(&models, &args.references, false)?;
+    if references.is_empty() {
+        return Err(anyhow!("No usable reference faces in {}", args.references.display()));
+    }
+    println!("Enrolled {} reference faces from {}", references.len(), args.references.display());
+    if let Some(dir) = &args.negative_references {
+        let negatives = enroll(&models, dir, true)?;
+        println!("Enrolled {} negative reference faces from {}", negatives.len(), dir.display());
+        references.extend(negatives);
+    }
+
+    let mut samples = Vec::new();
+    for (dir, positive) in [(&args.positives, true), (&args.negatives, false)] {
+        for path in image_files(dir)? {
+            match evaluate(&models, &references, &path, positive) {
+                Ok(sample) => {
+                    print_sample(&path, &sample, args.threshold);
+                    samples.push(sample);
+                }
+                Err(err) => eprintln!("{}: error ({})", path.display(), err),
+            }
+        }
+    }
+
+    let confusion = metrics::confusion_at(&samples, args.threshold);
+    println!("\nRecognition at THRESHOLD = {:.4}", args.threshold);
+    print_confusion(&confusion);
+
+    if models.classifier.is_some() {
+        println!("\nClassifier at p(lain) >= 0.5");
+        print_confusion(&metrics::classifier_confusion(&samples));
+    }
+
+    let roc = metrics::roc_curve(&samples);
+    println!("\nROC AUC: {:.4}", metrics::auc(&roc));
+    if let Some(best) = metrics::recommended_threshold(&roc) {
+        println!(
+            "Recommended THRESHOLD: {:.4} (TPR {:.3}, FPR {:.3})",
+            best.threshold, best.tpr, best.fpr
+        );
+    }
+
+    if let Some(path) = &args.roc {
+        let mut csv = String::from("threshold,tpr,fpr\n");
+        for point in &roc {
+            csv.push_str(&format!("{},{},{}\n", point.threshold, point.tpr, point.fpr));
+        }
+        fs::write(path, csv).with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("ROC curve written to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Loads a model with the preprocessing the canister uses by default.
+fn load_network(path: &Path, spec: PreprocessSpec) -> anyhow::Result<Network> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let model = onnx::load_model(Bytes::from(bytes))
+        .map_err(|err| anyhow!("Failed to load {}: {}", path.display(), err))?;
+    Ok(Network { model, spec })
+}
+
+/// Loads a face detection model in the format given by its metadata.
+fn load_detector(path: &Path) -> anyhow::Result<Detector> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    onnx::load_detector(Bytes::from(bytes))
+        .map_err(|err| anyhow!("Failed to load {}: {}", path.display(), err))
+}
+
+/// Returns the image files of a directory, sorted by name.
+fn image_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+                .unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Enrolls the images of a directory, skipping those without a usable face.
+fn enroll(models: &Models, dir: &Path, negative: bool) -> anyhow::Result<Vec<Reference>> {
+    let mut references = Vec::new();
+    for path in image_files(dir)? {
+        let bytes = fs::read(&path)?;
+        let embedding = image::load_from_memory(&bytes)
+            .map_err(anyhow::Error::from)
+            .and_then(|image| face_embedding(models, &image.to_rgb8()));
+        match embedding {
+            Ok(embedding) => references.push(Reference { bytes, embedding, negative }),
+            Err(err) => eprintln!("{}: skipped reference ({})", path.display(), err),
+        }
+    }
+    Ok(references)
+}
+
+/// Mirrors `onnx::face_embedding`: detects the most confident face and embeds
+/// the crop of that face alone.
+fn face_embedding(models: &Models, image: &RgbImage) -> anyhow::Result<Vec<f32>> {
+    let (bbox, _) = onnx::detect_with(&models.detector, image)?;
+    Ok(onnx::embedding_with(&models.recognizer, &onnx::crop_to_box(image, &bbox))?.v0)
+}
+
+/// Returns the closest reference of each identity, Lain or negative, as the
+/// canister ranks them. References made from the same image are skipped.
+fn closest_identities(
+    models: &Models,
+    references: &[Reference],
+    bytes: &[u8],
+    embedding: &[f32],
+) -> (Option<IdentityMatch>, Option<IdentityMatch>) {
+    let closest = |negative: bool| {
+        references
+            .iter()
+            .filter(|r| r.negative == negative && r.bytes != bytes)
+            .map(|r| models.metric.distance(&r.embedding, embedding))
+            .min_by(f32::total_cmp)
+            .map(|score| {
+                let label = if negative { "not lain" } else { "lain" }.to_string();
+                IdentityMatch { label, score, reference_id: 0, negative }
+            })
+    };
+    (closest(false), closest(true))
+}
+
+/// Mirrors `verify_and_store_artwork`: detect a face, then match its embedding
+/// against the references with the open-set rule of `onnx::recognize`.
+fn evaluate(models: &Models, references: &[Reference], path: &Path, positive: bool) -> anyhow::Result<Sample> {
+    let bytes = fs::read(path)?;
+    let image = image::load_from_memory(&bytes)?.to_rgb8();
+
+    let lain_probability = models
+        .classifier
+        .as_ref()
+        .map(|classifier| onnx::classify_with(classifier, &image))
+        .transpose()?;
+
+    let (distance, rejected_by_negative) = match face_embedding(models, &image) {
+        Ok(embedding) => {
+            let (lain, negative) = closest_identities(models, references, &bytes, &embedding);
+            let rejected = lain.as_ref().is_some_and(|lain| onnx::rejected_by_negative(lain, negative.as_ref()));
+            (lain.map(|lain| lain.score), rejected)
+        }
+        Err(_) => (None, false),
+    };
+
+    Ok(Sample { positive, distance, rejected_by_negative, lain_probability })
+}
+
+fn print_sample(path: &Path, sample: &Sample, threshold: f32) {
+    let accepted = sample.accepted(threshold);
+    let distance = sample
+        .distance
+        .map(|d| format!("{:.4}", d))
+        .unwrap_or_else(|| "no face".to_string());
+    let negative = if sample.rejected_by_negative { ", closer to a negative reference" } else { "" };
+    let classifier = sample
+        .lain_probability
+        .map(|p| format!(", p(lain)={:.3}", p))
+        .unwrap_or_default();
+    let mark = if accepted == sample.positive { "✓" } else { "✗" };
+    println!(
+        "{}: distance={}{}, accepted={}{} {}",
+        path.display(),
+        distance,
+        negative,
+        accepted,
+        classifier,
+        mark
+    );
+}
+
+fn print_confusion(confusion: &metrics::Confusion) {
+    println!(
+        "  TP={} FP={} TN={} FN={}",
+        confusion.tp, confusion.fp, confusion.tn, confusion.fn_
+    );
+    println!(
+        "  accuracy={:.3} precision={:.3} recall={:.3} f1={:.3}",
+        confusion.accuracy(),
+        confusion.precision(),
+        confusion.recall(),
+        confusion.f1()
+    );
+    println!(
+        "  FAR={:.3} FRR={:.3}",
+        confusion.false_positive_rate(),
+        confusion.false_negative_rate()
+    );
+}
@@ -0,0 +1,232 @@
+/// The outcome of running the pipeline on one labelled image.
+pub struct Sample {
+    /// Whether the image is expected to be recognized as Lain.
+    pub positive: bool,
+    /// Distance to the closest Lain reference embedding, `None` if no face was detected.
+    pub distance: Option<f32>,
+    /// Whether a negative reference is closer than the Lain references by more
+    /// than `NEGATIVE_MARGIN`, which rejects the image at any threshold.
+    pub rejected_by_negative: bool,
+    /// Lain probability reported by the classifier, if one was loaded.
+    pub lain_probability: Option<f32>,
+}
+
+impl Sample {
+    /// Whether the canister accepts the image at `threshold`.
+    pub fn accepted(&self, threshold: f32) -> bool {
+        !self.rejected_by_negative && matches!(self.distance, Some(d) if d <= threshold)
+    }
+}
+
+/// Confusion matrix counts for a binary decision.
+#[derive(Default, Clone, Copy)]
+pub struct Confusion {
+    pub tp: usize,
+    pub fp: usize,
+    pub tn: usize,
+    pub fn_: usize,
+}
+
+impl Confusion {
+    pub fn record(&mut self, positive: bool, accepted: bool) {
+        match (positive, accepted) {
+            (true, true) => self.tp += 1,
+            (true, false) => self.fn_ += 1,
+            (false, true) => self.fp += 1,
+            (false, false) => self.tn += 1,
+        }
+    }
+
+    pub fn accuracy(&self) -> f32 {
+        ratio(self.tp + self.tn, self.tp + self.tn + self.fp + self.fn_)
+    }
+
+    pub fn precision(&self) -> f32 {
+        ratio(self.tp, self.tp + self.fp)
+    }
+
+    pub fn recall(&self) -> f32 {
+        ratio(self.tp, self.tp + self.fn_)
+    }
+
+    pub fn false_positive_rate(&self) -> f32 {
+        ratio(self.fp, self.fp + self.tn)
+    }
+
+    pub fn false_negative_rate(&self) -> f32 {
+        ratio(self.fn_, self.tp + self.fn_)
+    }
+
+    pub fn f1(&self) -> f32 {
+        let (p, r) = (self.precision(), self.recall());
+        if p + r == 0.0 {
+            0.0
+        } else {
+            2.0 * p * r / (p + r)
+        }
+    }
+}
+
+/// One point of the ROC curve: images with a distance `<= threshold` are accepted.
+pub struct RocPoint {
+    pub threshold: f32,
+    pub tpr: f32,
+    pub fpr: f32,
+}
+
+/// Returns the confusion matrix of the canister decision rule at `threshold`.
+pub fn confusion_at(samples: &[Sample], threshold: f32) -> Confusion {
+    let mut confusion = Confusion::default();
+    for sample in samples {
+        confusion.record(sample.positive, sample.accepted(threshold));
+    }
+    confusion
+}
+
+/// Returns the confusion matrix of the classifier at a probability cut-off of 0.5.
+/// Samples without a classifier output are ignored.
+pub fn classifier_confusion(samples: &[Sample]) -> Confusion {
+    let mut confusion = Confusion::default();
+    for sample in samples {
+        if let Some(p) = sample.lain_probability {
+            confusion.record(sample.positive, p >= 0.5);
+        }
+    }
+    confusion
+}
+
+/// Sweeps the threshold over every observed distance and returns the ROC curve,
+/// ordered by increasing threshold.
+pub fn roc_curve(samples: &[Sample]) -> Vec<RocPoint> {
+    let mut thresholds: Vec<f32> = samples.iter().filter_map(|s| s.distance).collect();
+    thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    thresholds.dedup();
+
+    thresholds
+        .into_iter()
+        .map(|threshold| {
+            let confusion = confusion_at(samples, threshold);
+            RocPoint {
+                threshold,
+                tpr: confusion.recall(),
+                fpr: confusion.false_positive_rate(),
+            }
+        })
+        .collect()
+}
+
+/// Area under the ROC curve, using the trapezoidal rule from (0, 0).
+pub fn auc(roc: &[RocPoint]) -> f32 {
+    let mut area = 0.0;
+    let (mut prev_fpr, mut prev_tpr) = (0.0, 0.0);
+    for point in roc {
+        area += (point.fpr - prev_fpr) * (point.tpr + prev_tpr) / 2.0;
+        prev_fpr = point.fpr;
+        prev_tpr = point.tpr;
+    }
+    // Images without a detected face are never accepted, so the curve may not reach (1, 1).
+    area + (1.0 - prev_fpr) * (1.0 + prev_tpr) / 2.0
+}
+
+/// Recommends the threshold maximizing Youden's J statistic (TPR - FPR).
+/// Ties are broken in favour of the stricter (lower) threshold.
+pub fn recommended_threshold(roc: &[RocPoint]) -> Option<&RocPoint> {
+    roc.iter().fold(None, |best: Option<&RocPoint>, point| match best {
+        Some(best) if best.tpr - best.fpr >= point.tpr - point.fpr => Some(best),
+        _ => Some(point),
+    })
+}
+
+fn ratio(num: usize, den: usize) -> f32 {
+    if den == 0 {
+        0.0
+    } else {
+        num as f32 / den as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(positive: bool, distance: Option<f32>) -> Sample {
+        Sample { positive, distance, rejected_by_negative: false, lain_probability: None }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn confusion_rates() {
+        let confusion = Confusion { tp: 2, fp: 1, tn: 3, fn_: 2 };
+        assert!(close(confusion.accuracy(), 5.0 / 8.0));
+        assert!(close(confusion.precision(), 2.0 / 3.0));
+        assert!(close(confusion.recall(), 0.5));
+        assert!(close(confusion.false_positive_rate(), 0.25));
+        assert!(close(confusion.false_negative_rate(), 0.5));
+        assert!(close(confusion.f1(), 4.0 / 7.0));
+
+        let empty = Confusion::default();
+        assert_eq!((empty.accuracy(), empty.precision(), empty.recall(), empty.f1()), (0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn distances_up_to_the_threshold_are_accepted() {
+        let samples = [sample(true, Some(0.6)), sample(true, None), sample(false, Some(0.7))];
+        let confusion = confusion_at(&samples, 0.6);
+        assert_eq!((confusion.tp, confusion.fn_, confusion.fp, confusion.tn), (1, 1, 0, 1));
+    }
+
+    #[test]
+    fn samples_rejected_by_a_negative_reference_are_never_accepted() {
+        let mut rejected = sample(false, Some(0.1));
+        rejected.rejected_by_negative = true;
+        let samples = [sample(true, Some(0.2)), rejected];
+        let confusion = confusion_at(&samples, 2.0);
+        assert_eq!((confusion.tp, confusion.fn_, confusion.fp, confusion.tn), (1, 0, 0, 1));
+    }
+
+    #[test]
+    fn classifier_confusion_ignores_samples_without_a_probability() {
+        let samples = [
+            Sample { positive: true, distance: None, rejected_by_negative: false, lain_probability: Some(0.5) },
+            Sample { positive: false, distance: None, rejected_by_negative: false, lain_probability: Some(0.4) },
+            sample(false, Some(0.1)),
+        ];
+        let confusion = classifier_confusion(&samples);
+        assert_eq!((confusion.tp, confusion.fn_, confusion.fp, confusion.tn), (1, 0, 0, 1));
+    }
+
+    #[test]
+    fn separable_samples_have_an_auc_of_1() {
+        let samples = [
+            sample(true, Some(0.2)),
+            sample(true, Some(0.3)),
+            sample(false, Some(0.8)),
+            sample(false, Some(0.9)),
+        ];
+        let roc = roc_curve(&samples);
+        assert_eq!(roc.iter().map(|point| point.threshold).collect::<Vec<_>>(), vec![0.2, 0.3, 0.8, 0.9]);
+        assert!(close(auc(&roc), 1.0));
+        let best = recommended_threshold(&roc).unwrap();
+        assert_eq!((best.threshold, best.tpr, best.fpr), (0.3, 1.0, 0.0));
+    }
+
+    #[test]
+    fn undetected_faces_keep_the_curve_below_1() {
+        let samples = [sample(true, Some(0.2)), sample(true, None), sample(false, Some(0.8))];
+        let roc = roc_curve(&samples);
+        assert!(close(auc(&roc), 0.5));
+        assert!(recommended_threshold(&[]).is_none());
+    }
+
+    #[test]
+    fn ties_recommend_the_stricter_threshold() {
+        let roc = [
+            RocPoint { threshold: 0.4, tpr: 0.5, fpr: 0.0 },
+            RocPoint { threshold: 0.5, tpr: 0.75, fpr: 0.25 },
+        ];
+        assert_eq!(recommended_threshold(&roc).unwrap().threshold, 0.4);
+    }
+}
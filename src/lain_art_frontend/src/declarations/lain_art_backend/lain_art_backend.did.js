@@ -1,55 +1,72 @@
 export const idlFactory = ({ IDL }) => {
-  const RewardResult = IDL.Record({
-    'token_id' : IDL.Nat,
-    'reward_txid' : IDL.Text,
+  const Embedding = IDL.Record({ 'v0' : IDL.Vec(IDL.Float32) });
+  const Addition = IDL.Variant({ 'Ok' : Embedding, 'Err' : IDL.Text });
+  const BoundingBox = IDL.Record({
+    'top' : IDL.Float32,
+    'left' : IDL.Float32,
+    'bottom' : IDL.Float32,
+    'right' : IDL.Float32,
+  });
+  const Detection = IDL.Variant({ 'Ok' : BoundingBox, 'Err' : IDL.Text });
+  const ApprovedArtwork = IDL.Record({
+    'id' : IDL.Nat64,
+    'creator' : IDL.Principal,
+    'mime_type' : IDL.Text,
+    'image_data' : IDL.Vec(IDL.Nat8),
+    'recognition_score' : IDL.Float32,
+    'timestamp' : IDL.Nat64,
   });
   const FeeInvoice = IDL.Record({
     'memo' : IDL.Opt(IDL.Nat),
     'subaccount' : IDL.Opt(IDL.Vec(IDL.Nat8)),
     'amount' : IDL.Nat,
   });
-  const ArtMeta = IDL.Record({
-    'sha256' : IDL.Vec(IDL.Nat8),
-    'mime' : IDL.Text,
-    'size' : IDL.Nat,
-    'chunks' : IDL.Nat,
-  });
-  const Status = IDL.Variant({
-    'Rewarded' : IDL.Null,
-    'Rejected' : IDL.Record({ 'reason' : IDL.Text }),
-    'PendingUpload' : IDL.Null,
-    'AwaitingFee' : IDL.Null,
-    'Verified' : IDL.Record({
-      'originality' : IDL.Nat16,
-      'visibility' : IDL.Nat16,
-    }),
-    'Verifying' : IDL.Null,
-  });
-  const Submission = IDL.Record({
-    'id' : IDL.Nat,
-    'art' : IDL.Opt(ArtMeta),
-    'status' : Status,
-    'updated_at' : IDL.Nat64,
-    'creator' : IDL.Principal,
-    'nft_token_id' : IDL.Opt(IDL.Nat),
-    'created_at' : IDL.Nat64,
-    'fee_paid' : IDL.Bool,
-  });
+  const Person = IDL.Record({ 'label' : IDL.Text, 'score' : IDL.Float32 });
+  const Recognition = IDL.Variant({ 'Ok' : Person, 'Err' : IDL.Text });
+  const Result = IDL.Variant({ 'Ok' : BoundingBox, 'Err' : IDL.Text });
+  const Result_1 = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
+  const Result_2 = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text });
   return IDL.Service({
-    'confirm_fee' : IDL.Func([IDL.Nat], [], []),
+    'add' : IDL.Func([IDL.Text, IDL.Vec(IDL.Nat8)], [Addition], []),
+    'append_face_detection_model_bytes' : IDL.Func(
+        [IDL.Vec(IDL.Nat8)],
+        [],
+        [],
+      ),
+    'append_face_recognition_model_bytes' : IDL.Func(
+        [IDL.Vec(IDL.Nat8)],
+        [],
+        [],
+      ),
+    'clear_face_detection_model_bytes' : IDL.Func([], [], []),
+    'clear_face_recognition_model_bytes' : IDL.Func([], [], []),
+    'detect' : IDL.Func([IDL.Vec(IDL.Nat8)], [Detection], ['query']),
     'finalize_asset' : IDL.Func(
         [IDL.Nat, IDL.Text, IDL.Nat, IDL.Vec(IDL.Nat8)],
         [],
         [],
       ),
-    'finalize_reward' : IDL.Func([IDL.Nat], [RewardResult], []),
+    'get_approved_artwork' : IDL.Func(
+        [],
+        [IDL.Vec(ApprovedArtwork)],
+        ['query'],
+      ),
+    'get_artwork_by_id' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(ApprovedArtwork)],
+        ['query'],
+      ),
+    'get_artwork_count' : IDL.Func([], [IDL.Nat64], ['query']),
+    'get_face_count' : IDL.Func([], [IDL.Nat], ['query']),
     'get_fee_invoice' : IDL.Func([IDL.Nat], [FeeInvoice], ['query']),
-    'get_submission' : IDL.Func([IDL.Nat], [IDL.Opt(Submission)], ['query']),
-    'override_verdict' : IDL.Func([IDL.Nat, Status, IDL.Text], [], []),
+    'list_stored_faces' : IDL.Func([], [IDL.Vec(IDL.Text)], ['query']),
     'put_chunk' : IDL.Func([IDL.Nat, IDL.Nat, IDL.Vec(IDL.Nat8)], [], []),
-    'set_verdict' : IDL.Func([IDL.Nat, IDL.Nat16, IDL.Nat16, IDL.Text], [], []),
+    'recognize' : IDL.Func([IDL.Vec(IDL.Nat8)], [Recognition], []),
+    'remove_face' : IDL.Func([IDL.Text], [], []),
+    'run_face_detection' : IDL.Func([IDL.Nat], [Result], []),
+    'setup_models' : IDL.Func([], [Result_1], []),
     'start_submission' : IDL.Func([], [IDL.Nat], []),
-    'trigger_verification' : IDL.Func([IDL.Nat], [], []),
+    'verify_and_store_artwork' : IDL.Func([IDL.Nat], [Result_2], []),
   });
 };
 export const init = ({ IDL }) => { return []; };
@@ -0,0 +1,273 @@
+//! A minimal ICRC-1 ledger for the PocketIC tests of `lain_art_backend`.
+//!
+//! Balances and the ICRC-3 block log live on the heap. Like the real ledgers,
+//! transfers with a `created_at_time` are deduplicated for `DEDUP_WINDOW` and
+//! refused as `TooOld` afterwards. Whether the ledger reports ICRC-3 support is
+//! set at install time, and `set_failing` makes `icrc1_transfer` trap, so that
+//! the caller cannot tell whether a transfer went through. Anyone can `mint`.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+/// Fee of every transfer.
+const FEE: u64 = 10_000;
+/// How long transfers are deduplicated, in nanoseconds.
+const DEDUP_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// How far ahead of the ledger time a `created_at_time` may be, in nanoseconds.
+const PERMITTED_DRIFT: u64 = 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    fn key(&self) -> (Principal, Vec<u8>) {
+        (self.owner, self.subaccount.clone().unwrap_or_else(|| vec![0; 32]))
+    }
+
+    fn value(&self) -> Value {
+        let mut parts = vec![Value::Blob(self.owner.as_slice().to_vec())];
+        if let Some(subaccount) = &self.subaccount {
+            parts.push(Value::Blob(subaccount.clone()));
+        }
+        Value::Array(parts)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: ArchiveCallback,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+candid::define_function!(pub ArchiveCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+/// A mint (without `from`) or a transfer.
+struct Block {
+    from: Option<Account>,
+    to: Account,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    timestamp: u64,
+}
+
+impl Block {
+    fn value(&self) -> Value {
+        let op = if self.from.is_some() { "xfer" } else { "mint" };
+        let mut tx = vec![
+            ("op".to_string(), Value::Text(op.to_string())),
+            ("amt".to_string(), Value::Nat(Nat::from(self.amount))),
+            ("to".to_string(), self.to.value()),
+        ];
+        if let Some(from) = &self.from {
+            tx.push(("from".to_string(), from.value()));
+        }
+        if let Some(memo) = &self.memo {
+            tx.push(("memo".to_string(), Value::Blob(memo.clone())));
+        }
+        if let Some(created_at_time) = self.created_at_time {
+            tx.push(("ts".to_string(), Value::Nat(Nat::from(created_at_time))));
+        }
+        Value::Map(vec![
+            ("ts".to_string(), Value::Nat(Nat::from(self.timestamp))),
+            ("tx".to_string(), Value::Map(tx)),
+        ])
+    }
+}
+
+thread_local! {
+    static BALANCES: RefCell<BTreeMap<(Principal, Vec<u8>), u64>> = const { RefCell::new(BTreeMap::new()) };
+    static BLOCKS: RefCell<Vec<Block>> = const { RefCell::new(Vec::new()) };
+    static ICRC3: Cell<bool> = const { Cell::new(false) };
+    static FAILING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn balance(account: &Account) -> u64 {
+    BALANCES.with_borrow(|balances| balances.get(&account.key()).copied().unwrap_or(0))
+}
+
+fn set_balance(account: &Account, amount: u64) {
+    BALANCES.with_borrow_mut(|balances| balances.insert(account.key(), amount));
+}
+
+fn push(block: Block) -> Nat {
+    BLOCKS.with_borrow_mut(|blocks| {
+        blocks.push(block);
+        Nat::from(blocks.len() as u64 - 1)
+    })
+}
+
+fn to_u64(nat: &Nat) -> u64 {
+    u64::try_from(nat.0.clone()).unwrap_or(u64::MAX)
+}
+
+/// Installs the ledger; `icrc3` sets whether it reports ICRC-3 support.
+#[ic_cdk::init]
+fn init(icrc3: bool) {
+    ICRC3.set(icrc3);
+}
+
+/// Credits an account and returns the block index of the mint.
+#[ic_cdk::update]
+fn mint(to: Account, amount: Nat) -> Nat {
+    let amount = to_u64(&amount);
+    set_balance(&to, balance(&to) + amount);
+    push(Block { from: None, to, amount, memo: None, created_at_time: None, timestamp: ic_cdk::api::time() })
+}
+
+/// Makes every transfer trap until it is called with `false`.
+#[ic_cdk::update]
+fn set_failing(failing: bool) {
+    FAILING.set(failing);
+}
+
+#[ic_cdk::update]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    if FAILING.get() {
+        ic_cdk::trap("The test ledger is failing");
+    }
+    let now = ic_cdk::api::time();
+    let from = Account { owner: ic_cdk::caller(), subaccount: arg.from_subaccount };
+    if arg.fee.as_ref().is_some_and(|fee| *fee != Nat::from(FEE)) {
+        return Err(TransferError::BadFee { expected_fee: Nat::from(FEE) });
+    }
+    let amount = to_u64(&arg.amount);
+    if let Some(created_at_time) = arg.created_at_time {
+        if created_at_time.saturating_add(DEDUP_WINDOW) < now {
+            return Err(TransferError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(TransferError::CreatedInFuture { ledger_time: now });
+        }
+        let duplicate = BLOCKS.with_borrow(|blocks| {
+            blocks.iter().position(|block| {
+                block.from.as_ref().map(Account::key) == Some(from.key())
+                    && block.to.key() == arg.to.key()
+                    && block.amount == amount
+                    && block.memo == arg.memo
+                    && block.created_at_time == arg.created_at_time
+            })
+        });
+        if let Some(index) = duplicate {
+            return Err(TransferError::Duplicate { duplicate_of: Nat::from(index as u64) });
+        }
+    }
+    let available = balance(&from);
+    if available < amount.saturating_add(FEE) {
+        return Err(TransferError::InsufficientFunds { balance: Nat::from(available) });
+    }
+    set_balance(&from, available - amount - FEE);
+    set_balance(&arg.to, balance(&arg.to) + amount);
+    Ok(push(Block {
+        from: Some(from),
+        to: arg.to,
+        amount,
+        memo: arg.memo,
+        created_at_time: arg.created_at_time,
+        timestamp: now,
+    }))
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    Nat::from(balance(&account))
+}
+
+#[ic_cdk::query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+#[ic_cdk::query]
+fn icrc1_supported_standards() -> Vec<SupportedStandard> {
+    let mut standards = vec!["ICRC-1"];
+    if ICRC3.get() {
+        standards.push("ICRC-3");
+    }
+    standards
+        .into_iter()
+        .map(|name| SupportedStandard {
+            name: name.to_string(),
+            url: "https://github.com/dfinity/ICRC-1".to_string(),
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    if !ICRC3.get() {
+        ic_cdk::trap("This ledger does not implement ICRC-3");
+    }
+    BLOCKS.with_borrow(|blocks| {
+        let mut found = Vec::new();
+        for arg in args {
+            let start = to_u64(&arg.start).min(blocks.len() as u64);
+            let end = start.saturating_add(to_u64(&arg.length)).min(blocks.len() as u64);
+            for id in start..end {
+                found.push(BlockWithId { id: Nat::from(id), block: blocks[id as usize].value() });
+            }
+        }
+        GetBlocksResult { log_length: Nat::from(blocks.len() as u64), blocks: found, archived_blocks: Vec::new() }
+    })
+}